@@ -1,6 +1,9 @@
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
 #![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(pi0_lib::testing::runner)]
+#![reexport_test_harness_main = "test_main"]
 #![allow(asm_sub_register)]
 
 extern crate alloc;
@@ -88,7 +91,25 @@ fn main() {
     // profile::demo();
 
     println!("FINISHED RSSTART");
-    println!("DONE!!!");
 
     rpi_reboot();
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+    use pi0_lib::timer;
+
+    #[test_case]
+    fn allocates() {
+        let values = (0..100).map(Box::new).collect::<Vec<_>>();
+        assert_eq!(values.iter().map(|v| **v).sum::<u32>(), 4950);
+    }
+
+    #[test_case]
+    fn timer_advances() {
+        let start = timer::timer_get_usec();
+        timer::delay_ms(2);
+        assert!(timer::timer_get_usec().wrapping_sub(start) >= 2000);
+    }
+}
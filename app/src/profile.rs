@@ -1,6 +1,7 @@
 extern crate alloc;
 
 use alloc::vec::Vec;
+use bootloader_shared::message::{Message, MAX_PROFILE_SAMPLES};
 use core::cell::RefCell;
 use critical_section::Mutex;
 use pi0_lib::{
@@ -8,8 +9,8 @@ use pi0_lib::{
         self, interrupts_enabled, register_interrupt_handler, remove_interrupt_handler,
         timer_initialized,
     },
-    print,
     setup::__code_end__,
+    uart::write_message,
 };
 
 use crate::{println, profile, timer};
@@ -61,12 +62,27 @@ impl Gprof {
                 gprof.pc_start,
                 gprof.pc_start + gprof.buffer.len() * 4
             );
+            // Send address/count pairs for the installer to resolve.
+            let mut samples = [0; MAX_PROFILE_SAMPLES * 8];
+            let mut length = 0;
             for (i, c) in gprof.buffer.iter().enumerate() {
-                if *c > 0 {
-                    print!("{:#08x}:{c},", gprof.pc_start + i * size_of::<usize>());
+                if *c == 0 {
+                    continue;
                 }
+                let pc = (gprof.pc_start + i * size_of::<usize>()) as u32;
+                samples[length..length + 4].copy_from_slice(&pc.to_le_bytes());
+                samples[length + 4..length + 8].copy_from_slice(&c.to_le_bytes());
+                length += 8;
+                if length == samples.len() {
+                    write_message(Message::ProfileSamples { samples: &samples });
+                    length = 0;
+                }
+            }
+            if length > 0 {
+                write_message(Message::ProfileSamples {
+                    samples: &samples[..length],
+                });
             }
-            println!("");
 
             println!("Total count: {}", total);
         })
@@ -1,15 +1,13 @@
 use bcm2835_lpa::Peripherals;
 use core::arch::{asm, global_asm};
 use pi0_lib::{
-    cycle_counter, get_pins,
+    crash, cycle_counter, get_pins,
     gpio::{Pin, Unset},
     interrupts::{gpio_interrupts_init, interrupt_init, timer_initialized},
     setup::{__bss_end__, __bss_start__, rpi_reboot, STACK_ADDR, SUPER_MODE},
     uart::{setup_uart, store_uart},
 };
 
-use crate::main;
-
 global_asm!(r#"
 .section ".text.start"
 .globl _start
@@ -71,9 +69,13 @@ pub unsafe extern "C" fn rsstart() -> ! {
     let (p15, _pins): (Pin<15, Unset>, _) = pins.pluck();
     let w = setup_uart(p14, p15, &mut peripherals);
     store_uart(w);
+    crash::report();
 
     // pi0_lib::virtual_memory::setup();
 
-    main();
+    #[cfg(test)]
+    crate::test_main();
+    #[cfg(not(test))]
+    crate::main();
     rpi_reboot();
 }
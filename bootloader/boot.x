@@ -0,0 +1,23 @@
+/* The firmware loads the bootloader at 0x8000, where it copies itself to
+ * 0x7E00000 (BOOTLOADER_LOCATION) to leave the rest of memory to programs. */
+SECTIONS
+{
+    .text 0x7E00000 : AT(0x8000) {
+        __code_start__ = .;
+        *(.text.start) *(.text*)
+        __code_end__ = .;
+    }
+    __data_start__ = .;
+    .rodata :       { *(.rodata*) }
+    .data :         { *(.data*) }
+    __data_end__ = .;
+    __bss_start__ = .;
+    .bss :          { *(.bss*)  *(COMMON) }
+    __bss_end__ = ALIGN(8);
+}
+
+/* Force link of _start and verify correct position */
+ENTRY(_start)
+ASSERT(_start == ADDR(.text), "_start symbol must be placed first in text section")
+/* Leave 1MB of stack below STACK_ADDR. */
+ASSERT(__bss_end__ <= 0x7F00000, "bootloader overlaps its stack")
@@ -1,29 +1,98 @@
 #![no_std]
 #![no_main]
 
-use core::{
-    arch::{asm, global_asm},
-    time::Duration,
-};
+use core::arch::{asm, global_asm};
 
 use bcm2835_lpa::Peripherals;
 use bootloader_shared::{
-    BASE, CRC_ALGORITHM, INSTALLER_PROG_INFO, INSTALLER_SUCCESS, PI_ERROR, PI_GET_CODE,
-    PI_GET_PROG_INFO, PI_SUCCESS,
+    boot_info::{BootInfo, BOOT_INFO},
+    fat::{self, Fat32},
+    message::{ExitStatus, Message},
+    pi::{Bootloader, Code, Event, Layout},
+    sign::Key,
+    Error, BASE, BOOTLOADER_LOCATION, BOOTLOADER_RESIDENT, CRC_ALGORITHM, DEFAULT_BAUD, PI_ERROR,
 };
 use pi0_lib::{
     gpio::{Pin, Unset},
-    setup::{rpi_reboot, STACK_ADDR, SUPER_MODE},
+    println,
+    sd::{self, Sd},
+    setup::{exit, reset, STACK_ADDR, SUPER_MODE},
     timer,
-    uart::{read_uart, read_uart_u32_timeout, setup_uart, store_uart, write_uart_u32},
+    uart::{
+        self, read_uart_raw, set_baud, setup_uart, store_uart, write_message, write_uart,
+        write_uart_u32,
+    },
 };
 
-const BOOTLOADER_LOCATION: u32 = 0x200000;
+/// Compressed programs are received below the bootloader, leaving the rest of
+/// memory to the program.
+const STAGING: u32 = 0x6000000;
+const LAYOUT: Layout = Layout {
+    limit: STAGING,
+    staging: STAGING,
+    staging_limit: BOOTLOADER_LOCATION,
+};
+/// What the firmware boots, and where updates keep the bootloader it replaces.
+const KERNEL: &str = "kernel.img";
+const KERNEL_BACKUP: &str = "kernel.bak";
+/// Only run programs signed with this key, given in hex when building, see
+/// `install --key`.
+const KEY: Option<Key> = match option_env!("BOOTLOADER_KEY") {
+    Some(hex) => match Key::from_hex(hex) {
+        Some(key) => Some(key),
+        None => panic!("BOOTLOADER_KEY must be 64 hex digits"),
+    },
+    None => None,
+};
+/// How long to wait after going back to [DEFAULT_BAUD] before the program
+/// runs, so the installer has switched too and hears its first output.
+const BAUD_SETTLE_MS: u32 = 50;
 
 global_asm!(r#"
 .section ".text.start"
 .globl _start
 _start:
+    b cold
+    @ lets programs find the warm entry, see BOOTLOADER_RESIDENT.
+    .word {resident}
+    .word warm
+
+cold:
+    @ we were loaded at BASE but are linked at BOOTLOADER_LOCATION, so copy
+    @ ourselves there using only position independent code.
+    mov r0, {base}
+    ldr r1, =__code_start__
+    ldr r2, =__data_end__
+1:
+    ldr r3, [r0], #4
+    str r3, [r1], #4
+    cmp r1, r2
+    blo 1b
+
+    @ invalidate the instruction cache and jump to the copy.
+    mov r3, #0
+    mcr p15, 0, r3, c7, c5, 0
+    ldr pc, =relocated
+
+warm:
+    @ a program finished and wants the next one loaded. go back to SUPER mode
+    @ with interrupts disabled, and turn the MMU and caches off again after
+    @ writing back anything the program left in the data cache.
+    mov r0, {super_mode}
+    orr r0, r0, #(1<<7)
+    msr cpsr, r0
+    mov r0, #0
+    mcr p15, 0, r0, c7, c14, 0  @ clean and invalidate the data cache.
+    mcr p15, 0, r0, c7, c10, 4  @ drain the write buffer.
+    mrc p15, 0, r1, c1, c0, 0
+    bic r1, r1, #(1<<12)        @ instruction cache.
+    bic r1, r1, #((1<<2)|1)     @ data cache and MMU.
+    mcr p15, 0, r1, c1, c0, 0
+    mcr p15, 0, r0, c7, c7, 0   @ invalidate both caches.
+    mcr p15, 0, r0, c8, c7, 0   @ invalidate the TLB.
+    mcr p15, 0, r0, c7, c5, 4   @ prefetch flush.
+
+relocated:
     mov sp,#0x08000000
     @ force the mode to be SUPER.
     mov r0,  {}
@@ -39,11 +108,8 @@ _start:
     bl rsstart          @ we could jump right to rsstart (notmain)
     @ bl _cstart        @ call our code to do initialization.
     bl rpi_reboot     @ if they return just reboot.
-
-@ give ourselves space to load the bootloaded program
-.space {LOCATION}-0x8024,0
 "#
-, const SUPER_MODE, const STACK_ADDR, LOCATION = const BOOTLOADER_LOCATION);
+, const SUPER_MODE, const STACK_ADDR, base = const BASE, super_mode = const SUPER_MODE, resident = const BOOTLOADER_RESIDENT);
 
 #[no_mangle]
 pub unsafe extern "C" fn rsstart() -> ! {
@@ -61,7 +127,7 @@ pub unsafe extern "C" fn rsstart() -> ! {
 
     main();
 
-    rpi_reboot();
+    reset();
 }
 
 fn main() {
@@ -74,85 +140,146 @@ fn main() {
     );
     store_uart(uart);
 
-    if let Err(()) = load() {
-        write_uart_u32(PI_ERROR);
-        p0.write(false);
-        timer::delay_ms(500);
-        p0.write(true);
-        timer::delay_ms(500);
-        p0.write(false);
-        rpi_reboot();
-    }
+    // Tell the installer what went wrong and wait for it to try again, which
+    // is faster than rebooting.
+    let entry = loop {
+        match load() {
+            Ok(entry) => break entry,
+            Err(e) => {
+                write_uart_u32(PI_ERROR);
+                for word in e.report().words() {
+                    write_uart_u32(word);
+                }
+                // The installer tries again at the default rate.
+                set_baud(DEFAULT_BAUD);
+                p0.write(false);
+                timer::delay_ms(500);
+                p0.write(true);
+                timer::delay_ms(500);
+                p0.write(false);
+            }
+        }
+    };
     p0.write(false);
+    if uart::baud() != DEFAULT_BAUD {
+        set_baud(DEFAULT_BAUD);
+        timer::delay_ms(BAUD_SETTLE_MS);
+    }
 
     // Jump to the loaded code!
-    unsafe { asm!("mov pc,{}", const BASE) };
+    unsafe { asm!("mov pc,{}", in(reg) entry) };
 }
 
-fn load() -> Result<(), ()> {
-    // Wait for message indicating transmission while sending program info req.
-    loop {
-        write_uart_u32(PI_GET_PROG_INFO);
-        if let Ok(v) = read_uart_u32_timeout(Duration::from_millis(300)) {
-            if v != INSTALLER_PROG_INFO {
-                return Err(());
-            }
-            break;
-        }
+/// Place each part of verified code.
+fn place(code: Code) {
+    for (address, data) in code {
+        let dest = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, data.len()) };
+        dest.copy_from_slice(data);
     }
+}
 
-    // Receive message length.
-    let program_length = read_uart_u32_timeout(Duration::from_millis(10))?;
-
-    // If there's not enough space, error.
-    if BASE + program_length >= BOOTLOADER_LOCATION {
-        return Err(());
+/// Load a program, returning its entry point.
+fn load() -> Result<u32, Error> {
+    let mut bootloader =
+        Bootloader::new(LAYOUT, timer::timer_get_usec()).with_core_clock(uart::core_clock());
+    if let Some(key) = KEY {
+        bootloader = bootloader.with_key(key);
     }
-
-    let checksum = read_uart_u32_timeout(Duration::from_millis(10))?;
-
-    // Request code and have other side validate checksum.
-    write_uart_u32(PI_GET_CODE);
-    write_uart_u32(checksum);
-
-    // Receive and copy in code.
-    let mut start = timer::timer_get_usec();
-    let mut index = BASE;
-    let mut digest = CRC_ALGORITHM.digest_with_initial(0);
     loop {
+        let now = timer::timer_get_usec();
+        bootloader.poll(now)?;
+        while let Some(bytes) = bootloader.transmit() {
+            write_uart(bytes);
+        }
+        set_baud(bootloader.baud());
+
         let mut buf = [0; 8];
-        let buf = read_uart(&mut buf);
-        if buf.is_empty() {
-            // If we time out waiting for a single byte, return.
-            if Duration::from_micros((timer::timer_get_usec() - start) as u64)
-                > Duration::from_millis(10)
-            {
-                return Err(());
+        let mut input = read_uart_raw(&mut buf);
+        while !input.is_empty() {
+            let (used, event) = bootloader.receive(input, now)?;
+            match event {
+                Event::Code(code) => place(code),
+                Event::Done { entry } => {
+                    for segment in bootloader.segments() {
+                        let (address, length) = segment.bss();
+                        unsafe { core::ptr::write_bytes(address as *mut u8, 0, length as usize) };
+                    }
+                    // Tell the program where its blobs are.
+                    unsafe { (BOOT_INFO as *mut BootInfo).write_volatile(bootloader.boot_info()) };
+                    return Ok(entry);
+                }
+                Event::Update { address, length } => {
+                    let image = unsafe {
+                        core::slice::from_raw_parts(address as *const u8, length as usize)
+                    };
+                    set_baud(DEFAULT_BAUD);
+                    update(image);
+                }
+                Event::None => {}
+            }
+            input = &input[used..];
+            if let Some(decompress) = bootloader.decompress() {
+                let (compressed, code) = unsafe {
+                    (
+                        core::slice::from_raw_parts(
+                            decompress.address as *const u8,
+                            decompress.compressed_length as usize,
+                        ),
+                        core::slice::from_raw_parts_mut(
+                            decompress.output as *mut u8,
+                            decompress.length as usize,
+                        ),
+                    )
+                };
+                lz4_flex::block::decompress_into(compressed, code)
+                    .map_err(|_| Error::Decompress)?;
+                place(bootloader.decompressed(code, timer::timer_get_usec())?);
+            }
+            while let Some(bytes) = bootloader.transmit() {
+                write_uart(bytes);
             }
-        } else {
-            start = timer::timer_get_usec();
+            set_baud(bootloader.baud());
         }
-        let dest = unsafe { core::slice::from_raw_parts_mut(index as *mut u8, buf.len()) };
-        dest.copy_from_slice(buf);
-        digest.update(buf);
+    }
+}
 
-        index += buf.len() as u32;
+enum UpdateError {
+    Sd(sd::Error),
+    Fat(fat::Error),
+    /// The card gave back something else than was written.
+    Mismatch,
+}
 
-        if index - BASE == program_length {
-            break;
+/// Write a verified bootloader update to the SD card, keeping the current
+/// bootloader as a fallback, and reboot into it. Reports failures to the
+/// installer and waits for the next program instead.
+fn update(image: &[u8]) -> ! {
+    match write_update(image) {
+        Ok(()) => {
+            println!(
+                "[bootloader] wrote {} bytes to {KERNEL}, the previous bootloader is {KERNEL_BACKUP}",
+                image.len()
+            );
+            write_message(Message::Exit(ExitStatus::success()));
+            reset()
         }
+        Err(UpdateError::Sd(e)) => println!("[bootloader] SD card failed: {e:?}"),
+        Err(UpdateError::Fat(e)) => println!("[bootloader] updating {KERNEL} failed: {e}"),
+        Err(UpdateError::Mismatch) => println!("[bootloader] {KERNEL} reads back wrong"),
     }
+    exit(1)
+}
 
-    // Verify checksum.
-    let calculated = digest.finalize();
-    if calculated != checksum {
-        return Err(());
-    }
-
-    write_uart_u32(PI_SUCCESS);
-    if read_uart_u32_timeout(Duration::from_millis(10))? != INSTALLER_SUCCESS {
-        return Err(());
+fn write_update(image: &[u8]) -> Result<(), UpdateError> {
+    let sd = Sd::init().map_err(UpdateError::Sd)?;
+    let mut fat = Fat32::open(sd).map_err(UpdateError::Fat)?;
+    fat.replace(KERNEL, KERNEL_BACKUP, image)
+        .map_err(UpdateError::Fat)?;
+    // Read it back, the write is only as good as the card.
+    let mut digest = CRC_ALGORITHM.digest_with_initial(0);
+    digest.update(image);
+    if fat.checksum(KERNEL).map_err(UpdateError::Fat)? != Some(digest.finalize()) {
+        return Err(UpdateError::Mismatch);
     }
-
     Ok(())
 }
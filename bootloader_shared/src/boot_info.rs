@@ -0,0 +1,101 @@
+//! Named blobs of data loaded along with a program, and the table telling the
+//! program where they ended up.
+//!
+//! The installer describes the blobs after `INSTALLER_BLOBS`, before the
+//! program info, and sends their contents after the code of the program's
+//! segments. Blobs without an address are placed by the bootloader, from the
+//! top of loadable memory down. A blob overlapping the segments, another blob
+//! or the staging area is refused. Before jumping to the program, the
+//! bootloader writes a [BootInfo] listing every blob to [BOOT_INFO].
+
+/// Most blobs loaded along with a program.
+pub const MAX_BLOBS: usize = 8;
+/// Longest blob name, in bytes.
+pub const BLOB_NAME_LENGTH: usize = 16;
+/// Blobs placed by the bootloader start at multiples of this.
+pub const BLOB_ALIGN: u32 = 4096;
+/// Where the bootloader leaves the [BootInfo], below [crate::BASE] so it never
+/// overlaps the program.
+pub const BOOT_INFO: u32 = 0x7000;
+const BOOT_INFO_MAGIC: u32 = 0xB007_14F0;
+
+/// A named blob, as sent by the installer and listed in the [BootInfo].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blob {
+    /// Where the blob goes. Sent as 0 for the bootloader to place it.
+    pub address: u32,
+    pub length: u32,
+    /// Padded with zeros.
+    pub(crate) name: [u8; BLOB_NAME_LENGTH],
+}
+
+impl Blob {
+    /// A blob at `address`, or placed by the bootloader. `None` if `name` is
+    /// empty, longer than [BLOB_NAME_LENGTH] or holds a zero byte.
+    pub fn new(name: &str, address: Option<u32>, length: u32) -> Option<Self> {
+        let bytes = name.as_bytes();
+        if bytes.is_empty() || bytes.len() > BLOB_NAME_LENGTH || bytes.contains(&0) {
+            return None;
+        }
+        let mut blob = Self {
+            address: address.unwrap_or(0),
+            length,
+            name: [0; BLOB_NAME_LENGTH],
+        };
+        blob.name[..bytes.len()].copy_from_slice(bytes);
+        Some(blob)
+    }
+
+    /// The name, empty if it didn't arrive as UTF-8.
+    pub fn name(&self) -> &str {
+        let length = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(BLOB_NAME_LENGTH);
+        core::str::from_utf8(&self.name[..length]).unwrap_or("")
+    }
+
+    /// The name as sent, padded with zeros.
+    pub fn name_bytes(&self) -> &[u8; BLOB_NAME_LENGTH] {
+        &self.name
+    }
+}
+
+/// Lists the blobs loaded along with the program, at [BOOT_INFO].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfo {
+    magic: u32,
+    count: u32,
+    blobs: [Blob; MAX_BLOBS],
+}
+
+impl BootInfo {
+    /// At most [MAX_BLOBS] of `blobs`, at their final addresses.
+    pub fn new(blobs: &[Blob]) -> Self {
+        let count = blobs.len().min(MAX_BLOBS);
+        let mut info = Self {
+            magic: BOOT_INFO_MAGIC,
+            count: count as u32,
+            blobs: [Blob::default(); MAX_BLOBS],
+        };
+        info.blobs[..count].copy_from_slice(&blobs[..count]);
+        info
+    }
+
+    /// Whether this was written by a bootloader, rather than being whatever
+    /// happened to be in memory.
+    pub fn is_valid(&self) -> bool {
+        self.magic == BOOT_INFO_MAGIC && self.count as usize <= MAX_BLOBS
+    }
+
+    pub fn blobs(&self) -> &[Blob] {
+        &self.blobs[..(self.count as usize).min(MAX_BLOBS)]
+    }
+
+    pub fn blob(&self, name: &str) -> Option<&Blob> {
+        self.blobs().iter().find(|blob| blob.name() == name)
+    }
+}
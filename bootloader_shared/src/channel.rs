@@ -0,0 +1,274 @@
+//! Channels multiplexed over the program's output, so tools can tell log
+//! lines and the program's own binary data apart from what the console shows.
+//!
+//! A frame is a zero byte, then the COBS encoding of the channel, the data and
+//! the checksum of both, then another zero byte. Since COBS leaves no zeros,
+//! a frame ends at the first zero after it starts. Output outside frames, as
+//! printed by `println!`, is on [Channel::Console]. Use [encode] on the Pi,
+//! and pass the output the message [Scanner] releases through a [Demux] on the
+//! host.
+//!
+//! What the installer itself acts on, such as the exit status, profiler
+//! samples, core dumps, GDB packets and requests for host files, stays in
+//! [messages](crate::message), which the [Scanner] takes out before the
+//! [Demux] sees the output. Channels are for streams the installer only
+//! routes.
+//!
+//! A zero byte in plain output is taken to start a frame, so it is dropped
+//! rather than shown. Zeros a tool should get belong on [Channel::Binary].
+//!
+//! [Scanner]: crate::message::Scanner
+use crate::CRC_ALGORITHM;
+
+/// Most bytes of data in a frame.
+pub const MAX_CHANNEL_DATA: usize = 256;
+/// The channel, the data and the checksum, before encoding.
+const MAX_RAW_LENGTH: usize = 1 + MAX_CHANNEL_DATA + 4;
+/// COBS adds a byte at the start and one per 254 bytes.
+const MAX_ENCODED_LENGTH: usize = MAX_RAW_LENGTH + MAX_RAW_LENGTH / 254 + 1;
+/// Bytes in the largest frame, with its zeros.
+pub const MAX_CHANNEL_FRAME: usize = MAX_ENCODED_LENGTH + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Shown on the console, same as plain output.
+    Console = 0,
+    /// Shown on the console unless saved elsewhere.
+    Log = 1,
+    /// Data for tools, never shown.
+    Binary = 2,
+    /// Commands for tools, never shown.
+    Control = 3,
+}
+
+impl Channel {
+    pub const ALL: [Self; 4] = [Self::Console, Self::Log, Self::Binary, Self::Control];
+
+    pub fn from_u8(channel: u8) -> Option<Self> {
+        Self::ALL.get(channel as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Console => "console",
+            Self::Log => "log",
+            Self::Binary => "binary",
+            Self::Control => "control",
+        }
+    }
+}
+
+/// Encode a frame of `data` on `channel` into `buf`, returning the frame.
+/// Data past [MAX_CHANNEL_DATA] is cut off.
+pub fn encode<'a>(channel: Channel, data: &[u8], buf: &'a mut [u8; MAX_CHANNEL_FRAME]) -> &'a [u8] {
+    let data = &data[..data.len().min(MAX_CHANNEL_DATA)];
+    let mut digest = CRC_ALGORITHM.digest();
+    digest.update(&[channel as u8]);
+    digest.update(data);
+    let checksum = digest.finalize().to_le_bytes();
+    let raw = core::iter::once(channel as u8)
+        .chain(data.iter().copied())
+        .chain(checksum);
+
+    buf[0] = 0;
+    // Where the length of the current run of non-zero bytes goes.
+    let mut code_at = 1;
+    let mut code = 1;
+    let mut length = 2;
+    for byte in raw {
+        if byte != 0 {
+            buf[length] = byte;
+            length += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xff {
+            buf[code_at] = code;
+            code_at = length;
+            length += 1;
+            code = 1;
+        }
+    }
+    buf[code_at] = code;
+    buf[length] = 0;
+    &buf[..length + 1]
+}
+
+/// Decode COBS `encoded` into `out`, returning its length, or `None` if it
+/// isn't valid COBS or doesn't fit.
+fn decode(encoded: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut length = 0;
+    let mut i = 0;
+    while i < encoded.len() {
+        let code = encoded[i] as usize;
+        let run = encoded.get(i + 1..i + code)?;
+        out.get_mut(length..length + run.len())?
+            .copy_from_slice(run);
+        length += run.len();
+        i += code;
+        // Runs of the longest length aren't followed by a zero.
+        if code < 0xff && i < encoded.len() {
+            *out.get_mut(length)? = 0;
+            length += 1;
+        }
+    }
+    Some(length)
+}
+
+/// Splits the program's output into channels.
+///
+/// Bytes after a zero are held back until the frame they may start ends, and
+/// let through as console output if it turns out not to be a frame. The zero
+/// itself is not let through.
+#[derive(Debug, Clone)]
+pub struct Demux {
+    /// Inside a frame, after its first zero.
+    framing: bool,
+    held: [u8; MAX_ENCODED_LENGTH + 1],
+    held_length: usize,
+    decoded: [u8; MAX_RAW_LENGTH],
+}
+
+impl Default for Demux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Demux {
+    pub fn new() -> Self {
+        Self {
+            framing: false,
+            held: [0; MAX_ENCODED_LENGTH + 1],
+            held_length: 0,
+            decoded: [0; MAX_RAW_LENGTH],
+        }
+    }
+
+    /// Add a byte of output, returning data for a channel if there is some.
+    pub fn push(&mut self, byte: u8) -> Option<(Channel, &[u8])> {
+        if !self.framing {
+            if byte == 0 {
+                self.framing = true;
+                return None;
+            }
+            self.decoded[0] = byte;
+            return Some((Channel::Console, &self.decoded[..1]));
+        }
+        if byte != 0 {
+            self.held[self.held_length] = byte;
+            self.held_length += 1;
+            if self.held_length < self.held.len() {
+                return None;
+            }
+            // Too long for a frame.
+            self.framing = false;
+            return Some(self.release());
+        }
+        // Zeros in a row are still the start of a frame.
+        if self.held_length == 0 {
+            return None;
+        }
+        let encoded = &self.held[..self.held_length];
+        match decode(encoded, &mut self.decoded) {
+            Some(length) if length >= 5 && valid(&self.decoded[..length]) => {
+                self.framing = false;
+                self.held_length = 0;
+                let channel = Channel::from_u8(self.decoded[0])?;
+                Some((channel, &self.decoded[1..length - 4]))
+            }
+            // Not a frame, but this zero may start one.
+            _ => Some(self.release()),
+        }
+    }
+
+    /// Let the held bytes through as console output.
+    fn release(&mut self) -> (Channel, &[u8]) {
+        let length = self.held_length;
+        self.held_length = 0;
+        (Channel::Console, &self.held[..length])
+    }
+}
+
+/// Whether a decoded frame has a known channel and the right checksum.
+fn valid(raw: &[u8]) -> bool {
+    let (body, checksum) = raw.split_at(raw.len() - 4);
+    Channel::from_u8(body[0]).is_some() && CRC_ALGORITHM.checksum(body).to_le_bytes() == checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// What `output` holds for each channel, joining data on the same channel.
+    fn demux(output: &[u8]) -> Vec<(Channel, Vec<u8>)> {
+        let mut demux = Demux::new();
+        let mut channels: Vec<(Channel, Vec<u8>)> = Vec::new();
+        for &byte in output {
+            let Some((channel, data)) = demux.push(byte) else {
+                continue;
+            };
+            match channels.last_mut() {
+                Some((last, joined)) if *last == channel => joined.extend_from_slice(data),
+                _ => channels.push((channel, data.to_vec())),
+            }
+        }
+        channels
+    }
+
+    fn channel_frame(channel: Channel, data: &[u8]) -> Vec<u8> {
+        encode(channel, data, &mut [0; MAX_CHANNEL_FRAME]).to_vec()
+    }
+
+    #[test]
+    fn splits_output_into_channels() {
+        let binary: Vec<u8> = (0..MAX_CHANNEL_DATA)
+            .map(|i| (i % 3) as u8 * 0x7f)
+            .collect();
+        let runs = [0xff; MAX_CHANNEL_DATA];
+        let mut output = b"hello\n".to_vec();
+        output.extend(channel_frame(Channel::Log, b"started\n"));
+        output.extend(channel_frame(Channel::Binary, &binary));
+        output.extend(channel_frame(Channel::Control, &runs));
+        output.extend(channel_frame(Channel::Console, b"framed "));
+        output.extend(b"plain");
+        assert_eq!(
+            demux(&output),
+            vec![
+                (Channel::Console, b"hello\n".to_vec()),
+                (Channel::Log, b"started\n".to_vec()),
+                (Channel::Binary, binary),
+                (Channel::Control, runs.to_vec()),
+                (Channel::Console, b"framed plain".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lets_through_zeros_that_start_no_frame() {
+        let mut output = b"a\0oops\0".to_vec();
+        output.extend(channel_frame(Channel::Log, b"x"));
+        let mut corrupted = channel_frame(Channel::Log, b"lost");
+        corrupted[3] ^= 1;
+        output.extend(&corrupted);
+        output.extend(b"b\0");
+        output.extend([b'c'; MAX_CHANNEL_FRAME]);
+        assert_eq!(
+            demux(&output),
+            vec![
+                (Channel::Console, b"aoops".to_vec()),
+                (Channel::Log, b"x".to_vec()),
+                (
+                    Channel::Console,
+                    [
+                        &corrupted[1..corrupted.len() - 1],
+                        b"b",
+                        &[b'c'; MAX_CHANNEL_FRAME]
+                    ]
+                    .concat()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Core dumps of a faulted program, for the installer to save as an ELF core
+//! file.
+//!
+//! The Pi sends a [Message::CoreRegisters], then for each region of memory a
+//! [Message::CoreRegion] followed by [memory_messages] for its contents, and
+//! finally a [Message::CoreEnd]. Parts of a region that aren't sent are
+//! zeros.
+//!
+//! [Message::CoreRegisters]: crate::message::Message::CoreRegisters
+//! [Message::CoreRegion]: crate::message::Message::CoreRegion
+//! [Message::CoreEnd]: crate::message::Message::CoreEnd
+use crate::message::{Message, MAX_PAYLOAD};
+
+/// Most bytes of memory in a [Message::CoreMemory], after its address.
+///
+/// [Message::CoreMemory]: crate::message::Message::CoreMemory
+pub const MAX_CORE_DATA: usize = MAX_PAYLOAD - 4;
+/// Registers in a [Message::CoreRegisters]: r0 to r15, then the cpsr.
+///
+/// [Message::CoreRegisters]: crate::message::Message::CoreRegisters
+pub const CORE_REGISTERS: usize = 17;
+
+/// Signals a fault is reported as, as a Linux core file would.
+pub const SIGILL: u32 = 4;
+pub const SIGSEGV: u32 = 11;
+
+/// Messages with the contents of `memory`, which starts at `address`, leaving
+/// out chunks that are all zeros.
+pub fn memory_messages(address: u32, memory: &[u8]) -> impl Iterator<Item = Message<'_>> {
+    (address..)
+        .step_by(MAX_CORE_DATA)
+        .zip(memory.chunks(MAX_CORE_DATA))
+        .filter(|(_, data)| data.iter().any(|&b| b != 0))
+        .map(|(address, data)| Message::CoreMemory { address, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+
+    use super::*;
+    use crate::{tests::program, BASE};
+
+    #[test]
+    fn leaves_zero_chunks_out_of_core_dumps() {
+        let mut memory = program(MAX_CORE_DATA * 5 + 10);
+        memory[MAX_CORE_DATA..MAX_CORE_DATA * 3].fill(0);
+        let mut restored = vec![0; memory.len()];
+        let mut sent = 0;
+        for message in memory_messages(BASE, &memory) {
+            let Message::CoreMemory { address, data } = message else {
+                panic!("expected memory, got {message:?}");
+            };
+            let start = (address - BASE) as usize;
+            restored[start..start + data.len()].copy_from_slice(data);
+            sent += 1;
+        }
+        assert_eq!(restored, memory);
+        assert_eq!(sent, 4);
+    }
+}
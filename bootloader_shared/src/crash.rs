@@ -0,0 +1,174 @@
+//! A report of why the last program crashed, kept in memory across a reset.
+//!
+//! The fault and panic handlers write a [CrashReport] to [CRASH_REPORT] before
+//! reporting to the installer, so the crash can still be told on the next boot
+//! if no host was listening. RAM keeps its contents through a watchdog reset,
+//! and [CRASH_REPORT] is below [crate::BASE] where neither loading nor
+//! clearing the BSS touches it.
+use core::fmt;
+
+use crate::{core_dump::CORE_REGISTERS, CRC_ALGORITHM};
+
+/// Where the [CrashReport] is kept, after the
+/// [crate::boot_info::BootInfo].
+pub const CRASH_REPORT: u32 = 0x7400;
+/// Longest message kept, in bytes.
+pub const MAX_CRASH_MESSAGE: usize = 256;
+const CRASH_MAGIC: u32 = 0xC4A5_4ED0;
+
+/// What went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Panic = 1,
+    DataAbort = 2,
+    PrefetchAbort = 3,
+    UndefinedInstruction = 4,
+}
+
+impl Reason {
+    fn from_u32(reason: u32) -> Option<Self> {
+        Some(match reason {
+            1 => Self::Panic,
+            2 => Self::DataAbort,
+            3 => Self::PrefetchAbort,
+            4 => Self::UndefinedInstruction,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Panic => "panic",
+            Self::DataAbort => "data abort",
+            Self::PrefetchAbort => "prefetch abort",
+            Self::UndefinedInstruction => "undefined instruction",
+        })
+    }
+}
+
+/// Kept at [CRASH_REPORT]. Written through [fmt::Write] to add to the
+/// message, which is cut short once full.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashReport {
+    magic: u32,
+    /// Of everything after it.
+    checksum: u32,
+    reason: u32,
+    /// r0 to r15, then the cpsr. Zeros for a panic, whose message says where
+    /// it happened.
+    pub registers: [u32; CORE_REGISTERS],
+    length: u32,
+    message: [u8; MAX_CRASH_MESSAGE],
+}
+
+impl CrashReport {
+    pub fn new(reason: Reason, registers: [u32; CORE_REGISTERS]) -> Self {
+        let mut report = Self {
+            magic: CRASH_MAGIC,
+            checksum: 0,
+            reason: reason as u32,
+            registers,
+            length: 0,
+            message: [0; MAX_CRASH_MESSAGE],
+        };
+        report.checksum = report.compute_checksum();
+        report
+    }
+
+    /// Whether this was written by a crash, rather than being whatever
+    /// happened to be in memory.
+    pub fn is_valid(&self) -> bool {
+        self.magic == CRASH_MAGIC
+            && self.length as usize <= MAX_CRASH_MESSAGE
+            && Reason::from_u32(self.reason).is_some()
+            && self.checksum == self.compute_checksum()
+    }
+
+    /// Scramble the report so it isn't told twice.
+    pub fn clear(&mut self) {
+        self.magic = 0;
+    }
+
+    /// `None` if the report isn't [valid](Self::is_valid).
+    pub fn reason(&self) -> Option<Reason> {
+        Reason::from_u32(self.reason)
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.registers[15]
+    }
+
+    /// The message, empty if it isn't UTF-8.
+    pub fn message(&self) -> &str {
+        let length = (self.length as usize).min(MAX_CRASH_MESSAGE);
+        core::str::from_utf8(&self.message[..length]).unwrap_or("")
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let mut digest = CRC_ALGORITHM.digest();
+        digest.update(&self.reason.to_le_bytes());
+        for register in self.registers {
+            digest.update(&register.to_le_bytes());
+        }
+        digest.update(&self.length.to_le_bytes());
+        digest.update(&self.message);
+        digest.finalize()
+    }
+}
+
+impl fmt::Write for CrashReport {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = (self.length as usize).min(MAX_CRASH_MESSAGE);
+        let mut length = s.len().min(MAX_CRASH_MESSAGE - start);
+        // Cut at a character, so the message stays UTF-8.
+        while !s.is_char_boundary(length) {
+            length -= 1;
+        }
+        self.message[start..start + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.length = (start + length) as u32;
+        self.checksum = self.compute_checksum();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::*;
+
+    #[test]
+    fn keeps_crash_reports() {
+        let registers = core::array::from_fn(|i| 0x1000 + i as u32);
+        let mut report = CrashReport::new(Reason::DataAbort, registers);
+        write!(report, "unexpected data abort: pc={:#010x}", report.pc()).unwrap();
+        assert!(report.is_valid());
+        assert_eq!(report.reason(), Some(Reason::DataAbort));
+        assert_eq!(report.pc(), 0x100f);
+        assert_eq!(report.message(), "unexpected data abort: pc=0x0000100f");
+
+        report.clear();
+        assert!(!report.is_valid());
+    }
+
+    #[test]
+    fn rejects_corrupted_crash_reports() {
+        let mut report = CrashReport::new(Reason::Panic, [0; CORE_REGISTERS]);
+        report.write_str("oops").unwrap();
+        report.registers[3] = 1;
+        assert!(!report.is_valid());
+    }
+
+    #[test]
+    fn cuts_long_crash_messages_at_a_character() {
+        let mut report = CrashReport::new(Reason::Panic, [0; CORE_REGISTERS]);
+        let message = "é".repeat(MAX_CRASH_MESSAGE);
+        report.write_str(&message).unwrap();
+        report.write_str("more").unwrap();
+        assert!(report.is_valid());
+        assert_eq!(report.message(), &message[..MAX_CRASH_MESSAGE]);
+    }
+}
@@ -0,0 +1,713 @@
+//! Just enough FAT32 to replace a file in the root directory of an SD card,
+//! as the bootloader does to update `kernel.img` over the wire.
+//!
+//! The card may have a partition table, in which case the first partition is
+//! used, or hold the filesystem directly. Only short (8.3) names are written.
+//! Long names of replaced entries are deleted, so other systems show the short
+//! name instead of a stale long one.
+use crate::CRC_ALGORITHM;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Size of a directory entry.
+const ENTRY_SIZE: usize = 32;
+/// Partition types of FAT32, with CHS and LBA addressing.
+const PARTITION_FAT32: [u8; 2] = [0x0B, 0x0C];
+/// Cluster values at or above this end a chain.
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Case flags of a short name, shown lowercase by systems that support it.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+const DELETED: u8 = 0xE5;
+/// Most long name entries a file can have.
+const MAX_LONG_NAME_ENTRIES: usize = 20;
+
+/// Storage read and written a sector at a time.
+pub trait BlockDevice {
+    fn read(&mut self, sector: u32, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), Error>;
+    fn write(&mut self, sector: u32, buf: &[u8; SECTOR_SIZE]) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The device failed to read or write a sector.
+    Device { sector: u32 },
+    /// Neither the card nor its first partition holds a FAT32 filesystem.
+    NotFat32,
+    /// The name doesn't fit in 8.3 characters.
+    InvalidName,
+    /// There are not enough free clusters for the file.
+    Full,
+    /// The root directory has no free entry for the file.
+    DirectoryFull,
+    /// A cluster chain points outside the filesystem.
+    Corrupt { cluster: u32 },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Device { sector } => write!(f, "failed to access sector {sector}"),
+            Error::NotFat32 => write!(f, "no FAT32 filesystem found"),
+            Error::InvalidName => write!(f, "name is not a valid 8.3 name"),
+            Error::Full => write!(f, "not enough free space"),
+            Error::DirectoryFull => write!(f, "root directory is full"),
+            Error::Corrupt { cluster } => write!(f, "cluster chain is broken at {cluster}"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// Where a directory entry is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    sector: u32,
+    offset: usize,
+}
+
+/// A short directory entry and the long name entries before it.
+#[derive(Debug, Clone, Copy)]
+struct Found {
+    location: Location,
+    entry: [u8; ENTRY_SIZE],
+    long_name: [Location; MAX_LONG_NAME_ENTRIES],
+    long_name_count: usize,
+}
+
+/// Position while walking the root directory.
+#[derive(Debug, Clone, Copy)]
+struct Walk {
+    cluster: u32,
+    /// Index of the entry in the cluster.
+    index: usize,
+}
+
+pub struct Fat32<D> {
+    device: D,
+    /// First sector of the first FAT.
+    fat_start: u32,
+    /// Sectors of each FAT.
+    fat_length: u32,
+    fat_count: u32,
+    /// First sector of cluster 2.
+    data_start: u32,
+    sectors_per_cluster: u32,
+    /// Clusters run from 2 to `cluster_end`.
+    cluster_end: u32,
+    root_cluster: u32,
+    fs_info: Option<u32>,
+    /// The sector last read, which writes of it go through.
+    cached: Option<u32>,
+    sector: [u8; SECTOR_SIZE],
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Find the filesystem on `device`.
+    pub fn open(mut device: D) -> Result<Self, Error> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read(0, &mut sector)?;
+        let start = if is_boot_sector(&sector) {
+            0
+        } else if sector[510..512] == [0x55, 0xAA] && PARTITION_FAT32.contains(&sector[0x1C2]) {
+            let start = u32_at(&sector, 0x1C6);
+            device.read(start, &mut sector)?;
+            if !is_boot_sector(&sector) {
+                return Err(Error::NotFat32);
+            }
+            start
+        } else {
+            return Err(Error::NotFat32);
+        };
+
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved = u16_at(&sector, 14) as u32;
+        let fat_count = sector[16] as u32;
+        let total = u32_at(&sector, 32);
+        let fat_length = u32_at(&sector, 36);
+        let root_cluster = u32_at(&sector, 44);
+        let fs_info = match u16_at(&sector, 48) {
+            0 | 0xFFFF => None,
+            fs_info => Some(start + fs_info as u32),
+        };
+        let data_start = start + reserved + fat_count * fat_length;
+        let data_length = (total + start)
+            .checked_sub(data_start)
+            .ok_or(Error::NotFat32)?;
+        // Limited by both the data area and the FAT.
+        let cluster_end = (data_length / sectors_per_cluster + 2)
+            .min(fat_length * (SECTOR_SIZE as u32 / 4))
+            .min(END_OF_CHAIN);
+        if !(2..cluster_end).contains(&root_cluster) {
+            return Err(Error::NotFat32);
+        }
+        Ok(Self {
+            device,
+            fat_start: start + reserved,
+            fat_length,
+            fat_count,
+            data_start,
+            sectors_per_cluster,
+            cluster_end,
+            root_cluster,
+            fs_info,
+            cached: None,
+            sector: [0; SECTOR_SIZE],
+        })
+    }
+
+    /// Give back the device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Pass the contents of the root directory file `name` to `f` in pieces,
+    /// returning whether it exists.
+    pub fn read_file(&mut self, name: &str, mut f: impl FnMut(&[u8])) -> Result<bool, Error> {
+        let (name, _) = short_name(name)?;
+        let Some(found) = self.find(&name)? else {
+            return Ok(false);
+        };
+        let mut remaining = u32_at(&found.entry, 28) as usize;
+        let mut cluster = first_cluster(&found.entry);
+        while remaining > 0 {
+            self.check_cluster(cluster)?;
+            for sector in self.cluster_sectors(cluster) {
+                if remaining == 0 {
+                    break;
+                }
+                let data = self.read(sector)?;
+                let length = remaining.min(SECTOR_SIZE);
+                f(&data[..length]);
+                remaining -= length;
+            }
+            cluster = self.fat_entry(cluster)?;
+        }
+        Ok(true)
+    }
+
+    /// Checksum of the root directory file `name` as [CRC_ALGORITHM] computes
+    /// it, or `None` if it doesn't exist.
+    pub fn checksum(&mut self, name: &str) -> Result<Option<u32>, Error> {
+        let mut digest = CRC_ALGORITHM.digest_with_initial(0);
+        let exists = self.read_file(name, |data| digest.update(data))?;
+        Ok(exists.then(|| digest.finalize()))
+    }
+
+    /// Write `data` as the root directory file `name`, renaming the current
+    /// file to `backup` and deleting the previous backup.
+    ///
+    /// The data is written before any directory entry changes, and the new
+    /// entry is added before the old one is renamed, so the card always holds
+    /// either file under `name`.
+    pub fn replace(&mut self, name: &str, backup: &str, data: &[u8]) -> Result<(), Error> {
+        let (name, name_case) = short_name(name)?;
+        let (backup, backup_case) = short_name(backup)?;
+        let first = self.write_chain(data)?;
+
+        if let Some(old_backup) = self.find(&backup)? {
+            self.free_chain(first_cluster(&old_backup.entry))?;
+            self.delete(&old_backup)?;
+        }
+        let old = self.find(&name)?;
+        let Some(free) = self.find_free()? else {
+            self.free_chain(first)?;
+            return Err(Error::DirectoryFull);
+        };
+
+        let mut entry = [0; ENTRY_SIZE];
+        // Keep the times of the old file, there is no clock to set new ones.
+        if let Some(old) = &old {
+            entry[13..26].copy_from_slice(&old.entry[13..26]);
+        }
+        entry[..11].copy_from_slice(&name);
+        entry[11] = ATTR_ARCHIVE;
+        entry[12] = name_case;
+        entry[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.write_entry(free, &entry)?;
+
+        if let Some(old) = old {
+            let mut entry = old.entry;
+            entry[..11].copy_from_slice(&backup);
+            entry[12] = backup_case;
+            self.write_entry(old.location, &entry)?;
+            self.delete_long_name(&old)?;
+        }
+        self.forget_free_count()
+    }
+
+    /// Write `data` to newly allocated clusters, returning the first one, or 0
+    /// for no data.
+    fn write_chain(&mut self, data: &[u8]) -> Result<u32, Error> {
+        let mut first = 0;
+        let mut previous = 0;
+        let mut search = 2;
+        for chunk in data.chunks(self.sectors_per_cluster as usize * SECTOR_SIZE) {
+            let cluster = match self.next_free(search) {
+                Ok(cluster) => cluster,
+                Err(e) => {
+                    if first != 0 {
+                        self.free_chain(first)?;
+                    }
+                    return Err(e);
+                }
+            };
+            for (sector, part) in self.cluster_sectors(cluster).zip(chunk.chunks(SECTOR_SIZE)) {
+                let mut buf = [0; SECTOR_SIZE];
+                buf[..part.len()].copy_from_slice(part);
+                self.device.write(sector, &buf)?;
+                if self.cached == Some(sector) {
+                    self.cached = None;
+                }
+            }
+            self.set_fat_entry(cluster, FAT_MASK)?;
+            if previous == 0 {
+                first = cluster;
+            } else {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            previous = cluster;
+            search = cluster + 1;
+        }
+        Ok(first)
+    }
+
+    /// Free the clusters of a chain starting at `cluster`, which may be 0 for
+    /// an empty file.
+    fn free_chain(&mut self, mut cluster: u32) -> Result<(), Error> {
+        let mut remaining = self.cluster_end;
+        while cluster != 0 && cluster < END_OF_CHAIN {
+            self.check_cluster(cluster)?;
+            remaining = remaining.checked_sub(1).ok_or(Error::Corrupt { cluster })?;
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    fn next_free(&mut self, start: u32) -> Result<u32, Error> {
+        for cluster in start..self.cluster_end {
+            if self.fat_entry(cluster)? == 0 {
+                return Ok(cluster);
+            }
+        }
+        Err(Error::Full)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, Error> {
+        let (sector, offset) = self.fat_position(cluster);
+        Ok(u32_at(self.read(sector)?, offset) & FAT_MASK)
+    }
+
+    /// Set an entry in every FAT, keeping its reserved top bits.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+        let (sector, offset) = self.fat_position(cluster);
+        let data = self.read(sector)?;
+        let old = u32_at(data, offset);
+        let new = (old & !FAT_MASK) | (value & FAT_MASK);
+        data[offset..offset + 4].copy_from_slice(&new.to_le_bytes());
+        for copy in 0..self.fat_count {
+            self.device
+                .write(sector + copy * self.fat_length, &self.sector)?;
+        }
+        Ok(())
+    }
+
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = cluster as usize * 4;
+        (
+            self.fat_start + (offset / SECTOR_SIZE) as u32,
+            offset % SECTOR_SIZE,
+        )
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), Error> {
+        if !(2..self.cluster_end).contains(&cluster) {
+            return Err(Error::Corrupt { cluster });
+        }
+        Ok(())
+    }
+
+    fn cluster_sectors(&self, cluster: u32) -> core::ops::Range<u32> {
+        let start = self.data_start + (cluster - 2) * self.sectors_per_cluster;
+        start..start + self.sectors_per_cluster
+    }
+
+    /// The next root directory entry, or `None` after the last cluster.
+    fn next_entry(&mut self, walk: &mut Walk) -> Result<Option<Location>, Error> {
+        let per_cluster = self.sectors_per_cluster as usize * SECTOR_SIZE / ENTRY_SIZE;
+        if walk.index == per_cluster {
+            let next = self.fat_entry(walk.cluster)?;
+            if next >= END_OF_CHAIN {
+                return Ok(None);
+            }
+            self.check_cluster(next)?;
+            *walk = Walk {
+                cluster: next,
+                index: 0,
+            };
+        }
+        let offset = walk.index * ENTRY_SIZE;
+        walk.index += 1;
+        Ok(Some(Location {
+            sector: self.cluster_sectors(walk.cluster).start + (offset / SECTOR_SIZE) as u32,
+            offset: offset % SECTOR_SIZE,
+        }))
+    }
+
+    fn root(&self) -> Walk {
+        Walk {
+            cluster: self.root_cluster,
+            index: 0,
+        }
+    }
+
+    fn entry(&mut self, location: Location) -> Result<[u8; ENTRY_SIZE], Error> {
+        let data = self.read(location.sector)?;
+        Ok(data[location.offset..location.offset + ENTRY_SIZE]
+            .try_into()
+            .unwrap())
+    }
+
+    fn write_entry(&mut self, location: Location, entry: &[u8; ENTRY_SIZE]) -> Result<(), Error> {
+        let data = self.read(location.sector)?;
+        data[location.offset..location.offset + ENTRY_SIZE].copy_from_slice(entry);
+        self.device.write(location.sector, &self.sector)
+    }
+
+    /// Find the file with the short name `name` in the root directory.
+    fn find(&mut self, name: &[u8; 11]) -> Result<Option<Found>, Error> {
+        let mut walk = self.root();
+        let mut long_name = [Location {
+            sector: 0,
+            offset: 0,
+        }; MAX_LONG_NAME_ENTRIES];
+        let mut long_name_count = 0;
+        while let Some(location) = self.next_entry(&mut walk)? {
+            let entry = self.entry(location)?;
+            match entry[0] {
+                0 => break,
+                DELETED => long_name_count = 0,
+                _ if entry[11] == ATTR_LONG_NAME => {
+                    if long_name_count < MAX_LONG_NAME_ENTRIES {
+                        long_name[long_name_count] = location;
+                        long_name_count += 1;
+                    }
+                }
+                _ if entry[11] & ATTR_VOLUME_ID != 0 => long_name_count = 0,
+                _ if entry[..11] == name[..] => {
+                    return Ok(Some(Found {
+                        location,
+                        entry,
+                        long_name,
+                        long_name_count,
+                    }))
+                }
+                _ => long_name_count = 0,
+            }
+        }
+        Ok(None)
+    }
+
+    /// The first unused root directory entry.
+    fn find_free(&mut self) -> Result<Option<Location>, Error> {
+        let mut walk = self.root();
+        while let Some(location) = self.next_entry(&mut walk)? {
+            if matches!(self.entry(location)?[0], 0 | DELETED) {
+                return Ok(Some(location));
+            }
+        }
+        Ok(None)
+    }
+
+    fn delete(&mut self, found: &Found) -> Result<(), Error> {
+        let mut entry = found.entry;
+        entry[0] = DELETED;
+        self.write_entry(found.location, &entry)?;
+        self.delete_long_name(found)
+    }
+
+    fn delete_long_name(&mut self, found: &Found) -> Result<(), Error> {
+        for &location in &found.long_name[..found.long_name_count] {
+            let mut entry = self.entry(location)?;
+            entry[0] = DELETED;
+            self.write_entry(location, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the free cluster count in the FSInfo sector as unknown, rather
+    /// than keeping track of it.
+    fn forget_free_count(&mut self) -> Result<(), Error> {
+        let Some(sector) = self.fs_info else {
+            return Ok(());
+        };
+        let data = self.read(sector)?;
+        if data[..4] != *b"RRaA" || data[484..488] != *b"rrAa" {
+            return Ok(());
+        }
+        data[488..492].copy_from_slice(&u32::MAX.to_le_bytes());
+        self.device.write(sector, &self.sector)
+    }
+
+    fn read(&mut self, sector: u32) -> Result<&mut [u8; SECTOR_SIZE], Error> {
+        if self.cached != Some(sector) {
+            self.cached = None;
+            self.device.read(sector, &mut self.sector)?;
+            self.cached = Some(sector);
+        }
+        Ok(&mut self.sector)
+    }
+}
+
+/// Whether `sector` starts a FAT32 filesystem with 512 byte sectors.
+fn is_boot_sector(sector: &[u8; SECTOR_SIZE]) -> bool {
+    let sectors_per_cluster = sector[13];
+    sector[510..512] == [0x55, 0xAA]
+        && u16_at(sector, 11) as usize == SECTOR_SIZE
+        && sectors_per_cluster.is_power_of_two()
+        && sector[16] > 0
+        // FAT12 and FAT16 have a fixed root directory and a 16 bit FAT size.
+        && u16_at(sector, 17) == 0
+        && u16_at(sector, 22) == 0
+        && u32_at(sector, 36) > 0
+}
+
+/// The 8.3 name as stored in a directory entry, and its case flags.
+fn short_name(name: &str) -> Result<([u8; 11], u8), Error> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Err(Error::InvalidName);
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, range, lower) in [
+        (base, 0..8, CASE_LOWER_BASE),
+        (extension, 8..11, CASE_LOWER_EXTENSION),
+    ] {
+        if part.bytes().any(|c| c.is_ascii_lowercase()) {
+            if part.bytes().any(|c| c.is_ascii_uppercase()) {
+                return Err(Error::InvalidName);
+            }
+            case |= lower;
+        }
+        for (short, c) in short[range].iter_mut().zip(part.bytes()) {
+            if !(c.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&c)) {
+                return Err(Error::InvalidName);
+            }
+            *short = c.to_ascii_uppercase();
+        }
+    }
+    Ok((short, case))
+}
+
+fn first_cluster(entry: &[u8; ENTRY_SIZE]) -> u32 {
+    ((u16_at(entry, 20) as u32) << 16) | u16_at(entry, 26) as u32
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::{
+        installer::Image,
+        tests::{bootloader, program},
+    };
+
+    /// An SD card in memory.
+    struct Card(Vec<u8>);
+
+    impl BlockDevice for Card {
+        fn read(&mut self, sector: u32, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), Error> {
+            let start = sector as usize * SECTOR_SIZE;
+            let data = self.0.get(start..start + SECTOR_SIZE);
+            buf.copy_from_slice(data.ok_or(Error::Device { sector })?);
+            Ok(())
+        }
+
+        fn write(&mut self, sector: u32, buf: &[u8; SECTOR_SIZE]) -> Result<(), Error> {
+            let start = sector as usize * SECTOR_SIZE;
+            let data = self.0.get_mut(start..start + SECTOR_SIZE);
+            data.ok_or(Error::Device { sector })?.copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// Where the partition starts on [format]ted cards.
+    const PARTITION: usize = 8;
+    const RESERVED: usize = 32;
+
+    fn fat_length(clusters: usize) -> usize {
+        ((clusters + 2) * 4).div_ceil(SECTOR_SIZE)
+    }
+
+    /// A card with a partition table and an empty FAT32 filesystem of
+    /// `clusters` one sector clusters, the first of which holds the root
+    /// directory.
+    fn format(clusters: usize) -> Card {
+        let fat_length = fat_length(clusters);
+        let total = RESERVED + 2 * fat_length + clusters;
+        let mut card = vec![0; (PARTITION + total) * SECTOR_SIZE];
+        let mbr = &mut card[..SECTOR_SIZE];
+        mbr[0x1C2] = 0x0C;
+        mbr[0x1C6..0x1CA].copy_from_slice(&(PARTITION as u32).to_le_bytes());
+        mbr[0x1CA..0x1CE].copy_from_slice(&(total as u32).to_le_bytes());
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let partition = &mut card[PARTITION * SECTOR_SIZE..];
+        let boot = &mut partition[..SECTOR_SIZE];
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[32..36].copy_from_slice(&(total as u32).to_le_bytes());
+        boot[36..40].copy_from_slice(&(fat_length as u32).to_le_bytes());
+        boot[44..48].copy_from_slice(&2_u32.to_le_bytes());
+        boot[48..50].copy_from_slice(&1_u16.to_le_bytes());
+        boot[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let fs_info = &mut partition[SECTOR_SIZE..2 * SECTOR_SIZE];
+        fs_info[..4].copy_from_slice(b"RRaA");
+        fs_info[484..488].copy_from_slice(b"rrAa");
+        fs_info[488..492].copy_from_slice(&(clusters as u32 - 1).to_le_bytes());
+        fs_info[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        for copy in 0..2 {
+            let start = (RESERVED + copy * fat_length) * SECTOR_SIZE;
+            for (index, value) in [0x0FFF_FFF8_u32, 0x0FFF_FFFF, 0x0FFF_FFFF]
+                .iter()
+                .enumerate()
+            {
+                let offset = start + index * 4;
+                partition[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        Card(card)
+    }
+
+    /// Byte offset of the root directory on a [format]ted card.
+    fn root(clusters: usize) -> usize {
+        (PARTITION + RESERVED + 2 * fat_length(clusters)) * SECTOR_SIZE
+    }
+
+    fn read_file(fat: &mut Fat32<Card>, name: &str) -> Option<Vec<u8>> {
+        let mut contents = Vec::new();
+        let exists = fat
+            .read_file(name, |data| contents.extend_from_slice(data))
+            .unwrap();
+        exists.then_some(contents)
+    }
+
+    #[test]
+    fn writes_file_to_empty_card() {
+        let mut fat = Fat32::open(format(100)).unwrap();
+        let kernel = program(3000);
+        fat.replace("kernel.img", "kernel.bak", &kernel).unwrap();
+        assert_eq!(read_file(&mut fat, "kernel.img"), Some(kernel.clone()));
+        assert_eq!(read_file(&mut fat, "kernel.bak"), None);
+
+        // Written to the card, in lowercase.
+        let card = fat.into_inner();
+        let entry = &card.0[root(100)..root(100) + 32];
+        assert_eq!(&entry[..11], b"KERNEL  IMG");
+        assert_eq!(entry[12], 0x18);
+        let mut fat = Fat32::open(card).unwrap();
+        assert_eq!(read_file(&mut fat, "kernel.img"), Some(kernel));
+    }
+
+    #[test]
+    fn keeps_replaced_file_as_backup() {
+        let mut fat = Fat32::open(format(100)).unwrap();
+        let kernels = [program(3000), program(700), bootloader(1500)];
+        for kernel in &kernels {
+            fat.replace("kernel.img", "kernel.bak", kernel).unwrap();
+        }
+        assert_eq!(
+            read_file(&mut fat, "kernel.img").as_ref(),
+            Some(&kernels[2])
+        );
+        assert_eq!(
+            read_file(&mut fat, "kernel.bak").as_ref(),
+            Some(&kernels[1])
+        );
+        assert_eq!(
+            fat.checksum("kernel.img"),
+            Ok(Some(Image::raw(&kernels[2]).checksum()))
+        );
+        assert_eq!(fat.checksum("missing.img"), Ok(None));
+    }
+
+    #[test]
+    fn frees_old_backups() {
+        // Room for three copies, but not if old backups were kept around.
+        let mut fat = Fat32::open(format(64)).unwrap();
+        let kernel = program(20 * SECTOR_SIZE);
+        for _ in 0..10 {
+            fat.replace("kernel.img", "kernel.bak", &kernel).unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_file_when_card_is_full() {
+        let mut fat = Fat32::open(format(16)).unwrap();
+        let kernel = program(10 * SECTOR_SIZE);
+        fat.replace("kernel.img", "kernel.bak", &kernel).unwrap();
+        assert_eq!(
+            fat.replace("kernel.img", "kernel.bak", &program(11 * SECTOR_SIZE)),
+            Err(Error::Full)
+        );
+        assert_eq!(read_file(&mut fat, "kernel.img"), Some(kernel));
+        // The clusters of the failed write were freed again.
+        fat.replace("config.txt", "config.bak", &program(5 * SECTOR_SIZE))
+            .unwrap();
+    }
+
+    #[test]
+    fn deletes_long_name_of_replaced_file() {
+        let mut fat = Fat32::open(format(100)).unwrap();
+        fat.replace("kernel.img", "kernel.bak", &program(100))
+            .unwrap();
+        // Move the entry along to make room for a long name entry before it.
+        let mut card = fat.into_inner();
+        let root = root(100);
+        card.0.copy_within(root..root + 32, root + 32);
+        card.0[root] = 0x41;
+        card.0[root + 11] = 0x0F;
+
+        let mut fat = Fat32::open(card).unwrap();
+        fat.replace("kernel.img", "kernel.bak", &program(200))
+            .unwrap();
+        let card = fat.into_inner();
+        assert_eq!(card.0[root], 0xE5);
+        assert_eq!(&card.0[root + 32..root + 43], b"KERNEL  BAK");
+    }
+
+    #[test]
+    fn rejects_card_without_fat32() {
+        assert!(matches!(
+            Fat32::open(Card(vec![0; 16 * SECTOR_SIZE])),
+            Err(Error::NotFat32)
+        ));
+        let mut fat = Fat32::open(format(100)).unwrap();
+        assert_eq!(
+            fat.replace("kernel.image", "kernel.bak", &[]),
+            Err(Error::InvalidName)
+        );
+    }
+}
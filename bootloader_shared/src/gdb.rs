@@ -0,0 +1,697 @@
+//! A stub for GDB's remote serial protocol, to debug programs on the Pi with
+//! `arm-none-eabi-gdb`.
+//!
+//! The Pi runs a [Stub] while the program is stopped: feed it the bytes GDB
+//! sends with [Stub::receive], which acts on the [Target], and send whatever
+//! [Stub::transmit] returns. Once GDB continues or steps the program, tell the
+//! stub why it stopped again with [Stub::stopped].
+//!
+//! The stub's output reaches the installer in [Message::Gdb] frames, which
+//! forwards it to GDB over TCP. GDB's packets come back as raw program input,
+//! with anything outside a packet ignored.
+//!
+//! Registers are as GDB expects for ARM without a target description: r0 to
+//! r15, eight FPA registers and fps, which always read as zero, then the cpsr.
+//!
+//! [Message::Gdb]: crate::message::Message::Gdb
+
+/// Most bytes between the `$` and `#` of a packet, either way.
+pub const MAX_PACKET: usize = 512;
+/// Registers of a [Target]: r0 to r15, then the cpsr.
+pub const REGISTERS: usize = 17;
+
+const PC: usize = 15;
+const CPSR: usize = 16;
+/// GDB's number for the cpsr, after the FPA registers and fps.
+const GDB_CPSR: usize = 25;
+/// Bytes of the FPA registers and fps in a `g` packet.
+const FPA_LENGTH: usize = 8 * 12 + 4;
+/// Bytes of a `g` packet.
+const REGISTERS_LENGTH: usize = 16 * 4 + FPA_LENGTH + 4;
+/// `$`, `#` and the checksum around a packet.
+const FRAMING: usize = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// The kinds of breakpoint in `Z` packets, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Patched into memory as a `bkpt` instruction.
+    Software,
+    Hardware,
+    /// A watchpoint on writes.
+    Write,
+    Read,
+    Access,
+}
+
+impl Breakpoint {
+    fn from_word(word: u32) -> Option<Self> {
+        Some(match word {
+            0 => Breakpoint::Software,
+            1 => Breakpoint::Hardware,
+            2 => Breakpoint::Write,
+            3 => Breakpoint::Read,
+            4 => Breakpoint::Access,
+            _ => return None,
+        })
+    }
+}
+
+/// Why the program stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// A breakpoint, a finished step or a `bkpt` instruction in the program.
+    Trap,
+    /// A watchpoint on `address` was hit.
+    Watch { kind: Breakpoint, address: u32 },
+    /// An abort that isn't a debug event, reported as a segmentation fault.
+    Fault,
+}
+
+/// What GDB asked the program to do once it stops being debugged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    /// Run a single instruction.
+    Step,
+    /// Continue without GDB, which removed its breakpoints first.
+    Detach,
+    Kill,
+}
+
+/// The stopped program, as seen by the stub.
+pub trait Target {
+    /// r0 to r15, then the cpsr.
+    fn registers(&mut self) -> [u32; REGISTERS];
+    fn set_registers(&mut self, registers: [u32; REGISTERS]);
+    /// Returns false if some of the memory can't be read.
+    fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool;
+    /// Returns false if some of the memory can't be written.
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool;
+    /// Returns false if the breakpoint can't be set, as when the hardware
+    /// has none left.
+    fn insert(&mut self, kind: Breakpoint, address: u32, length: u32) -> bool;
+    fn remove(&mut self, kind: Breakpoint, address: u32, length: u32) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a `$`.
+    Idle,
+    Data,
+    Checksum,
+    /// The first digit of the checksum arrived.
+    ChecksumDigit(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct Stub {
+    state: State,
+    packet: [u8; MAX_PACKET],
+    length: usize,
+    /// The packet didn't fit, so it is refused.
+    overflowed: bool,
+    checksum: u8,
+    /// The last packet sent, to send again if GDB asks.
+    last: [u8; MAX_PACKET + FRAMING],
+    last_length: usize,
+    output: [u8; MAX_PACKET + FRAMING + 1],
+    output_length: usize,
+    stop: Stop,
+    /// GDB resumed the program and waits to hear why it stopped.
+    running: bool,
+}
+
+impl Stub {
+    /// A stub for a program that stopped because of `stop`, before GDB is
+    /// attached.
+    pub fn new(stop: Stop) -> Self {
+        Self {
+            state: State::Idle,
+            packet: [0; MAX_PACKET],
+            length: 0,
+            overflowed: false,
+            checksum: 0,
+            last: [0; MAX_PACKET + FRAMING],
+            last_length: 0,
+            output: [0; MAX_PACKET + FRAMING + 1],
+            output_length: 0,
+            stop,
+            running: false,
+        }
+    }
+
+    /// The program stopped again after GDB resumed it.
+    pub fn stopped(&mut self, stop: Stop) {
+        self.stop = stop;
+        if core::mem::take(&mut self.running) {
+            let mut reply = Reply::new();
+            reply.stop(stop);
+            self.send(&reply);
+        }
+    }
+
+    /// Bytes to send to GDB. Call after each [Stub::receive] and
+    /// [Stub::stopped].
+    pub fn transmit(&mut self) -> Option<&[u8]> {
+        let length = core::mem::take(&mut self.output_length);
+        (length > 0).then(|| &self.output[..length])
+    }
+
+    /// Take a byte from GDB, returning what to do with the program once GDB
+    /// resumes it.
+    pub fn receive(&mut self, byte: u8, target: &mut impl Target) -> Option<Resume> {
+        match (self.state, byte) {
+            // Also starts a packet over, as after a lost `#`.
+            (_, b'$') => {
+                self.state = State::Data;
+                self.length = 0;
+                self.overflowed = false;
+                self.checksum = 0;
+            }
+            (State::Idle, b'-') => {
+                self.output[..self.last_length].copy_from_slice(&self.last[..self.last_length]);
+                self.output_length = self.last_length;
+            }
+            // Acks, and interrupts from a GDB that missed the stop.
+            (State::Idle, _) => {}
+            (State::Data, b'#') => self.state = State::Checksum,
+            (State::Data, _) => {
+                self.checksum = self.checksum.wrapping_add(byte);
+                if self.length == MAX_PACKET {
+                    self.overflowed = true;
+                } else {
+                    self.packet[self.length] = byte;
+                    self.length += 1;
+                }
+            }
+            (State::Checksum, _) => match hex_digit(byte) {
+                Some(digit) => self.state = State::ChecksumDigit(digit),
+                None => self.refuse(),
+            },
+            (State::ChecksumDigit(high), _) => {
+                self.state = State::Idle;
+                match hex_digit(byte) {
+                    Some(low) if high << 4 | low == self.checksum && !self.overflowed => {
+                        self.output[0] = b'+';
+                        self.output_length = 1;
+                        return self.handle(target);
+                    }
+                    _ => self.refuse(),
+                }
+            }
+        }
+        None
+    }
+
+    /// Ask GDB to send the packet again.
+    fn refuse(&mut self) {
+        self.state = State::Idle;
+        self.output[0] = b'-';
+        self.output_length = 1;
+    }
+
+    /// Queue `reply` after what is already queued, keeping it to send again.
+    fn send(&mut self, reply: &Reply) {
+        let packet = reply.frame(&mut self.last);
+        self.last_length = packet.len();
+        let end = self.output_length + packet.len();
+        self.output[self.output_length..end].copy_from_slice(packet);
+        self.output_length = end;
+    }
+
+    fn handle(&mut self, target: &mut impl Target) -> Option<Resume> {
+        let packet = &self.packet[..self.length];
+        let mut reply = Reply::new();
+        let resume = match packet {
+            b"?" => {
+                reply.stop(self.stop);
+                None
+            }
+            b"g" => {
+                let registers = target.registers();
+                registers[..16].iter().for_each(|&r| reply.word(r));
+                reply.bytes(&[0; FPA_LENGTH]);
+                reply.word(registers[CPSR]);
+                None
+            }
+            [b'G', hex @ ..] => {
+                let mut bytes = [0; REGISTERS_LENGTH];
+                match decode_hex(hex, &mut bytes) {
+                    Some(REGISTERS_LENGTH) => {
+                        let mut registers = [0; REGISTERS];
+                        for (register, chunk) in registers[..16].iter_mut().zip(bytes.chunks(4)) {
+                            *register = u32::from_le_bytes(chunk.try_into().unwrap());
+                        }
+                        registers[CPSR] =
+                            u32::from_le_bytes(bytes[REGISTERS_LENGTH - 4..].try_into().unwrap());
+                        target.set_registers(registers);
+                        reply.ok();
+                    }
+                    _ => reply.error(),
+                }
+                None
+            }
+            [b'p', number @ ..] => {
+                match parse_hex(number).map(|n| n as usize) {
+                    Some(n @ 0..=15) => reply.word(target.registers()[n]),
+                    Some(16..=23) => reply.bytes(&[0; 12]),
+                    Some(24) => reply.bytes(&[0; 4]),
+                    Some(GDB_CPSR) => reply.word(target.registers()[CPSR]),
+                    _ => reply.error(),
+                }
+                None
+            }
+            [b'P', rest @ ..] => {
+                let mut bytes = [0; 4];
+                let parsed = split(rest, b'=').and_then(|(number, value)| {
+                    Some((parse_hex(number)? as usize, decode_hex(value, &mut bytes)?))
+                });
+                let index = match parsed {
+                    Some((n @ 0..=15, 4)) => Some(n),
+                    Some((GDB_CPSR, 4)) => Some(CPSR),
+                    _ => None,
+                };
+                match (index, parsed) {
+                    (Some(index), _) => {
+                        let mut registers = target.registers();
+                        registers[index] = u32::from_le_bytes(bytes);
+                        target.set_registers(registers);
+                        reply.ok();
+                    }
+                    // The FPA registers and fps ignore writes.
+                    (None, Some((16..=24, _))) => reply.ok(),
+                    (None, _) => reply.error(),
+                }
+                None
+            }
+            [b'm', rest @ ..] => {
+                let mut buf = [0; MAX_PACKET / 2];
+                match split(rest, b',').and_then(|(address, length)| {
+                    Some((parse_hex(address)?, parse_hex(length)? as usize))
+                }) {
+                    Some((address, length)) if length <= buf.len() => {
+                        if target.read_memory(address, &mut buf[..length]) {
+                            reply.bytes(&buf[..length]);
+                        } else {
+                            reply.error();
+                        }
+                    }
+                    _ => reply.error(),
+                }
+                None
+            }
+            [b'M', rest @ ..] => {
+                let mut buf = [0; MAX_PACKET / 2];
+                let parsed = split(rest, b',').and_then(|(address, rest)| {
+                    let (length, data) = split(rest, b':')?;
+                    let length = parse_hex(length)? as usize;
+                    (decode_hex(data, &mut buf)? == length).then_some((parse_hex(address)?, length))
+                });
+                match parsed {
+                    Some((address, length)) if target.write_memory(address, &buf[..length]) => {
+                        reply.ok()
+                    }
+                    _ => reply.error(),
+                }
+                None
+            }
+            [b'Z' | b'z', rest @ ..] => {
+                let parsed = split(rest, b',').and_then(|(kind, rest)| {
+                    let (address, length) = split(rest, b',')?;
+                    let kind = Breakpoint::from_word(parse_hex(kind)?)?;
+                    Some((kind, parse_hex(address)?, parse_hex(length)?))
+                });
+                // Unsupported kinds get an empty reply, as GDB expects.
+                if let Some((kind, address, length)) = parsed {
+                    let done = if packet[0] == b'Z' {
+                        target.insert(kind, address, length)
+                    } else {
+                        target.remove(kind, address, length)
+                    };
+                    if done {
+                        reply.ok();
+                    } else {
+                        reply.error();
+                    }
+                }
+                None
+            }
+            [command @ (b'c' | b's'), address @ ..] => {
+                if let Some(address) = parse_hex(address) {
+                    let mut registers = target.registers();
+                    registers[PC] = address;
+                    target.set_registers(registers);
+                }
+                self.running = true;
+                // The reply is sent once the program stops.
+                return Some(if *command == b'c' {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            [b'D', ..] => {
+                reply.ok();
+                Some(Resume::Detach)
+            }
+            b"k" => return Some(Resume::Kill),
+            _ if packet.starts_with(b"qSupported") => {
+                reply.str("PacketSize=");
+                reply.hex(MAX_PACKET as u32);
+                None
+            }
+            b"qAttached" => {
+                reply.str("1");
+                None
+            }
+            [b'H', ..] => {
+                reply.ok();
+                None
+            }
+            // Anything else is unsupported, which an empty reply says.
+            _ => None,
+        };
+        self.send(&reply);
+        resume
+    }
+}
+
+/// The data of a packet being built.
+struct Reply {
+    data: [u8; MAX_PACKET],
+    length: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self {
+            data: [0; MAX_PACKET],
+            length: 0,
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.data[self.length..self.length + s.len()].copy_from_slice(s.as_bytes());
+        self.length += s.len();
+    }
+
+    fn ok(&mut self) {
+        self.str("OK");
+    }
+
+    /// Reply with `E01`, as GDB only shows the number.
+    fn error(&mut self) {
+        self.str("E01");
+    }
+
+    /// Hex digits of `bytes`, in order.
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[self.length] = HEX[(byte >> 4) as usize];
+            self.data[self.length + 1] = HEX[(byte & 0xf) as usize];
+            self.length += 2;
+        }
+    }
+
+    /// A register, little endian.
+    fn word(&mut self, word: u32) {
+        self.bytes(&word.to_le_bytes());
+    }
+
+    /// A number, most significant digit first.
+    fn hex(&mut self, n: u32) {
+        self.bytes(&n.to_be_bytes());
+    }
+
+    fn stop(&mut self, stop: Stop) {
+        match stop {
+            Stop::Trap => {
+                self.str("S");
+                self.bytes(&[SIGTRAP]);
+            }
+            Stop::Fault => {
+                self.str("S");
+                self.bytes(&[SIGSEGV]);
+            }
+            Stop::Watch { kind, address } => {
+                self.str("T");
+                self.bytes(&[SIGTRAP]);
+                self.str(match kind {
+                    Breakpoint::Read => "rwatch:",
+                    Breakpoint::Access => "awatch:",
+                    _ => "watch:",
+                });
+                self.hex(address);
+                self.str(";");
+            }
+        }
+    }
+
+    /// The packet with its framing, in `buf`.
+    fn frame<'b>(&self, buf: &'b mut [u8; MAX_PACKET + FRAMING]) -> &'b [u8] {
+        let data = &self.data[..self.length];
+        let checksum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        let end = data.len() + 1;
+        buf[0] = b'$';
+        buf[1..end].copy_from_slice(data);
+        buf[end] = b'#';
+        buf[end + 1] = HEX[(checksum >> 4) as usize];
+        buf[end + 2] = HEX[(checksum & 0xf) as usize];
+        &buf[..end + 3]
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// A number of at most 8 hex digits.
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0, |n, &b| Some(n << 4 | hex_digit(b)? as u32))
+}
+
+/// Decode pairs of hex digits into `buf`, returning how many bytes there were.
+fn decode_hex(digits: &[u8], buf: &mut [u8]) -> Option<usize> {
+    if !digits.len().is_multiple_of(2) || digits.len() / 2 > buf.len() {
+        return None;
+    }
+    for (byte, pair) in buf.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+/// The parts before and after the first `separator`.
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{format, string::String, vec, vec::Vec};
+
+    use super::*;
+    use crate::BASE;
+
+    /// A stopped program with memory from [BASE].
+    #[derive(Default)]
+    struct Debuggee {
+        registers: [u32; REGISTERS],
+        memory: Vec<u8>,
+        breakpoints: Vec<(Breakpoint, u32, u32)>,
+    }
+
+    impl Debuggee {
+        fn memory(&mut self, address: u32, length: usize) -> Option<&mut [u8]> {
+            let start = address.checked_sub(BASE)? as usize;
+            self.memory.get_mut(start..start + length)
+        }
+    }
+
+    impl Target for Debuggee {
+        fn registers(&mut self) -> [u32; REGISTERS] {
+            self.registers
+        }
+
+        fn set_registers(&mut self, registers: [u32; REGISTERS]) {
+            self.registers = registers;
+        }
+
+        fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool {
+            self.memory(address, buf.len())
+                .map(|memory| buf.copy_from_slice(memory))
+                .is_some()
+        }
+
+        fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+            self.memory(address, data.len())
+                .map(|memory| memory.copy_from_slice(data))
+                .is_some()
+        }
+
+        fn insert(&mut self, kind: Breakpoint, address: u32, length: u32) -> bool {
+            // One watchpoint, as on the Pi.
+            if kind != Breakpoint::Software && !self.breakpoints.is_empty() {
+                return false;
+            }
+            self.breakpoints.push((kind, address, length));
+            true
+        }
+
+        fn remove(&mut self, kind: Breakpoint, address: u32, length: u32) -> bool {
+            let before = self.breakpoints.len();
+            self.breakpoints.retain(|&b| b != (kind, address, length));
+            self.breakpoints.len() < before
+        }
+    }
+
+    /// `data` framed as a packet.
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("${data}#{checksum:02x}")
+    }
+
+    /// Send `input` to `stub`, returning what it sent back and the last resume.
+    fn gdb(stub: &mut Stub, target: &mut Debuggee, input: &str) -> (String, Option<Resume>) {
+        let mut output = Vec::new();
+        let mut resume = None;
+        for byte in input.bytes() {
+            resume = stub.receive(byte, target).or(resume);
+            output.extend(stub.transmit().unwrap_or_default());
+        }
+        (String::from_utf8(output).unwrap(), resume)
+    }
+
+    #[test]
+    fn answers_gdb_register_packets() {
+        let mut stub = Stub::new(Stop::Trap);
+        let mut target = Debuggee::default();
+        target.registers[15] = 0x8000;
+        target.registers[16] = 0x6000_01d3;
+        let (output, _) = gdb(&mut stub, &mut target, &packet("?"));
+        assert_eq!(output, format!("+{}", packet("S05")));
+
+        let (output, _) = gdb(&mut stub, &mut target, &packet("g"));
+        let registers = format!(
+            "{}00800000{}d3010060",
+            "00000000".repeat(15),
+            "00".repeat(100)
+        );
+        assert_eq!(output, format!("+{}", packet(&registers)));
+
+        let (output, _) = gdb(&mut stub, &mut target, &packet("P1=78563412"));
+        assert_eq!(output, format!("+{}", packet("OK")));
+        assert_eq!(target.registers[1], 0x12345678);
+        let (output, _) = gdb(&mut stub, &mut target, &packet("p19"));
+        assert_eq!(output, format!("+{}", packet("d3010060")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("p10"));
+        assert_eq!(output, format!("+{}", packet(&"00".repeat(12))));
+
+        let registers = registers.replacen("00000000", "efbeadde", 1);
+        gdb(&mut stub, &mut target, &packet(&format!("G{registers}")));
+        assert_eq!(target.registers[0], 0xdeadbeef);
+        assert_eq!(target.registers[15], 0x8000);
+        let (output, _) = gdb(&mut stub, &mut target, &packet("G00"));
+        assert_eq!(output, format!("+{}", packet("E01")));
+    }
+
+    #[test]
+    fn answers_gdb_memory_packets() {
+        let mut stub = Stub::new(Stop::Trap);
+        let mut target = Debuggee {
+            memory: vec![0xaa; 16],
+            ..Default::default()
+        };
+        let (output, _) = gdb(&mut stub, &mut target, &packet("M8002,3:010203"));
+        assert_eq!(output, format!("+{}", packet("OK")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("m8000,6"));
+        assert_eq!(output, format!("+{}", packet("aaaa010203aa")));
+        // Past the end of memory.
+        let (output, _) = gdb(&mut stub, &mut target, &packet("m800e,4"));
+        assert_eq!(output, format!("+{}", packet("E01")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("M8000,2:01"));
+        assert_eq!(output, format!("+{}", packet("E01")));
+        assert_eq!(&target.memory[..2], &[0xaa; 2]);
+    }
+
+    #[test]
+    fn sets_gdb_breakpoints() {
+        let mut stub = Stub::new(Stop::Trap);
+        let mut target = Debuggee::default();
+        let (output, _) = gdb(&mut stub, &mut target, &packet("Z0,8010,4"));
+        assert_eq!(output, format!("+{}", packet("OK")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("Z2,9000,4"));
+        assert_eq!(output, format!("+{}", packet("E01")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("z0,8010,4"));
+        assert_eq!(output, format!("+{}", packet("OK")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("Z3,9000,4"));
+        assert_eq!(output, format!("+{}", packet("OK")));
+        assert_eq!(target.breakpoints, vec![(Breakpoint::Read, 0x9000, 4)]);
+        // Unknown kinds get an empty reply.
+        let (output, _) = gdb(&mut stub, &mut target, &packet("Z5,9000,4"));
+        assert_eq!(output, format!("+{}", packet("")));
+    }
+
+    #[test]
+    fn reports_gdb_stop_after_resuming() {
+        let mut stub = Stub::new(Stop::Trap);
+        let mut target = Debuggee::default();
+        // Nothing is owed before GDB resumes the program.
+        stub.stopped(Stop::Fault);
+        assert_eq!(stub.transmit(), None);
+
+        let (output, resume) = gdb(&mut stub, &mut target, &packet("s8004"));
+        assert_eq!((output.as_str(), resume), ("+", Some(Resume::Step)));
+        assert_eq!(target.registers[15], 0x8004);
+        stub.stopped(Stop::Trap);
+        assert_eq!(stub.transmit(), Some(packet("S05").as_bytes()));
+
+        let (_, resume) = gdb(&mut stub, &mut target, &packet("c"));
+        assert_eq!(resume, Some(Resume::Continue));
+        stub.stopped(Stop::Watch {
+            kind: Breakpoint::Access,
+            address: 0x9000,
+        });
+        assert_eq!(
+            stub.transmit(),
+            Some(packet("T05awatch:00009000;").as_bytes())
+        );
+        let (output, _) = gdb(&mut stub, &mut target, &packet("?"));
+        assert_eq!(output, format!("+{}", packet("T05awatch:00009000;")));
+
+        let (output, resume) = gdb(&mut stub, &mut target, &packet("D"));
+        assert_eq!(output, format!("+{}", packet("OK")));
+        assert_eq!(resume, Some(Resume::Detach));
+    }
+
+    #[test]
+    fn retransmits_gdb_packets() {
+        let mut stub = Stub::new(Stop::Trap);
+        let mut target = Debuggee::default();
+        let (output, _) = gdb(&mut stub, &mut target, "+$qSupported:swbreak+#00");
+        assert_eq!(output, "-");
+        let (output, _) = gdb(&mut stub, &mut target, &packet("qSupported:swbreak+"));
+        assert_eq!(output, format!("+{}", packet("PacketSize=00000200")));
+        let (output, _) = gdb(&mut stub, &mut target, "-");
+        assert_eq!(output, packet("PacketSize=00000200"));
+        // Noise and a restarted packet.
+        let (output, _) = gdb(
+            &mut stub,
+            &mut target,
+            &format!("\x03$qAtt{}", packet("qAttached")),
+        );
+        assert_eq!(output, format!("+{}", packet("1")));
+        let (output, _) = gdb(&mut stub, &mut target, &packet("vMustReplyEmpty"));
+        assert_eq!(output, format!("+{}", packet("")));
+    }
+}
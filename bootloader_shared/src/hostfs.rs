@@ -0,0 +1,292 @@
+//! Files on the host for running programs, like ARM semihosting.
+//!
+//! The program asks with a [Message::HostOpen], [Message::HostRead],
+//! [Message::HostWrite] or [Message::HostClose] and waits for the installer's
+//! [Reply]: `INSTALLER_HOST_REPLY`, a status, a value, the length of the data,
+//! the data and the checksum of everything after `INSTALLER_HOST_REPLY`. Use
+//! [Reply::encode] on the host, and feed the program's input to a
+//! [ReplyReader] on the Pi.
+//!
+//! [Message::HostOpen]: crate::message::Message::HostOpen
+//! [Message::HostRead]: crate::message::Message::HostRead
+//! [Message::HostWrite]: crate::message::Message::HostWrite
+//! [Message::HostClose]: crate::message::Message::HostClose
+use crate::{
+    message::{checksum, word, MAX_PAYLOAD},
+    INSTALLER_HOST_REPLY,
+};
+
+/// Most bytes of data in a [Reply].
+pub const MAX_DATA: usize = MAX_PAYLOAD;
+/// Longest path to open, after the mode in the payload.
+pub const MAX_PATH: usize = MAX_PAYLOAD - 4;
+/// Most bytes written per request, after the handle in the payload.
+pub const MAX_WRITE: usize = MAX_PAYLOAD - 4;
+/// Bytes in the largest reply.
+pub const MAX_REPLY_LENGTH: usize = HEADER_LENGTH + MAX_DATA + 4;
+
+/// `INSTALLER_HOST_REPLY`, the status, the value and the data length.
+const HEADER_LENGTH: usize = 16;
+
+/// How to open a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Read,
+    /// Created if missing and truncated otherwise.
+    Write,
+    /// Created if missing, writing at the end.
+    Append,
+    /// Reading and writing an existing file.
+    ReadWrite,
+}
+
+impl Mode {
+    pub fn word(self) -> u32 {
+        match self {
+            Mode::Read => 0,
+            Mode::Write => 1,
+            Mode::Append => 2,
+            Mode::ReadWrite => 3,
+        }
+    }
+
+    pub fn from_word(word: u32) -> Option<Self> {
+        Some(match word {
+            0 => Mode::Read,
+            1 => Mode::Write,
+            2 => Mode::Append,
+            3 => Mode::ReadWrite,
+            _ => return None,
+        })
+    }
+}
+
+/// Why the host couldn't do what the program asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostError {
+    /// The installer runs without `--hostfs`.
+    Disabled,
+    NotFound,
+    PermissionDenied,
+    /// The path is absolute or leads out of the served directory.
+    OutsideRoot,
+    /// No file is open with the handle.
+    BadHandle,
+    /// The installer has as many files open as it allows.
+    TooManyFiles,
+    /// Any other failure on the host.
+    Io,
+}
+
+impl HostError {
+    fn code(self) -> u32 {
+        match self {
+            HostError::Disabled => 1,
+            HostError::NotFound => 2,
+            HostError::PermissionDenied => 3,
+            HostError::OutsideRoot => 4,
+            HostError::BadHandle => 5,
+            HostError::TooManyFiles => 6,
+            HostError::Io => 7,
+        }
+    }
+
+    /// Unknown codes, as from another version of the installer, are
+    /// [HostError::Io].
+    fn from_code(code: u32) -> Self {
+        match code {
+            1 => HostError::Disabled,
+            2 => HostError::NotFound,
+            3 => HostError::PermissionDenied,
+            4 => HostError::OutsideRoot,
+            5 => HostError::BadHandle,
+            6 => HostError::TooManyFiles,
+            _ => HostError::Io,
+        }
+    }
+}
+
+impl core::fmt::Display for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HostError::Disabled => write!(f, "host files are disabled, run with --hostfs"),
+            HostError::NotFound => write!(f, "no such file on the host"),
+            HostError::PermissionDenied => write!(f, "permission denied on the host"),
+            HostError::OutsideRoot => write!(f, "path is outside the served directory"),
+            HostError::BadHandle => write!(f, "no file open with this handle"),
+            HostError::TooManyFiles => write!(f, "too many files open on the host"),
+            HostError::Io => write!(f, "host file access failed"),
+        }
+    }
+}
+
+impl core::error::Error for HostError {}
+
+/// The installer's answer to a request. The value is the handle of an opened
+/// file or the number of bytes written, and the data the bytes read, empty at
+/// the end of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply<'a> {
+    pub result: Result<u32, HostError>,
+    pub data: &'a [u8],
+}
+
+impl<'a> Reply<'a> {
+    /// Encode the reply into `buf`, returning the part used. Data past
+    /// [MAX_DATA] is left out.
+    pub fn encode<'b>(&self, buf: &'b mut [u8; MAX_REPLY_LENGTH]) -> &'b [u8] {
+        let (status, value) = match self.result {
+            Ok(value) => (0, value),
+            Err(error) => (error.code(), 0),
+        };
+        let length = self.data.len().min(MAX_DATA);
+        let end = HEADER_LENGTH + length;
+        buf[0..4].copy_from_slice(&INSTALLER_HOST_REPLY.to_le_bytes());
+        buf[4..8].copy_from_slice(&status.to_le_bytes());
+        buf[8..12].copy_from_slice(&value.to_le_bytes());
+        buf[12..16].copy_from_slice(&(length as u32).to_le_bytes());
+        buf[HEADER_LENGTH..end].copy_from_slice(&self.data[..length]);
+        let checksum = checksum(&buf[4..end]);
+        buf[end..end + 4].copy_from_slice(&checksum.to_le_bytes());
+        &buf[..end + 4]
+    }
+
+    /// Decode a whole reply, if it is a valid one.
+    fn decode(frame: &'a [u8]) -> Option<Self> {
+        let end = frame.len().checked_sub(4)?;
+        if frame.len() < HEADER_LENGTH + 4
+            || word(frame, 0) != INSTALLER_HOST_REPLY
+            || word(frame, 12) as usize != end - HEADER_LENGTH
+            || word(frame, end) != checksum(&frame[4..end])
+        {
+            return None;
+        }
+        let result = match word(frame, 4) {
+            0 => Ok(word(frame, 8)),
+            code => Err(HostError::from_code(code)),
+        };
+        Some(Self {
+            result,
+            data: &frame[HEADER_LENGTH..end],
+        })
+    }
+}
+
+/// Picks a [Reply] out of the program's input, skipping anything else, such
+/// as keys typed into the console.
+#[derive(Debug, Clone)]
+pub struct ReplyReader {
+    buf: [u8; MAX_REPLY_LENGTH],
+    length: usize,
+}
+
+impl Default for ReplyReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplyReader {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_REPLY_LENGTH],
+            length: 0,
+        }
+    }
+
+    /// Add a byte of input, returning the reply once one arrived intact.
+    pub fn push(&mut self, byte: u8) -> Option<Reply<'_>> {
+        self.buf[self.length] = byte;
+        self.length += 1;
+        // The empty suffix could always start a reply, so this stops.
+        let mut start = 0;
+        while !Self::could_be_reply(&self.buf[start..self.length]) {
+            start += 1;
+        }
+        self.buf.copy_within(start..self.length, 0);
+        self.length -= start;
+
+        if self.length < HEADER_LENGTH || self.length < reply_length(&self.buf) {
+            return None;
+        }
+        let length = core::mem::take(&mut self.length);
+        Reply::decode(&self.buf[..length])
+    }
+
+    fn could_be_reply(bytes: &[u8]) -> bool {
+        let magic = INSTALLER_HOST_REPLY.to_le_bytes();
+        let n = bytes.len().min(magic.len());
+        if bytes[..n] != magic[..n] {
+            return false;
+        }
+        if bytes.len() < HEADER_LENGTH {
+            return true;
+        }
+        if word(bytes, 12) as usize > MAX_DATA {
+            return false;
+        }
+        bytes.len() < reply_length(bytes) || Reply::decode(bytes).is_some()
+    }
+}
+
+/// Length of the reply starting with the header in `bytes`.
+fn reply_length(bytes: &[u8]) -> usize {
+    HEADER_LENGTH + word(bytes, 12) as usize + 4
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::tests::program;
+
+    /// Read `input` with a [ReplyReader], returning the value or error and data
+    /// of each reply.
+    fn read_replies(input: &[u8]) -> Vec<(Result<u32, HostError>, Vec<u8>)> {
+        let mut reader = ReplyReader::new();
+        input
+            .iter()
+            .filter_map(|&byte| {
+                reader
+                    .push(byte)
+                    .map(|reply| (reply.result, reply.data.to_vec()))
+            })
+            .collect()
+    }
+
+    fn reply(result: Result<u32, HostError>, data: &[u8]) -> Vec<u8> {
+        Reply { result, data }
+            .encode(&mut [0; MAX_REPLY_LENGTH])
+            .to_vec()
+    }
+
+    #[test]
+    fn reads_host_replies_among_input() {
+        let data = program(MAX_DATA);
+        let read = reply(Ok(0), &data);
+        let error = reply(Err(HostError::OutsideRoot), &[]);
+        let mut corrupted = reply(Ok(5), b"hello");
+        corrupted[18] ^= 1;
+        let input = [
+            b"typed",
+            &read[..3],
+            &corrupted[..],
+            &read[..],
+            b"x",
+            &error[..],
+        ]
+        .concat();
+        assert_eq!(
+            read_replies(&input),
+            vec![(Ok(0), data), (Err(HostError::OutsideRoot), vec![])]
+        );
+    }
+
+    #[test]
+    fn cuts_long_host_replies() {
+        let data = program(MAX_DATA + 10);
+        let replies = read_replies(&reply(Ok(1), &data));
+        assert_eq!(replies, vec![(Ok(1), data[..MAX_DATA].to_vec())]);
+    }
+}
@@ -0,0 +1,711 @@
+//! Installer side of the load protocol.
+//!
+//! Feed received bytes to [Installer::receive], call [Installer::poll]
+//! regularly, and send whatever [Installer::transmit] returns. Once done, the
+//! remaining bytes are output from the loaded program.
+//!
+//! To load at another baud rate than [crate::DEFAULT_BAUD], see
+//! [Installer::with_baud]. Whenever [Installer::baud] changes after sending
+//! what [Installer::transmit] returns, switch the UART to it once everything
+//! sent so far has gone out.
+use crate::{
+    block_checksum, block_count, block_length,
+    boot_info::{Blob, MAX_BLOBS},
+    is_pi_get_prog_info_byte,
+    sign::{Key, Signature, Signer, SIGNATURE_LENGTH},
+    Error, Micros, Outbox, Report, Segment, Word, BASE, BAUD_PATTERN, BLOCK_SIZE, CRC_ALGORITHM,
+    DEFAULT_BAUD, INSTALLER_BAUD, INSTALLER_BAUD_CHECK, INSTALLER_BLOBS, INSTALLER_CODE,
+    INSTALLER_PROG_INFO, INSTALLER_PROG_INFO_LZ4, INSTALLER_SIGNATURE, INSTALLER_SUCCESS,
+    INSTALLER_UPDATE_INFO, MAX_RETRIES, MAX_SEGMENTS, PI_BAUD, PI_BAUD_OK, PI_BAUD_UNSUPPORTED,
+    PI_BLOCK_OK, PI_BLOCK_RESEND, PI_ERROR, PI_GET_CODE, PI_GET_PROG_INFO, PI_SUCCESS,
+};
+
+/// Longest time allowed between protocol steps.
+pub const STEP_TIMEOUT: Micros = 10_000_000;
+/// How long to wait for a block to be acknowledged before sending it again.
+pub const ACK_TIMEOUT: Micros = 500_000;
+/// How long to wait for the Pi to switch to a proposed baud rate and confirm
+/// it before falling back to [DEFAULT_BAUD]. Longer than the Pi waits, so it
+/// has fallen back already.
+pub const BAUD_TIMEOUT: Micros = 2 * crate::pi::BAUD_TIMEOUT;
+
+/// Room for the largest program info, its signature and blobs.
+const OUTBOX_SIZE: usize =
+    4 * (5 + 3 * MAX_SEGMENTS) + 4 + SIGNATURE_LENGTH + 4 * (2 + 6 * MAX_BLOBS);
+
+/// Why an [Image] can't be put together as asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// The program has more than [MAX_SEGMENTS] segments.
+    TooManySegments { count: u32 },
+    /// More than [MAX_BLOBS] blobs come along with the program.
+    TooManyBlobs { count: u32 },
+    /// The code is `got` bytes, but the segments and blobs add up to
+    /// `expected`.
+    WrongLength { expected: u64, got: u64 },
+    /// Blobs were added after signing or compressing, or to a bootloader
+    /// update.
+    BlobsTooLate,
+    /// A bootloader update was to be compressed, which the Pi doesn't take.
+    CompressedUpdate,
+    /// The image was signed after compressing, but the signature covers the
+    /// uncompressed code.
+    SignedCompressed,
+}
+
+impl core::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ImageError::TooManySegments { count } => write!(
+                f,
+                "program has {count} segments but at most {MAX_SEGMENTS} are supported"
+            ),
+            ImageError::TooManyBlobs { count } => {
+                write!(f, "{count} blobs but at most {MAX_BLOBS} are supported")
+            }
+            ImageError::WrongLength { expected, got } => write!(
+                f,
+                "code is {got} bytes but the segments and blobs add up to {expected}"
+            ),
+            ImageError::BlobsTooLate => write!(
+                f,
+                "add blobs before signing or compressing, and not to bootloader updates"
+            ),
+            ImageError::CompressedUpdate => write!(f, "bootloader updates can't be compressed"),
+            ImageError::SignedCompressed => write!(f, "sign images before compressing them"),
+        }
+    }
+}
+
+impl core::error::Error for ImageError {}
+
+/// A program as sent over the wire.
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    /// The bytes sent as code.
+    data: &'a [u8],
+    entry: u32,
+    segments: [Segment; MAX_SEGMENTS],
+    segment_count: usize,
+    blobs: [Blob; MAX_BLOBS],
+    blob_count: usize,
+    /// Length of the code, uncompressed.
+    length: u32,
+    /// Checksum of the code, uncompressed.
+    checksum: u32,
+    compressed: bool,
+    /// A new bootloader rather than a program.
+    update: bool,
+    signature: Option<Signature>,
+}
+
+impl<'a> Image<'a> {
+    /// A flat binary, loaded and started at [BASE].
+    pub fn raw(program: &'a [u8]) -> Self {
+        let length = program.len() as u32;
+        let segment = Segment {
+            address: BASE,
+            length,
+            memory_length: length,
+        };
+        let mut segments = [Segment::default(); MAX_SEGMENTS];
+        segments[0] = segment;
+        Self {
+            data: program,
+            entry: BASE,
+            segments,
+            segment_count: 1,
+            blobs: [Blob::default(); MAX_BLOBS],
+            blob_count: 0,
+            length,
+            checksum: checksum(program),
+            compressed: false,
+            update: false,
+            signature: None,
+        }
+    }
+
+    /// A program started at `entry`. `code` must be the contents of
+    /// `segments` back to back.
+    pub fn new(entry: u32, segments: &[Segment], code: &'a [u8]) -> Result<Self, ImageError> {
+        if segments.len() > MAX_SEGMENTS {
+            return Err(ImageError::TooManySegments {
+                count: segments.len() as u32,
+            });
+        }
+        let length = segments.iter().map(|segment| segment.length as u64).sum();
+        check_length(length, code)?;
+        let mut image = Self::raw(code);
+        image.entry = entry;
+        image.segments[..segments.len()].copy_from_slice(segments);
+        image.segment_count = segments.len();
+        Ok(image)
+    }
+
+    /// Send `blobs` along with the program, see [crate::boot_info]. `code` must
+    /// be the code of the segments followed by the contents of `blobs` back to
+    /// back. Add blobs before signing or compressing.
+    pub fn with_blobs(self, blobs: &[Blob], code: &'a [u8]) -> Result<Self, ImageError> {
+        if self.compressed || self.update || self.signature.is_some() {
+            return Err(ImageError::BlobsTooLate);
+        }
+        if blobs.len() > MAX_BLOBS {
+            return Err(ImageError::TooManyBlobs {
+                count: blobs.len() as u32,
+            });
+        }
+        let length = self.length as u64 + blobs.iter().map(|blob| blob.length as u64).sum::<u64>();
+        check_length(length, code)?;
+        let mut image = Self {
+            data: code,
+            length: length as u32,
+            checksum: checksum(code),
+            blob_count: blobs.len(),
+            ..self
+        };
+        image.blobs[..blobs.len()].copy_from_slice(blobs);
+        Ok(image)
+    }
+
+    /// Send the code compressed. `compressed` must be the LZ4 block (without a
+    /// size prefix) of the code.
+    pub fn lz4(self, compressed: &'a [u8]) -> Result<Self, ImageError> {
+        if self.update {
+            return Err(ImageError::CompressedUpdate);
+        }
+        Ok(Self {
+            data: compressed,
+            compressed: true,
+            ..self
+        })
+    }
+
+    /// Send a flat binary as a new bootloader for the Pi to write to its SD
+    /// card, see [crate::INSTALLER_UPDATE_INFO]. Updates are never compressed.
+    pub fn update(self) -> Result<Self, ImageError> {
+        if self.compressed {
+            return Err(ImageError::CompressedUpdate);
+        }
+        Ok(Self {
+            update: true,
+            ..self
+        })
+    }
+
+    /// Sign the image for a bootloader built with `key`, see [crate::sign].
+    /// Sign before compressing, the signature covers the uncompressed code.
+    pub fn sign(self, key: &Key) -> Result<Self, ImageError> {
+        if self.compressed {
+            return Err(ImageError::SignedCompressed);
+        }
+        let mut signer = Signer::new(key, self.update, self.entry, self.segments(), self.blobs());
+        signer.update(self.data);
+        Ok(Self {
+            signature: Some(signer.finalize()),
+            ..self
+        })
+    }
+
+    pub fn is_update(&self) -> bool {
+        self.update
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments[..self.segment_count]
+    }
+
+    pub fn blobs(&self) -> &[Blob] {
+        &self.blobs[..self.blob_count]
+    }
+
+    /// Length of the code, uncompressed.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    /// Bytes that go over the wire.
+    pub fn wire_length(&self) -> usize {
+        self.data.len()
+    }
+
+    fn block(&self, index: u32) -> &'a [u8] {
+        let start = (index * BLOCK_SIZE) as usize;
+        let length = block_length(self.data.len() as u32, index) as usize;
+        &self.data[start..start + length]
+    }
+
+    fn block_count(&self) -> u32 {
+        block_count(self.data.len() as u32)
+    }
+}
+
+/// Check that `code` is the `expected` number of bytes.
+fn check_length(expected: u64, code: &[u8]) -> Result<(), ImageError> {
+    let got = code.len() as u64;
+    if got != expected {
+        return Err(ImageError::WrongLength { expected, got });
+    }
+    Ok(())
+}
+
+fn checksum(program: &[u8]) -> u32 {
+    let mut checksum = CRC_ALGORITHM.digest_with_initial(0);
+    checksum.update(program);
+    checksum.finalize()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for `PI_GET_PROG_INFO` anywhere in the stream.
+    ProgInfo {
+        word: Word,
+    },
+    /// Waiting for `PI_BAUD` anywhere in the stream after proposing `baud`.
+    BaudReply {
+        word: Word,
+        baud: u32,
+    },
+    /// Waiting for the rate the Pi switched to.
+    BaudRate {
+        baud: u32,
+    },
+    /// Waiting at the new rate for `PI_BAUD_OK` anywhere in the stream, once
+    /// `INSTALLER_BAUD_CHECK` is `sent`.
+    BaudCheck {
+        word: Word,
+        sent: bool,
+    },
+    /// Waiting for `PI_GET_CODE`, ignoring trailing `PI_GET_PROG_INFO` bytes.
+    GetCode,
+    /// Waiting for the Pi to echo the checksum.
+    Checksum,
+    /// Waiting for replies to the current block, or for `PI_SUCCESS` once all
+    /// have been acknowledged.
+    Blocks {
+        word: Word,
+    },
+    /// Waiting for the index of an acknowledged or requested block.
+    BlockReply {
+        ok: bool,
+    },
+    /// Receiving the [Report] after `PI_ERROR`.
+    Report {
+        words: [u32; Report::WORDS],
+        received: usize,
+    },
+    Done,
+}
+
+/// Progress through sending the current block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sending {
+    Idle,
+    Header(u32),
+    Code(u32),
+    Checksum(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    None,
+    /// The Pi asked for the program.
+    ProgInfoRequested,
+    /// The Pi echoed the right checksum, the program is being sent.
+    SendingCode,
+    /// The Pi asked for a block again.
+    Resending {
+        block: u32,
+    },
+    /// The Pi acknowledged the load and is running the program, or writing
+    /// the new bootloader.
+    Done,
+    /// Both sides switched to `baud` for the load.
+    Baud {
+        baud: u32,
+    },
+    /// The Pi can't run at the proposed `baud`, so both stay at
+    /// [DEFAULT_BAUD].
+    BaudUnsupported {
+        baud: u32,
+    },
+}
+
+pub struct Installer<'a> {
+    image: Image<'a>,
+    state: State,
+    word: Word,
+    out: Outbox<OUTBOX_SIZE>,
+    /// The block being sent or waiting to be acknowledged.
+    block: u32,
+    /// Times the current block has been sent again.
+    retries: u32,
+    sending: Sending,
+    /// When the current block was last sent.
+    sent: Micros,
+    trailer: [u8; 4],
+    /// Time of the last protocol step.
+    last: Micros,
+    baud: u32,
+    /// Rate to propose, until proposed.
+    proposal: Option<u32>,
+}
+
+impl<'a> Installer<'a> {
+    pub fn new(image: Image<'a>, now: Micros) -> Self {
+        Self {
+            image,
+            state: State::ProgInfo {
+                word: Word::default(),
+            },
+            word: Word::default(),
+            out: Outbox::new(),
+            block: 0,
+            retries: 0,
+            sending: Sending::Idle,
+            sent: now,
+            trailer: [0; 4],
+            last: now,
+            baud: DEFAULT_BAUD,
+            proposal: None,
+        }
+    }
+
+    /// Propose loading at `baud`, falling back to [DEFAULT_BAUD] if it doesn't
+    /// work.
+    pub fn with_baud(self, baud: u32) -> Self {
+        Self {
+            proposal: (baud != DEFAULT_BAUD).then_some(baud),
+            ..self
+        }
+    }
+
+    /// The rate the UART should run at. Back to [DEFAULT_BAUD] once done, as
+    /// programs run at that.
+    pub fn baud(&self) -> u32 {
+        if self.state == State::Done {
+            DEFAULT_BAUD
+        } else {
+            self.baud
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Bytes to send to the Pi.
+    pub fn transmit(&mut self) -> Option<&[u8]> {
+        if let Sending::Header(block) = self.sending {
+            self.out.push(INSTALLER_CODE);
+            self.out.push(block);
+            self.sending = Sending::Code(block);
+        }
+        if let Some(bytes) = self.out.take() {
+            return Some(bytes);
+        }
+        match self.sending {
+            Sending::Code(block) => {
+                self.sending = Sending::Checksum(block);
+                Some(self.image.block(block))
+            }
+            Sending::Checksum(block) => {
+                self.sending = Sending::Idle;
+                self.trailer = block_checksum(block, self.image.block(block)).to_le_bytes();
+                Some(&self.trailer)
+            }
+            Sending::Idle | Sending::Header(_) => None,
+        }
+    }
+
+    /// Resend unacknowledged blocks and check for timeouts.
+    pub fn poll(&mut self, now: Micros) -> Result<(), Error> {
+        if self.state == State::Done {
+            return Ok(());
+        }
+        if now.wrapping_sub(self.last) > STEP_TIMEOUT {
+            return Err(Error::Timeout);
+        }
+        match &mut self.state {
+            State::BaudReply { .. } | State::BaudRate { .. } | State::BaudCheck { .. }
+                if now.wrapping_sub(self.last) > BAUD_TIMEOUT =>
+            {
+                self.fall_back(now);
+            }
+            // Only now that the caller switched to the new rate.
+            State::BaudCheck { sent, .. } if !*sent => {
+                *sent = true;
+                self.out.push(INSTALLER_BAUD_CHECK);
+                for word in BAUD_PATTERN.chunks_exact(4) {
+                    self.out.push(u32::from_le_bytes(word.try_into().unwrap()));
+                }
+            }
+            _ => {}
+        }
+        if matches!(self.state, State::Blocks { .. } | State::BlockReply { .. })
+            && self.block < self.image.block_count()
+            && now.wrapping_sub(self.sent) > ACK_TIMEOUT
+        {
+            self.resend(self.block, now)?;
+        }
+        Ok(())
+    }
+
+    /// Consume a prefix of `input`, returning how many bytes were used.
+    ///
+    /// Stops consuming once done.
+    pub fn receive(&mut self, input: &[u8], now: Micros) -> Result<(usize, Event), Error> {
+        for (index, &byte) in input.iter().enumerate() {
+            let used = index + 1;
+            match &mut self.state {
+                State::ProgInfo { word } => {
+                    if word.shift(byte) == PI_GET_PROG_INFO {
+                        if let Some(baud) = self.proposal.take() {
+                            self.out.push(INSTALLER_BAUD);
+                            self.out.push(baud);
+                            self.step(
+                                State::BaudReply {
+                                    word: Word::default(),
+                                    baud,
+                                },
+                                now,
+                            );
+                            return Ok((used, Event::None));
+                        }
+                        let image = self.image;
+                        if let Some(signature) = image.signature {
+                            self.out.push(INSTALLER_SIGNATURE);
+                            for word in signature.chunks_exact(4) {
+                                self.out.push(u32::from_le_bytes(word.try_into().unwrap()));
+                            }
+                        }
+                        if !image.blobs().is_empty() {
+                            self.out.push(INSTALLER_BLOBS);
+                            self.out.push(image.blob_count as u32);
+                            for blob in image.blobs() {
+                                self.out.push(blob.address);
+                                self.out.push(blob.length);
+                                for word in blob.name_bytes().chunks_exact(4) {
+                                    self.out.push(u32::from_le_bytes(word.try_into().unwrap()));
+                                }
+                            }
+                        }
+                        self.out.push(if image.update {
+                            INSTALLER_UPDATE_INFO
+                        } else if image.compressed {
+                            INSTALLER_PROG_INFO_LZ4
+                        } else {
+                            INSTALLER_PROG_INFO
+                        });
+                        self.out.push(image.entry);
+                        self.out.push(image.segment_count as u32);
+                        for segment in image.segments() {
+                            self.out.push(segment.address);
+                            self.out.push(segment.length);
+                            self.out.push(segment.memory_length);
+                        }
+                        self.out.push(image.checksum);
+                        if image.compressed {
+                            self.out.push(image.data.len() as u32);
+                        }
+                        self.step(State::GetCode, now);
+                        return Ok((used, Event::ProgInfoRequested));
+                    }
+                }
+                State::BaudReply { word, baud } => {
+                    let baud = *baud;
+                    match word.shift(byte) {
+                        PI_BAUD => {
+                            self.word = Word::default();
+                            self.state = State::BaudRate { baud };
+                            return Ok((used, Event::None));
+                        }
+                        PI_BAUD_UNSUPPORTED => {
+                            self.fall_back(now);
+                            return Ok((used, Event::BaudUnsupported { baud }));
+                        }
+                        _ => {}
+                    }
+                }
+                State::BaudRate { baud } => {
+                    let baud = *baud;
+                    let Some(got) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if got != baud {
+                        // The Pi will time out at whatever it switched to.
+                        self.fall_back(now);
+                        return Ok((used, Event::None));
+                    }
+                    self.baud = baud;
+                    self.step(
+                        State::BaudCheck {
+                            word: Word::default(),
+                            sent: false,
+                        },
+                        now,
+                    );
+                    return Ok((used, Event::None));
+                }
+                State::BaudCheck { word, sent } => {
+                    if *sent && word.shift(byte) == PI_BAUD_OK {
+                        self.step(
+                            State::ProgInfo {
+                                word: Word::default(),
+                            },
+                            now,
+                        );
+                        let baud = self.baud;
+                        return Ok((used, Event::Baud { baud }));
+                    }
+                }
+                State::GetCode => {
+                    // Ignore trailing GET_PROG_INFO bytes.
+                    if self.word == Word::default() && is_pi_get_prog_info_byte(byte) {
+                        continue;
+                    }
+                    let Some(got) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if got == PI_ERROR {
+                        self.fail(now);
+                        return Ok((used, Event::None));
+                    }
+                    if got != PI_GET_CODE {
+                        return Err(Error::Unexpected {
+                            expected: PI_GET_CODE,
+                            got,
+                        });
+                    }
+                    self.step(State::Checksum, now);
+                    return Ok((used, Event::None));
+                }
+                State::Checksum => {
+                    let Some(got) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if got != self.image.checksum {
+                        return Err(Error::ChecksumMismatch {
+                            expected: self.image.checksum,
+                            got,
+                        });
+                    }
+                    if self.image.block_count() > 0 {
+                        self.send(0, now);
+                    }
+                    self.step(
+                        State::Blocks {
+                            word: Word::default(),
+                        },
+                        now,
+                    );
+                    return Ok((used, Event::SendingCode));
+                }
+                State::Blocks { word } => match word.shift(byte) {
+                    reply @ (PI_BLOCK_OK | PI_BLOCK_RESEND) => {
+                        self.state = State::BlockReply {
+                            ok: reply == PI_BLOCK_OK,
+                        };
+                        return Ok((used, Event::None));
+                    }
+                    // Also acknowledges the last block, in case its
+                    // `PI_BLOCK_OK` got lost.
+                    PI_SUCCESS if self.block + 1 >= self.image.block_count() => {
+                        self.out.push(INSTALLER_SUCCESS);
+                        self.step(State::Done, now);
+                        return Ok((used, Event::Done));
+                    }
+                    PI_ERROR => {
+                        self.fail(now);
+                        return Ok((used, Event::None));
+                    }
+                    _ => {}
+                },
+                State::BlockReply { ok } => {
+                    let ok = *ok;
+                    let Some(block) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.state = State::Blocks {
+                        word: Word::default(),
+                    };
+                    if ok && block == self.block {
+                        self.block += 1;
+                        self.retries = 0;
+                        self.last = now;
+                        if self.block < self.image.block_count() {
+                            self.send(self.block, now);
+                        }
+                    } else if !ok && block < self.image.block_count() {
+                        self.resend(block, now)?;
+                        return Ok((used, Event::Resending { block }));
+                    }
+                    return Ok((used, Event::None));
+                }
+                State::Report { words, received } => {
+                    let Some(word) = self.word.push(byte) else {
+                        continue;
+                    };
+                    words[*received] = word;
+                    *received += 1;
+                    if *received == Report::WORDS {
+                        return Err(Error::Pi(Report::from_words(*words)));
+                    }
+                }
+                State::Done => return Ok((index, Event::None)),
+            }
+        }
+        Ok((input.len(), Event::None))
+    }
+
+    fn send(&mut self, block: u32, now: Micros) {
+        self.block = block;
+        self.sending = Sending::Header(block);
+        self.sent = now;
+    }
+
+    fn resend(&mut self, block: u32, now: Micros) -> Result<(), Error> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return Err(Error::TooManyRetries { block });
+        }
+        self.send(block, now);
+        Ok(())
+    }
+
+    /// Go back to [DEFAULT_BAUD] and load the program there.
+    fn fall_back(&mut self, now: Micros) {
+        self.baud = DEFAULT_BAUD;
+        self.step(
+            State::ProgInfo {
+                word: Word::default(),
+            },
+            now,
+        );
+    }
+
+    /// Receive the report of why the Pi failed.
+    fn fail(&mut self, now: Micros) {
+        self.word = Word::default();
+        self.sending = Sending::Idle;
+        self.step(
+            State::Report {
+                words: [0; Report::WORDS],
+                received: 0,
+            },
+            now,
+        );
+    }
+
+    fn step(&mut self, state: State, now: Micros) {
+        self.state = state;
+        self.last = now;
+    }
+}
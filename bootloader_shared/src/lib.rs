@@ -1,5 +1,25 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
+pub mod boot_info;
+pub mod channel;
+pub mod core_dump;
+pub mod crash;
+pub mod fat;
+pub mod gdb;
+pub mod hostfs;
+pub mod installer;
+pub mod message;
+pub mod pi;
+pub mod rpc;
+pub mod sign;
+#[cfg(test)]
+mod tests;
+
+/// Followed by a [Report] of why the bootloader failed, after which it waits
+/// for the installer to try again.
 pub const PI_ERROR: u32 = 0x00001111;
 pub const PI_GET_PROG_INFO: u32 = 0xEEEEFFFF;
 pub const fn is_pi_get_prog_info_byte(b: u8) -> bool {
@@ -7,11 +27,412 @@ pub const fn is_pi_get_prog_info_byte(b: u8) -> bool {
 }
 pub const PI_GET_CODE: u32 = 0x11112222;
 pub const PI_SUCCESS: u32 = 0x22223333;
+/// Followed by the index of a block that arrived intact.
+pub const PI_BLOCK_OK: u32 = 0x55556666;
+/// Followed by the index of the block the Pi wants (again).
+pub const PI_BLOCK_RESEND: u32 = 0x66667777;
+/// Starts a message from the running program, see [message]. None of its bytes
+/// are printable, so it can't be confused with output.
+pub const PI_MESSAGE: u32 = 0x88889999;
+/// Answers `INSTALLER_BAUD`, followed by the proposed rate. The Pi switches to
+/// it right after sending this.
+pub const PI_BAUD: u32 = 0x77778888;
+/// The [BAUD_PATTERN] arrived intact, so the new rate is kept for the load.
+pub const PI_BAUD_OK: u32 = 0xCCCCDDDD;
+/// Answers `INSTALLER_BAUD` instead of `PI_BAUD` when the UART can't run at
+/// the proposed rate, followed by the rate. Both sides stay at
+/// [DEFAULT_BAUD].
+pub const PI_BAUD_UNSUPPORTED: u32 = 0x7777AAAA;
 
+/// Followed by the entry point, the number of segments, the address, length
+/// and memory length of each segment, and the checksum of the code. The code
+/// is the contents of all segments back to back.
 pub const INSTALLER_PROG_INFO: u32 = 0xBEEFDEAD;
+/// Like `INSTALLER_PROG_INFO`, but the code is LZ4 block compressed and its
+/// length follows the checksum.
+pub const INSTALLER_PROG_INFO_LZ4: u32 = 0xBEEFC0DE;
+/// Like `INSTALLER_PROG_INFO` for a flat binary, but the code is a new
+/// bootloader for the Pi to write to its SD card instead of running.
+pub const INSTALLER_UPDATE_INFO: u32 = 0xBEEFB007;
+/// Answers `PI_GET_PROG_INFO` instead of the program info, followed by a baud
+/// rate to load the program at. See [DEFAULT_BAUD].
+pub const INSTALLER_BAUD: u32 = 0xBEEFBA0D;
+/// Sent at the new rate once the Pi switched, followed by [BAUD_PATTERN].
+pub const INSTALLER_BAUD_CHECK: u32 = 0xBEEFC4EC;
+/// Followed by the [sign::Signature] of the program info that comes next.
+/// Bootloaders without a key skip it.
+pub const INSTALLER_SIGNATURE: u32 = 0xBEEF5167;
+/// Sent before the program info when blobs come along with the program,
+/// followed by the number of blobs, and the address, length and name of each,
+/// see [boot_info]. Their contents follow the code of the segments.
+pub const INSTALLER_BLOBS: u32 = 0xBEEFB10B;
+/// Starts a block: the block index, up to `BLOCK_SIZE` bytes of code, and the
+/// checksum of the index and code.
 pub const INSTALLER_CODE: u32 = 0x33334444;
 pub const INSTALLER_SUCCESS: u32 = 0x44445555;
+/// Sent from the console to ask the running program to return to the
+/// bootloader. None of its bytes are printable, so it can't be typed by
+/// accident.
+pub const INSTALLER_REBOOT: u32 = 0x99998888;
+/// Answers [message::Message::Tests], followed by the index of the first test
+/// to run.
+pub const INSTALLER_RUN_TESTS: u32 = 0xAAAABBBB;
+/// Starts the installer's answer to a request for a file on the host, see
+/// [hostfs].
+pub const INSTALLER_HOST_REPLY: u32 = 0xCCCCEEEE;
+
+/// Both sides start at this rate and programs run at it. The installer may
+/// propose another rate with `INSTALLER_BAUD`, which both switch to once the
+/// Pi got [BAUD_PATTERN] intact at it, and leave again after the load. If the
+/// check fails or times out, both fall back to this rate.
+pub const DEFAULT_BAUD: u32 = 115_200 * 8;
+/// Core clock the mini UART's rate derives from, if the firmware doesn't say.
+pub const DEFAULT_CORE_CLOCK: u32 = 250_000_000;
+/// Bytes with many different bit patterns, which arrive mangled if the rates
+/// of the two sides are too far apart.
+pub const BAUD_PATTERN: [u8; 16] = [
+    0x55, 0xAA, 0x00, 0xFF, 0x0F, 0xF0, 0x33, 0xCC, 0x01, 0x80, 0x7E, 0x81, 0x5A, 0xA5, 0x3C, 0xC3,
+];
 
 pub const BASE: u32 = 0x8000;
+/// Where the bootloader stays resident while programs run, between the
+/// highest loadable address and the stack.
+pub const BOOTLOADER_LOCATION: u32 = 0x7E00000;
+/// Stored in the word after the first instruction of a resident bootloader,
+/// and followed by the address a finished program can jump to so the
+/// bootloader waits for the next program without a reboot.
+pub const BOOTLOADER_RESIDENT: u32 = 0xB007_10AD;
+/// Longest bootloader, which must fit between [BOOTLOADER_LOCATION] and its
+/// stack.
+pub const MAX_BOOTLOADER_LENGTH: u32 = 0x100000;
+
+/// Whether `image` starts like a bootloader built from this repository, so a
+/// program can't be written over `kernel.img` by mistake.
+pub fn is_bootloader(image: &[u8]) -> bool {
+    image.len() <= MAX_BOOTLOADER_LENGTH as usize
+        && image.get(4..8) == Some(&BOOTLOADER_RESIDENT.to_le_bytes()[..])
+}
+
+/// The mini UART's baud rate register for `baud` at `core_clock`, or `None`
+/// if the register can't hold it or it would be 0.
+pub fn baud_divisor(core_clock: u32, baud: u32) -> Option<u16> {
+    let divisor = (core_clock / 8).checked_div(baud)?.checked_sub(1)?;
+    u16::try_from(divisor).ok().filter(|&divisor| divisor != 0)
+}
+
+/// Code is sent in blocks of this many bytes, the last one possibly shorter.
+pub const BLOCK_SIZE: u32 = 1024;
+/// How often either side asks for a block again before giving up.
+pub const MAX_RETRIES: u32 = 8;
+/// Most loadable segments a program may have.
+pub const MAX_SEGMENTS: usize = 8;
+
+/// A loadable segment of a program.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    /// Bytes of code sent for this segment.
+    pub length: u32,
+    /// Bytes the segment takes up in memory, zeroed past `length`.
+    pub memory_length: u32,
+}
+
+impl Segment {
+    /// The zeroed part of the segment, as an address and length.
+    pub fn bss(&self) -> (u32, u32) {
+        (self.address + self.length, self.memory_length - self.length)
+    }
+}
 
 pub const CRC_ALGORITHM: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_BZIP2);
+
+/// Timestamps in microseconds, as read from the Pi's free running timer.
+///
+/// Wraps around, so only compare differences.
+pub type Micros = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The other side stopped responding.
+    Timeout,
+    /// The compressed program or bootloader update does not fit in the
+    /// staging area, which holds at most `available` bytes.
+    TooLarge { length: u32, available: u32 },
+    /// A segment or the entry point lies outside loadable memory, which is
+    /// from [BASE] to below `limit`.
+    OutOfBounds {
+        address: u32,
+        length: u32,
+        limit: u32,
+    },
+    /// The program has more than [MAX_SEGMENTS] segments.
+    TooManySegments { count: u32 },
+    /// More than [boot_info::MAX_BLOBS] blobs come along with the program.
+    TooManyBlobs { count: u32 },
+    /// The blob of `length` bytes at `address` overlaps a segment, another
+    /// blob or the staging area, which starts at `other`.
+    Overlap {
+        address: u32,
+        length: u32,
+        other: u32,
+    },
+    /// Got a different protocol word than the one expected next.
+    Unexpected { expected: u32, got: u32 },
+    /// The checksum of the program does not match the one announced.
+    ChecksumMismatch { expected: u32, got: u32 },
+    /// The compressed program is malformed or has the wrong length.
+    Decompress,
+    /// A block kept getting lost or corrupted.
+    TooManyRetries { block: u32 },
+    /// A bootloader update doesn't look like a bootloader.
+    NotBootloader,
+    /// The program is unsigned or signed with a different key than the
+    /// bootloader's.
+    BadSignature,
+    /// The bootloader received the whole program but the installer never
+    /// acknowledged it.
+    MissingAck,
+    /// The bootloader failed and reported why.
+    Pi(Report),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "timed out"),
+            Error::TooLarge { length, available } => write!(
+                f,
+                "program of {length} bytes is too large for the {available} bytes of staging area"
+            ),
+            Error::OutOfBounds {
+                address, length, ..
+            } => write!(
+                f,
+                "{length} bytes at {address:#010x} are outside loadable memory"
+            ),
+            Error::TooManySegments { count } => write!(
+                f,
+                "program has {count} segments but at most {MAX_SEGMENTS} are supported"
+            ),
+            Error::TooManyBlobs { count } => write!(
+                f,
+                "{count} blobs but at most {} are supported",
+                boot_info::MAX_BLOBS
+            ),
+            Error::Overlap {
+                address,
+                length,
+                other,
+            } => write!(
+                f,
+                "blob of {length} bytes at {address:#010x} overlaps memory in use at {other:#010x}"
+            ),
+            Error::Unexpected { expected, got } => {
+                write!(f, "expected {expected:#010x} but got {got:#010x}")
+            }
+            Error::ChecksumMismatch { expected, got } => {
+                write!(
+                    f,
+                    "mismatched checksum: got {got:#010x} but expected {expected:#010x}"
+                )
+            }
+            Error::Decompress => write!(f, "program failed to decompress"),
+            Error::TooManyRetries { block } => {
+                write!(f, "block {block} failed after {MAX_RETRIES} retries")
+            }
+            Error::NotBootloader => write!(f, "update is not a bootloader"),
+            Error::BadSignature => write!(f, "program is not signed with the bootloader's key"),
+            Error::MissingAck => write!(f, "the load was never acknowledged"),
+            Error::Pi(report) => match report.error() {
+                Some(error) => write!(f, "the Pi failed: {error}"),
+                None => write!(f, "the Pi failed with unknown error {:#x}", report.code),
+            },
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+const ERROR_TIMEOUT: u32 = 1;
+const ERROR_TOO_LARGE: u32 = 2;
+const ERROR_OUT_OF_BOUNDS: u32 = 3;
+const ERROR_TOO_MANY_SEGMENTS: u32 = 4;
+const ERROR_UNEXPECTED: u32 = 5;
+const ERROR_CHECKSUM_MISMATCH: u32 = 6;
+const ERROR_DECOMPRESS: u32 = 7;
+const ERROR_TOO_MANY_RETRIES: u32 = 8;
+const ERROR_NOT_BOOTLOADER: u32 = 9;
+const ERROR_BAD_SIGNATURE: u32 = 10;
+const ERROR_MISSING_ACK: u32 = 11;
+const ERROR_TOO_MANY_BLOBS: u32 = 12;
+const ERROR_OVERLAP: u32 = 13;
+
+/// An [Error] as the bootloader sends it after `PI_ERROR`: a code and up to
+/// three arguments, as words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    code: u32,
+    args: [u32; 3],
+}
+
+impl Report {
+    pub const WORDS: usize = 4;
+
+    pub fn from_words(words: [u32; Self::WORDS]) -> Self {
+        let [code, args @ ..] = words;
+        Self { code, args }
+    }
+
+    pub fn words(&self) -> [u32; Self::WORDS] {
+        let [a, b, c] = self.args;
+        [self.code, a, b, c]
+    }
+
+    /// The reported error, unless it is unknown to this side, as from a
+    /// different version of the bootloader.
+    pub fn error(&self) -> Option<Error> {
+        let [a, b, c] = self.args;
+        Some(match self.code {
+            ERROR_TIMEOUT => Error::Timeout,
+            ERROR_TOO_LARGE => Error::TooLarge {
+                length: a,
+                available: b,
+            },
+            ERROR_OUT_OF_BOUNDS => Error::OutOfBounds {
+                address: a,
+                length: b,
+                limit: c,
+            },
+            ERROR_TOO_MANY_SEGMENTS => Error::TooManySegments { count: a },
+            ERROR_UNEXPECTED => Error::Unexpected {
+                expected: a,
+                got: b,
+            },
+            ERROR_CHECKSUM_MISMATCH => Error::ChecksumMismatch {
+                expected: a,
+                got: b,
+            },
+            ERROR_DECOMPRESS => Error::Decompress,
+            ERROR_TOO_MANY_RETRIES => Error::TooManyRetries { block: a },
+            ERROR_NOT_BOOTLOADER => Error::NotBootloader,
+            ERROR_BAD_SIGNATURE => Error::BadSignature,
+            ERROR_MISSING_ACK => Error::MissingAck,
+            ERROR_TOO_MANY_BLOBS => Error::TooManyBlobs { count: a },
+            ERROR_OVERLAP => Error::Overlap {
+                address: a,
+                length: b,
+                other: c,
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl Error {
+    /// How the bootloader reports this error to the installer.
+    pub fn report(&self) -> Report {
+        let (code, args) = match *self {
+            Error::Timeout => (ERROR_TIMEOUT, [0; 3]),
+            Error::TooLarge { length, available } => (ERROR_TOO_LARGE, [length, available, 0]),
+            Error::OutOfBounds {
+                address,
+                length,
+                limit,
+            } => (ERROR_OUT_OF_BOUNDS, [address, length, limit]),
+            Error::TooManySegments { count } => (ERROR_TOO_MANY_SEGMENTS, [count, 0, 0]),
+            Error::Unexpected { expected, got } => (ERROR_UNEXPECTED, [expected, got, 0]),
+            Error::ChecksumMismatch { expected, got } => {
+                (ERROR_CHECKSUM_MISMATCH, [expected, got, 0])
+            }
+            Error::Decompress => (ERROR_DECOMPRESS, [0; 3]),
+            Error::TooManyRetries { block } => (ERROR_TOO_MANY_RETRIES, [block, 0, 0]),
+            Error::NotBootloader => (ERROR_NOT_BOOTLOADER, [0; 3]),
+            Error::BadSignature => (ERROR_BAD_SIGNATURE, [0; 3]),
+            Error::MissingAck => (ERROR_MISSING_ACK, [0; 3]),
+            Error::TooManyBlobs { count } => (ERROR_TOO_MANY_BLOBS, [count, 0, 0]),
+            Error::Overlap {
+                address,
+                length,
+                other,
+            } => (ERROR_OVERLAP, [address, length, other]),
+            // Passed on as is.
+            Error::Pi(report) => return report,
+        };
+        Report { code, args }
+    }
+}
+
+/// Assembles little endian words from a byte stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Word {
+    value: u32,
+    count: u8,
+}
+
+impl Word {
+    /// Returns the word once four bytes have been pushed, then starts over.
+    fn push(&mut self, byte: u8) -> Option<u32> {
+        self.value = (self.value >> 8) + ((byte as u32) << 24);
+        self.count += 1;
+        if self.count < 4 {
+            return None;
+        }
+        let value = self.value;
+        *self = Self::default();
+        Some(value)
+    }
+
+    /// Shift in a byte without ever completing, for matching a word anywhere
+    /// in a stream.
+    fn shift(&mut self, byte: u8) -> u32 {
+        self.value = (self.value >> 8) + ((byte as u32) << 24);
+        self.value
+    }
+}
+
+fn block_count(length: u32) -> u32 {
+    length.div_ceil(BLOCK_SIZE)
+}
+
+/// Length of block `index` of a `length` byte transfer.
+fn block_length(length: u32, index: u32) -> u32 {
+    (length - index * BLOCK_SIZE).min(BLOCK_SIZE)
+}
+
+/// Checksum of a block as sent after its code.
+fn block_checksum(index: u32, code: &[u8]) -> u32 {
+    let mut digest = CRC_ALGORITHM.digest_with_initial(0);
+    digest.update(&index.to_le_bytes());
+    digest.update(code);
+    digest.finalize()
+}
+
+/// Small queue of protocol words waiting to be sent.
+#[derive(Debug)]
+struct Outbox<const N: usize = 16> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Outbox<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, word: u32) {
+        self.buf[self.len..self.len + 4].copy_from_slice(&word.to_le_bytes());
+        self.len += 4;
+    }
+
+    fn take(&mut self) -> Option<&[u8]> {
+        if self.len == 0 {
+            return None;
+        }
+        let len = self.len;
+        self.len = 0;
+        Some(&self.buf[..len])
+    }
+}
@@ -0,0 +1,540 @@
+//! Messages a running program sends to the installer, mixed in with its output.
+//!
+//! A frame is `PI_MESSAGE`, the kind of message, the length of its payload,
+//! the payload and the checksum of everything after `PI_MESSAGE`. Use
+//! [Message::encode] on the Pi, and pass the program's output through a
+//! [Scanner] on the host.
+use crate::{
+    core_dump::{CORE_REGISTERS, MAX_CORE_DATA},
+    hostfs::Mode,
+    Micros, CRC_ALGORITHM, PI_MESSAGE,
+};
+
+/// Most bytes of payload in a frame. Longer test names are cut short.
+pub const MAX_PAYLOAD: usize = 128;
+/// Bytes in the largest frame.
+pub const MAX_FRAME_LENGTH: usize = HEADER_LENGTH + MAX_PAYLOAD + 4;
+/// Exit code of a program that panicked, as with Rust's standard library.
+pub const PANIC_CODE: u32 = 101;
+/// Most samples in one [Message::ProfileSamples].
+pub const MAX_PROFILE_SAMPLES: usize = MAX_PAYLOAD / 8;
+
+/// `PI_MESSAGE`, the kind and the payload length.
+const HEADER_LENGTH: usize = 12;
+const FLAG_PANICKED: u32 = 1;
+
+const KIND_EXIT: u32 = 1;
+const KIND_TESTS: u32 = 2;
+const KIND_TEST_STARTED: u32 = 3;
+const KIND_TEST_PASSED: u32 = 4;
+const KIND_PROFILE_SAMPLES: u32 = 5;
+const KIND_HOST_OPEN: u32 = 6;
+const KIND_HOST_READ: u32 = 7;
+const KIND_HOST_WRITE: u32 = 8;
+const KIND_HOST_CLOSE: u32 = 9;
+const KIND_GDB: u32 = 10;
+const KIND_CORE_REGISTERS: u32 = 11;
+const KIND_CORE_REGION: u32 = 12;
+const KIND_CORE_MEMORY: u32 = 13;
+const KIND_CORE_END: u32 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub code: u32,
+    /// The program stopped because it panicked.
+    pub panicked: bool,
+}
+
+impl ExitStatus {
+    pub const fn success() -> Self {
+        Self::code(0)
+    }
+
+    pub const fn code(code: u32) -> Self {
+        Self {
+            code,
+            panicked: false,
+        }
+    }
+
+    pub const fn panicked() -> Self {
+        Self {
+            code: PANIC_CODE,
+            panicked: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message<'a> {
+    /// The program stopped and is about to return to the bootloader.
+    Exit(ExitStatus),
+    /// A test runner has `count` tests, and waits briefly for
+    /// `INSTALLER_RUN_TESTS` saying which one to start at.
+    Tests {
+        count: u32,
+    },
+    /// Test number `index` is running. If the Pi goes back to the bootloader
+    /// before it passes, it failed.
+    TestStarted {
+        index: u32,
+        name: &'a str,
+    },
+    TestPassed {
+        index: u32,
+        micros: Micros,
+    },
+    /// Program counter samples from a profiler, as little endian address and
+    /// count pairs, see [profile_samples].
+    ProfileSamples {
+        samples: &'a [u8],
+    },
+    /// Asks the installer to open `path` on the host, see [crate::hostfs].
+    /// Paths past [crate::hostfs::MAX_PATH] are cut short.
+    HostOpen {
+        mode: Mode,
+        path: &'a str,
+    },
+    /// Asks for up to `length` bytes from an open file.
+    HostRead {
+        handle: u32,
+        length: u32,
+    },
+    /// Asks to write `data` to an open file, at most
+    /// [crate::hostfs::MAX_WRITE] bytes.
+    HostWrite {
+        handle: u32,
+        data: &'a [u8],
+    },
+    HostClose {
+        handle: u32,
+    },
+    /// Output of the GDB stub for the installer to forward, see
+    /// [crate::gdb]. Data past [MAX_PAYLOAD] is left out.
+    Gdb {
+        data: &'a [u8],
+    },
+    /// The program faulted and a core dump follows, see [crate::core_dump].
+    CoreRegisters {
+        signal: u32,
+        registers: [u32; CORE_REGISTERS],
+    },
+    /// A region of memory in the core dump.
+    CoreRegion {
+        address: u32,
+        length: u32,
+    },
+    /// Part of a region, at most [MAX_CORE_DATA] bytes.
+    CoreMemory {
+        address: u32,
+        data: &'a [u8],
+    },
+    /// The core dump is complete.
+    CoreEnd,
+}
+
+impl<'a> Message<'a> {
+    /// Encode the frame into `buf`, returning the part used.
+    pub fn encode<'b>(&self, buf: &'b mut [u8; MAX_FRAME_LENGTH]) -> &'b [u8] {
+        let mut payload = [0; MAX_PAYLOAD];
+        let (kind, length) = match *self {
+            Message::Exit(status) => {
+                let flags = if status.panicked { FLAG_PANICKED } else { 0 };
+                (KIND_EXIT, put_words(&mut payload, &[status.code, flags]))
+            }
+            Message::Tests { count } => (KIND_TESTS, put_words(&mut payload, &[count])),
+            Message::TestStarted { index, name } => {
+                let mut length = put_words(&mut payload, &[index]);
+                let name = truncate(name, MAX_PAYLOAD - length);
+                payload[length..length + name.len()].copy_from_slice(name.as_bytes());
+                length += name.len();
+                (KIND_TEST_STARTED, length)
+            }
+            Message::TestPassed { index, micros } => {
+                (KIND_TEST_PASSED, put_words(&mut payload, &[index, micros]))
+            }
+            Message::ProfileSamples { samples } => {
+                let length = samples.len().min(MAX_PROFILE_SAMPLES * 8) / 8 * 8;
+                payload[..length].copy_from_slice(&samples[..length]);
+                (KIND_PROFILE_SAMPLES, length)
+            }
+            Message::HostOpen { mode, path } => {
+                let mut length = put_words(&mut payload, &[mode.word()]);
+                let path = truncate(path, MAX_PAYLOAD - length);
+                payload[length..length + path.len()].copy_from_slice(path.as_bytes());
+                length += path.len();
+                (KIND_HOST_OPEN, length)
+            }
+            Message::HostRead { handle, length } => {
+                (KIND_HOST_READ, put_words(&mut payload, &[handle, length]))
+            }
+            Message::HostWrite { handle, data } => {
+                let mut length = put_words(&mut payload, &[handle]);
+                let data = &data[..data.len().min(MAX_PAYLOAD - length)];
+                payload[length..length + data.len()].copy_from_slice(data);
+                length += data.len();
+                (KIND_HOST_WRITE, length)
+            }
+            Message::HostClose { handle } => (KIND_HOST_CLOSE, put_words(&mut payload, &[handle])),
+            Message::Gdb { data } => {
+                let length = data.len().min(MAX_PAYLOAD);
+                payload[..length].copy_from_slice(&data[..length]);
+                (KIND_GDB, length)
+            }
+            Message::CoreRegisters { signal, registers } => {
+                let mut words = [0; 1 + CORE_REGISTERS];
+                words[0] = signal;
+                words[1..].copy_from_slice(&registers);
+                (KIND_CORE_REGISTERS, put_words(&mut payload, &words))
+            }
+            Message::CoreRegion { address, length } => (
+                KIND_CORE_REGION,
+                put_words(&mut payload, &[address, length]),
+            ),
+            Message::CoreMemory { address, data } => {
+                let mut length = put_words(&mut payload, &[address]);
+                let data = &data[..data.len().min(MAX_CORE_DATA)];
+                payload[length..length + data.len()].copy_from_slice(data);
+                length += data.len();
+                (KIND_CORE_MEMORY, length)
+            }
+            Message::CoreEnd => (KIND_CORE_END, 0),
+        };
+        let end = HEADER_LENGTH + length;
+        buf[0..4].copy_from_slice(&PI_MESSAGE.to_le_bytes());
+        buf[4..8].copy_from_slice(&kind.to_le_bytes());
+        buf[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+        buf[HEADER_LENGTH..end].copy_from_slice(&payload[..length]);
+        let checksum = checksum(&buf[4..end]);
+        buf[end..end + 4].copy_from_slice(&checksum.to_le_bytes());
+        &buf[..end + 4]
+    }
+
+    /// Decode a whole frame, if it is a valid one.
+    fn decode(frame: &'a [u8]) -> Option<Self> {
+        let end = frame.len().checked_sub(4)?;
+        if frame.len() < HEADER_LENGTH + 4
+            || word(frame, 0) != PI_MESSAGE
+            || word(frame, 8) as usize != end - HEADER_LENGTH
+            || word(frame, end) != checksum(&frame[4..end])
+        {
+            return None;
+        }
+        let payload = &frame[HEADER_LENGTH..end];
+        match (word(frame, 4), payload.len()) {
+            (KIND_EXIT, 8) => Some(Message::Exit(ExitStatus {
+                code: word(payload, 0),
+                panicked: word(payload, 4) & FLAG_PANICKED != 0,
+            })),
+            (KIND_TESTS, 4) => Some(Message::Tests {
+                count: word(payload, 0),
+            }),
+            (KIND_TEST_STARTED, 4..) => Some(Message::TestStarted {
+                index: word(payload, 0),
+                name: core::str::from_utf8(&payload[4..]).ok()?,
+            }),
+            (KIND_TEST_PASSED, 8) => Some(Message::TestPassed {
+                index: word(payload, 0),
+                micros: word(payload, 4),
+            }),
+            (KIND_PROFILE_SAMPLES, length) if length % 8 == 0 => {
+                Some(Message::ProfileSamples { samples: payload })
+            }
+            (KIND_HOST_OPEN, 4..) => Some(Message::HostOpen {
+                mode: Mode::from_word(word(payload, 0))?,
+                path: core::str::from_utf8(&payload[4..]).ok()?,
+            }),
+            (KIND_HOST_READ, 8) => Some(Message::HostRead {
+                handle: word(payload, 0),
+                length: word(payload, 4),
+            }),
+            (KIND_HOST_WRITE, 4..) => Some(Message::HostWrite {
+                handle: word(payload, 0),
+                data: &payload[4..],
+            }),
+            (KIND_HOST_CLOSE, 4) => Some(Message::HostClose {
+                handle: word(payload, 0),
+            }),
+            (KIND_GDB, _) => Some(Message::Gdb { data: payload }),
+            (KIND_CORE_REGISTERS, length) if length == 4 * (1 + CORE_REGISTERS) => {
+                Some(Message::CoreRegisters {
+                    signal: word(payload, 0),
+                    registers: core::array::from_fn(|i| word(payload, 4 + 4 * i)),
+                })
+            }
+            (KIND_CORE_REGION, 8) => Some(Message::CoreRegion {
+                address: word(payload, 0),
+                length: word(payload, 4),
+            }),
+            (KIND_CORE_MEMORY, 4..) => Some(Message::CoreMemory {
+                address: word(payload, 0),
+                data: &payload[4..],
+            }),
+            (KIND_CORE_END, 0) => Some(Message::CoreEnd),
+            _ => None,
+        }
+    }
+}
+
+/// The address and count pairs in [Message::ProfileSamples].
+pub fn profile_samples(samples: &[u8]) -> impl Iterator<Item = (u32, u32)> + '_ {
+    samples
+        .chunks_exact(8)
+        .map(|pair| (word(pair, 0), word(pair, 4)))
+}
+
+fn put_words(payload: &mut [u8], words: &[u32]) -> usize {
+    for (chunk, word) in payload.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    words.len() * 4
+}
+
+pub(crate) fn word(bytes: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(bytes[index..index + 4].try_into().unwrap())
+}
+
+/// The longest prefix of `s` that fits in `length` bytes.
+fn truncate(s: &str, length: usize) -> &str {
+    let mut end = s.len().min(length);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    let mut checksum = CRC_ALGORITHM.digest_with_initial(0);
+    checksum.update(bytes);
+    checksum.finalize()
+}
+
+/// Picks message frames out of program output.
+///
+/// Bytes that might start a frame are held back until it is clear whether they
+/// do, so frames never show up in the output.
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    held: [u8; MAX_FRAME_LENGTH],
+    held_length: usize,
+    released: [u8; MAX_FRAME_LENGTH],
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Self {
+            held: [0; MAX_FRAME_LENGTH],
+            held_length: 0,
+            released: [0; MAX_FRAME_LENGTH],
+        }
+    }
+
+    /// Add a byte of output, returning the bytes that are plain output and the
+    /// message if this byte completed a frame.
+    pub fn push(&mut self, byte: u8) -> (&[u8], Option<Message<'_>>) {
+        self.held[self.held_length] = byte;
+        self.held_length += 1;
+        // The empty suffix could always start a frame, so this stops.
+        let mut start = 0;
+        while !Self::could_be_frame(&self.held[start..self.held_length]) {
+            start += 1;
+        }
+        self.released[..start].copy_from_slice(&self.held[..start]);
+        self.held.copy_within(start..self.held_length, 0);
+        self.held_length -= start;
+
+        let frame = &self.held[..self.held_length];
+        let mut message = None;
+        if frame.len() >= HEADER_LENGTH && frame.len() == frame_length(frame) {
+            message = Message::decode(frame);
+            self.held_length = 0;
+        }
+        (&self.released[..start], message)
+    }
+
+    /// Bytes held back because they might start a frame.
+    pub fn pending(&self) -> &[u8] {
+        &self.held[..self.held_length]
+    }
+
+    fn could_be_frame(bytes: &[u8]) -> bool {
+        let magic = PI_MESSAGE.to_le_bytes();
+        let n = bytes.len().min(magic.len());
+        if bytes[..n] != magic[..n] {
+            return false;
+        }
+        if bytes.len() < HEADER_LENGTH {
+            return true;
+        }
+        if word(bytes, 8) as usize > MAX_PAYLOAD {
+            return false;
+        }
+        bytes.len() < frame_length(bytes) || Message::decode(bytes).is_some()
+    }
+}
+
+/// Length of the frame starting with the header in `bytes`.
+fn frame_length(bytes: &[u8]) -> usize {
+    HEADER_LENGTH + word(bytes, 8) as usize + 4
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::{core_dump::SIGSEGV, tests::program, BASE};
+
+    fn frame(message: Message) -> Vec<u8> {
+        message.encode(&mut [0; MAX_FRAME_LENGTH]).to_vec()
+    }
+
+    /// Run output through a [Scanner], returning what gets printed and the
+    /// frames of the messages found.
+    fn scan(output: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut scanner = Scanner::new();
+        let mut printed = Vec::new();
+        let mut messages = Vec::new();
+        for &byte in output {
+            let (bytes, message) = scanner.push(byte);
+            printed.extend_from_slice(bytes);
+            messages.extend(message.map(frame));
+        }
+        printed.extend_from_slice(scanner.pending());
+        (printed, messages)
+    }
+
+    #[test]
+    fn finds_messages_in_output() {
+        let exit = frame(Message::Exit(ExitStatus::code(3)));
+        let started = frame(Message::TestStarted {
+            index: 2,
+            name: "app::tests::adds",
+        });
+        let output = [b"hello", &started[..], b"bye", &exit[..]].concat();
+        assert_eq!(scan(&output), (b"hellobye".to_vec(), vec![started, exit]));
+    }
+
+    #[test]
+    fn round_trips_profile_samples() {
+        let pairs = [(0x8000, 3), (0x8004, 1), (0x9abc, 70000)];
+        let samples = pairs
+            .iter()
+            .flat_map(|&(address, count): &(u32, u32)| [address.to_le_bytes(), count.to_le_bytes()])
+            .flatten()
+            .collect::<Vec<_>>();
+        let frame = frame(Message::ProfileSamples { samples: &samples });
+        let mut scanner = Scanner::new();
+        let (last, rest) = frame.split_last().unwrap();
+        rest.iter()
+            .for_each(|&byte| assert!(scanner.push(byte).1.is_none()));
+        let (_, Some(Message::ProfileSamples { samples })) = scanner.push(*last) else {
+            panic!("no samples in {frame:?}");
+        };
+        assert_eq!(profile_samples(samples).collect::<Vec<_>>(), pairs);
+    }
+
+    #[test]
+    fn finds_message_after_partial_magic() {
+        let exit = frame(Message::Exit(ExitStatus::panicked()));
+        let output = [&exit[..3], &exit[..]].concat();
+        assert_eq!(scan(&output), (exit[..3].to_vec(), vec![exit]));
+    }
+
+    #[test]
+    fn passes_corrupted_message_through() {
+        let mut exit = frame(Message::Exit(ExitStatus::code(1)));
+        exit[13] ^= 1;
+        assert_eq!(scan(&exit), (exit.clone(), vec![]));
+        // Too long to be a frame.
+        let mut tests = frame(Message::Tests { count: 1 });
+        tests[9] = 1;
+        assert_eq!(scan(&tests), (tests.clone(), vec![]));
+    }
+
+    #[test]
+    fn truncates_long_test_names() {
+        let name = "é".repeat(MAX_PAYLOAD);
+        let started = frame(Message::TestStarted {
+            index: 0,
+            name: &name,
+        });
+        assert_eq!(started.len(), MAX_FRAME_LENGTH);
+        let mut scanner = Scanner::new();
+        let (last, rest) = started.split_last().unwrap();
+        for &byte in rest {
+            assert_eq!(scanner.push(byte), (&[][..], None));
+        }
+        let (_, Some(Message::TestStarted { name: got, .. })) = scanner.push(*last) else {
+            panic!("no message in {started:?}");
+        };
+        assert_eq!(got, &name[..MAX_PAYLOAD - 4]);
+    }
+
+    #[test]
+    fn round_trips_host_requests() {
+        let data = [7; 200];
+        let requests = [
+            Message::HostOpen {
+                mode: Mode::Append,
+                path: "results/out.bin",
+            },
+            Message::HostRead {
+                handle: 3,
+                length: 128,
+            },
+            Message::HostWrite {
+                handle: 3,
+                data: &data[..100],
+            },
+            Message::HostClose { handle: 3 },
+        ];
+        for request in requests {
+            let frame = frame(request);
+            assert_eq!(scan(&frame), (vec![], vec![frame]));
+        }
+        // Writes are cut to what fits.
+        let frame = frame(Message::HostWrite {
+            handle: 1,
+            data: &data,
+        });
+        let mut scanner = Scanner::new();
+        let (last, rest) = frame.split_last().unwrap();
+        rest.iter()
+            .for_each(|&byte| assert!(scanner.push(byte).1.is_none()));
+        let (_, Some(Message::HostWrite { data: sent, .. })) = scanner.push(*last) else {
+            panic!("no write in {frame:?}");
+        };
+        assert_eq!(sent, &data[..MAX_PAYLOAD - 4]);
+    }
+
+    #[test]
+    fn round_trips_core_messages() {
+        let data = program(MAX_CORE_DATA);
+        let registers = core::array::from_fn(|i| 0x1000 + i as u32);
+        let messages = [
+            Message::CoreRegisters {
+                signal: SIGSEGV,
+                registers,
+            },
+            Message::CoreRegion {
+                address: BASE,
+                length: 0x4000,
+            },
+            Message::CoreMemory {
+                address: BASE + 0x100,
+                data: &data,
+            },
+            Message::CoreEnd,
+        ];
+        let output: Vec<u8> = messages.iter().flat_map(|&m| frame(m)).collect();
+        let frames = messages.map(frame).to_vec();
+        assert_eq!(scan(&output), (vec![], frames));
+    }
+}
@@ -0,0 +1,907 @@
+//! Bootloader side of the load protocol.
+//!
+//! Feed received bytes to [Bootloader::receive], call [Bootloader::poll]
+//! regularly, and send whatever [Bootloader::transmit] returns. Code arrives in
+//! checksummed blocks, and blocks that are corrupted or go missing are asked
+//! for again. Each [Event::Code] says where its bytes go, which may be several
+//! segments.
+//!
+//! Compressed programs are received into the staging area, and once
+//! [Bootloader::decompress] returns a request, must be decompressed and handed
+//! to [Bootloader::decompressed].
+//!
+//! A bootloader update is received into the staging area too, and
+//! [Event::Update] says where to find it once verified.
+//!
+//! Blobs sent along with the program are placed like segments, see
+//! [crate::boot_info]. Before jumping to the program, write
+//! [Bootloader::boot_info] to [crate::boot_info::BOOT_INFO].
+//!
+//! The installer may propose another baud rate first, see
+//! [crate::DEFAULT_BAUD]. Whenever [Bootloader::baud] changes after sending
+//! what [Bootloader::transmit] returns, switch the UART to it. Rates the UART
+//! can't run at, given the core clock from [Bootloader::with_core_clock], are
+//! refused.
+//!
+//! A bootloader given a key with [Bootloader::with_key] only finishes loading
+//! programs signed with it. Code is still placed as it arrives, so an
+//! unverified program may be in memory, but it is never jumped to.
+use crate::{
+    baud_divisor, block_checksum, block_count, block_length,
+    boot_info::{Blob, BootInfo, BLOB_ALIGN, BLOB_NAME_LENGTH, MAX_BLOBS},
+    is_bootloader,
+    sign::{Key, Signature, Signer, SIGNATURE_LENGTH},
+    Error, Micros, Outbox, Segment, Word, BASE, BAUD_PATTERN, BLOCK_SIZE, CRC_ALGORITHM,
+    DEFAULT_BAUD, DEFAULT_CORE_CLOCK, INSTALLER_BAUD, INSTALLER_BAUD_CHECK, INSTALLER_BLOBS,
+    INSTALLER_CODE, INSTALLER_PROG_INFO, INSTALLER_PROG_INFO_LZ4, INSTALLER_SIGNATURE,
+    INSTALLER_SUCCESS, INSTALLER_UPDATE_INFO, MAX_BOOTLOADER_LENGTH, MAX_RETRIES, MAX_SEGMENTS,
+    PI_BAUD, PI_BAUD_OK, PI_BAUD_UNSUPPORTED, PI_BLOCK_OK, PI_BLOCK_RESEND, PI_GET_CODE,
+    PI_GET_PROG_INFO, PI_SUCCESS,
+};
+
+/// How often to ask for a program while no installer has answered.
+pub const PROG_INFO_INTERVAL: Micros = 300_000;
+/// Longest silence allowed once an installer has answered. While receiving
+/// code, the current block is asked for again instead, and once it all
+/// arrived, `PI_SUCCESS` is sent again until acknowledged. Leaves room for a
+/// USB serial adapter's latency and a busy host, while staying below the
+/// installer's [ACK_TIMEOUT] so that the Pi asks for a lost block first.
+///
+/// [ACK_TIMEOUT]: crate::installer::ACK_TIMEOUT
+pub const BYTE_TIMEOUT: Micros = 200_000;
+/// How long to wait at a proposed baud rate for [BAUD_PATTERN], and then for
+/// the program info, before falling back to [DEFAULT_BAUD].
+pub const BAUD_TIMEOUT: Micros = 500_000;
+
+/// Memory the bootloader may load into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Segments, blobs and the entry point must lie between [BASE] and
+    /// `limit`. Blobs without an address are placed right below `limit`.
+    pub limit: u32,
+    /// Compressed programs are received and decompressed at `staging` and
+    /// must end below `staging_limit`. Must not overlap the program.
+    pub staging: u32,
+    pub staging_limit: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for `INSTALLER_PROG_INFO`, `INSTALLER_PROG_INFO_LZ4`,
+    /// `INSTALLER_UPDATE_INFO`, `INSTALLER_SIGNATURE`, `INSTALLER_BLOBS` or
+    /// `INSTALLER_BAUD` anywhere in the stream.
+    ProgInfo {
+        word: Word,
+    },
+    /// Waiting for the rate after `INSTALLER_BAUD`.
+    BaudRate,
+    /// Waiting at the proposed rate for `INSTALLER_BAUD_CHECK` anywhere in the
+    /// stream.
+    BaudCheck {
+        word: Word,
+    },
+    BaudPattern {
+        received: usize,
+    },
+    Signature {
+        received: usize,
+    },
+    BlobCount,
+    BlobAddress {
+        index: usize,
+    },
+    BlobLength {
+        index: usize,
+    },
+    BlobName {
+        index: usize,
+        received: usize,
+    },
+    Entry,
+    SegmentCount,
+    SegmentAddress {
+        index: usize,
+    },
+    SegmentLength {
+        index: usize,
+    },
+    SegmentMemoryLength {
+        index: usize,
+    },
+    Checksum,
+    CompressedLength,
+    /// Waiting for `INSTALLER_CODE` anywhere in the stream.
+    Block {
+        word: Word,
+    },
+    BlockIndex,
+    BlockCode {
+        index: u32,
+        received: usize,
+    },
+    BlockChecksum {
+        index: u32,
+    },
+    /// Waiting for the caller to decompress the staged program.
+    Decompress,
+    /// Waiting for `INSTALLER_SUCCESS`, ignoring blocks resent because their
+    /// acknowledgement got lost.
+    Success {
+        word: Word,
+    },
+    Done,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    None,
+    /// Verified program bytes to place.
+    Code(Code<'a>),
+    /// The installer acknowledged the load. Zero the BSS of each segment and
+    /// jump to `entry`.
+    Done {
+        entry: u32,
+    },
+    /// The installer acknowledged a bootloader update, whose `length` bytes
+    /// are at `address`. Write it to the SD card instead of jumping anywhere.
+    Update {
+        address: u32,
+        length: u32,
+    },
+}
+
+/// Verified program bytes, as an iterator of addresses and the bytes to place
+/// there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code<'a> {
+    /// Where the code goes, or `None` for the staging area.
+    segments: Option<&'a [Segment]>,
+    staging: u32,
+    /// Offset of `data` into the code.
+    offset: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Code<'a> {
+    type Item = (u32, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let Some(segments) = self.segments else {
+            let data = core::mem::take(&mut self.data);
+            return Some((self.staging + self.offset, data));
+        };
+        let mut start = 0;
+        for segment in segments {
+            let end = start + segment.length;
+            if self.offset < end {
+                let count = ((end - self.offset) as usize).min(self.data.len());
+                let (data, rest) = self.data.split_at(count);
+                let address = segment.address + (self.offset - start);
+                self.offset += count as u32;
+                self.data = rest;
+                return Some((address, data));
+            }
+            start = end;
+        }
+        None
+    }
+}
+
+/// A compressed program waiting in the staging area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decompress {
+    pub address: u32,
+    pub compressed_length: u32,
+    /// Decompress to `output`, where exactly `length` bytes must come out.
+    pub output: u32,
+    pub length: u32,
+}
+
+pub struct Bootloader {
+    state: State,
+    word: Word,
+    layout: Layout,
+    entry: u32,
+    /// The program's segments, followed by the blobs once placed.
+    segments: [Segment; MAX_SEGMENTS + MAX_BLOBS],
+    segment_count: usize,
+    /// Blobs as sent, see [Bootloader::regions] for where they go.
+    blobs: [Blob; MAX_BLOBS],
+    blob_count: usize,
+    /// Length of the code, uncompressed.
+    length: u32,
+    /// Set for compressed programs.
+    compressed_length: Option<u32>,
+    /// Receiving a bootloader update rather than a program.
+    update: bool,
+    checksum: u32,
+    digest: crc::Digest<'static, u32>,
+    /// Programs must be signed with this key.
+    key: Option<Key>,
+    signature: Option<Signature>,
+    /// Checks the signature of the code, with a key.
+    signer: Option<Signer>,
+    /// Index of the next block to accept.
+    expected: u32,
+    /// Times the next block has been asked for again.
+    retries: u32,
+    block: [u8; BLOCK_SIZE as usize],
+    out: Outbox,
+    /// Time of the last request while waiting for an installer, and of the
+    /// last received byte afterwards.
+    last: Micros,
+    baud: u32,
+    /// When the UART switched away from [DEFAULT_BAUD].
+    baud_since: Micros,
+    /// What the UART's rate derives from, to tell which rates it can run at.
+    core_clock: u32,
+}
+
+impl Bootloader {
+    pub fn new(layout: Layout, now: Micros) -> Self {
+        let mut out = Outbox::new();
+        out.push(PI_GET_PROG_INFO);
+        Self {
+            state: State::ProgInfo {
+                word: Word::default(),
+            },
+            word: Word::default(),
+            layout,
+            entry: BASE,
+            segments: [Segment::default(); MAX_SEGMENTS + MAX_BLOBS],
+            segment_count: 0,
+            blobs: [Blob::default(); MAX_BLOBS],
+            blob_count: 0,
+            length: 0,
+            compressed_length: None,
+            update: false,
+            checksum: 0,
+            digest: CRC_ALGORITHM.digest_with_initial(0),
+            key: None,
+            signature: None,
+            signer: None,
+            expected: 0,
+            retries: 0,
+            block: [0; BLOCK_SIZE as usize],
+            out,
+            last: now,
+            baud: DEFAULT_BAUD,
+            baud_since: now,
+            core_clock: DEFAULT_CORE_CLOCK,
+        }
+    }
+
+    /// Run the UART from `core_clock` rather than [DEFAULT_CORE_CLOCK].
+    pub fn with_core_clock(self, core_clock: u32) -> Self {
+        Self { core_clock, ..self }
+    }
+
+    /// Only load programs signed with `key`.
+    pub fn with_key(self, key: Key) -> Self {
+        Self {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// The rate the UART should run at.
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Segments of the program, valid once the program info arrived.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments[..self.segment_count]
+    }
+
+    /// Where the blobs went, valid once the program info arrived.
+    pub fn boot_info(&self) -> BootInfo {
+        let mut blobs = self.blobs;
+        for (blob, segment) in blobs.iter_mut().zip(&self.segments[self.segment_count..]) {
+            blob.address = segment.address;
+        }
+        BootInfo::new(&blobs[..self.blob_count])
+    }
+
+    /// Segments followed by blobs, as the code arrives.
+    fn regions(&self) -> &[Segment] {
+        &self.segments[..self.segment_count + self.blob_count]
+    }
+
+    /// Bytes to send to the installer.
+    pub fn transmit(&mut self) -> Option<&[u8]> {
+        self.out.take()
+    }
+
+    /// Resend requests and check for timeouts.
+    pub fn poll(&mut self, now: Micros) -> Result<(), Error> {
+        match self.state {
+            State::ProgInfo { .. } => {
+                // The installer may have missed `PI_BAUD_OK` and fallen back.
+                if self.baud != DEFAULT_BAUD && now.wrapping_sub(self.baud_since) > BAUD_TIMEOUT {
+                    self.fall_back(now);
+                }
+                if now.wrapping_sub(self.last) >= PROG_INFO_INTERVAL {
+                    self.out.push(PI_GET_PROG_INFO);
+                    self.last = now;
+                }
+                Ok(())
+            }
+            State::Block { .. }
+            | State::BlockIndex
+            | State::BlockCode { .. }
+            | State::BlockChecksum { .. } => {
+                if now.wrapping_sub(self.last) > BYTE_TIMEOUT {
+                    self.last = now;
+                    self.resend()?;
+                }
+                Ok(())
+            }
+            State::BaudCheck { .. } | State::BaudPattern { .. } => {
+                if now.wrapping_sub(self.baud_since) > BAUD_TIMEOUT {
+                    self.fall_back(now);
+                }
+                Ok(())
+            }
+            State::Decompress | State::Done => Ok(()),
+            State::Success { .. } if now.wrapping_sub(self.last) > BYTE_TIMEOUT => {
+                // It may have got lost, or the last block's acknowledgement,
+                // which it stands in for.
+                self.retries += 1;
+                if self.retries > MAX_RETRIES {
+                    return Err(Error::MissingAck);
+                }
+                self.out.push(PI_SUCCESS);
+                self.last = now;
+                Ok(())
+            }
+            _ if now.wrapping_sub(self.last) > BYTE_TIMEOUT => Err(Error::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// The staged program to decompress, once it has arrived.
+    pub fn decompress(&self) -> Option<Decompress> {
+        if self.state != State::Decompress {
+            return None;
+        }
+        let compressed_length = self.compressed_length?;
+        Some(Decompress {
+            address: self.layout.staging,
+            compressed_length,
+            output: self.layout.staging + compressed_length,
+            length: self.length,
+        })
+    }
+
+    /// Verify the decompressed program, returning where to place it.
+    pub fn decompressed<'a>(&'a mut self, code: &'a [u8], now: Micros) -> Result<Code<'a>, Error> {
+        if self.state != State::Decompress || code.len() != self.length as usize {
+            return Err(Error::Decompress);
+        }
+        self.digest.update(code);
+        if let Some(signer) = &mut self.signer {
+            signer.update(code);
+        }
+        self.last = now;
+        self.finish_code()?;
+        Ok(Code {
+            segments: Some(self.regions()),
+            staging: self.layout.staging,
+            offset: 0,
+            data: code,
+        })
+    }
+
+    /// Consume a prefix of `input`, returning how many bytes were used and
+    /// what the caller should do with them.
+    pub fn receive<'a>(
+        &'a mut self,
+        input: &[u8],
+        now: Micros,
+    ) -> Result<(usize, Event<'a>), Error> {
+        if !matches!(self.state, State::ProgInfo { .. }) {
+            self.last = now;
+        }
+        let total = self.transfer_length();
+        if let State::BlockCode { index, received } = &mut self.state {
+            let length = block_length(total, *index) as usize;
+            let count = input.len().min(length - *received);
+            self.block[*received..*received + count].copy_from_slice(&input[..count]);
+            *received += count;
+            if *received == length {
+                self.state = State::BlockChecksum { index: *index };
+            }
+            return Ok((count, Event::None));
+        }
+
+        for (index, &byte) in input.iter().enumerate() {
+            let used = index + 1;
+            match &mut self.state {
+                State::ProgInfo { word } => {
+                    let word = word.shift(byte);
+                    if word == INSTALLER_SIGNATURE {
+                        self.state = State::Signature { received: 0 };
+                        self.last = now;
+                        return Ok((used, Event::None));
+                    }
+                    if word == INSTALLER_BAUD {
+                        self.state = State::BaudRate;
+                        self.last = now;
+                        return Ok((used, Event::None));
+                    }
+                    if word == INSTALLER_BLOBS {
+                        self.state = State::BlobCount;
+                        self.last = now;
+                        return Ok((used, Event::None));
+                    }
+                    if matches!(
+                        word,
+                        INSTALLER_PROG_INFO | INSTALLER_PROG_INFO_LZ4 | INSTALLER_UPDATE_INFO
+                    ) {
+                        self.compressed_length = (word == INSTALLER_PROG_INFO_LZ4).then_some(0);
+                        self.update = word == INSTALLER_UPDATE_INFO;
+                        if self.key.is_some() && self.signature.is_none() {
+                            return Err(Error::BadSignature);
+                        }
+                        self.state = State::Entry;
+                        self.last = now;
+                        return Ok((used, Event::None));
+                    }
+                }
+                State::BaudRate => {
+                    let Some(baud) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if baud_divisor(self.core_clock, baud).is_none() {
+                        self.out.push(PI_BAUD_UNSUPPORTED);
+                        self.out.push(baud);
+                        self.out.push(PI_GET_PROG_INFO);
+                        self.state = State::ProgInfo {
+                            word: Word::default(),
+                        };
+                        return Ok((used, Event::None));
+                    }
+                    // Sent at the old rate, switching afterwards.
+                    self.out.push(PI_BAUD);
+                    self.out.push(baud);
+                    self.baud = baud;
+                    self.baud_since = now;
+                    self.state = State::BaudCheck {
+                        word: Word::default(),
+                    };
+                    return Ok((used, Event::None));
+                }
+                State::BaudCheck { word } => {
+                    if word.shift(byte) == INSTALLER_BAUD_CHECK {
+                        self.state = State::BaudPattern { received: 0 };
+                        return Ok((used, Event::None));
+                    }
+                }
+                State::BaudPattern { received } => {
+                    if byte != BAUD_PATTERN[*received] {
+                        self.fall_back(now);
+                        return Ok((used, Event::None));
+                    }
+                    *received += 1;
+                    if *received == BAUD_PATTERN.len() {
+                        self.out.push(PI_BAUD_OK);
+                        self.out.push(PI_GET_PROG_INFO);
+                        self.baud_since = now;
+                        self.state = State::ProgInfo {
+                            word: Word::default(),
+                        };
+                    }
+                    return Ok((used, Event::None));
+                }
+                State::Signature { received } => {
+                    let signature = self.signature.get_or_insert([0; SIGNATURE_LENGTH]);
+                    signature[*received] = byte;
+                    *received += 1;
+                    if *received == SIGNATURE_LENGTH {
+                        self.state = State::ProgInfo {
+                            word: Word::default(),
+                        };
+                    }
+                    return Ok((used, Event::None));
+                }
+                State::BlobCount => {
+                    let Some(count) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if count as usize > MAX_BLOBS {
+                        return Err(Error::TooManyBlobs { count });
+                    }
+                    self.blob_count = count as usize;
+                    self.state = if count == 0 {
+                        State::ProgInfo {
+                            word: Word::default(),
+                        }
+                    } else {
+                        State::BlobAddress { index: 0 }
+                    };
+                    return Ok((used, Event::None));
+                }
+                State::BlobAddress { index } => {
+                    let index = *index;
+                    let Some(address) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.blobs[index].address = address;
+                    self.state = State::BlobLength { index };
+                    return Ok((used, Event::None));
+                }
+                State::BlobLength { index } => {
+                    let index = *index;
+                    let Some(length) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.blobs[index].length = length;
+                    self.state = State::BlobName { index, received: 0 };
+                    return Ok((used, Event::None));
+                }
+                State::BlobName { index, received } => {
+                    let index = *index;
+                    self.blobs[index].name[*received] = byte;
+                    *received += 1;
+                    if *received == BLOB_NAME_LENGTH {
+                        self.state = if index + 1 == self.blob_count {
+                            State::ProgInfo {
+                                word: Word::default(),
+                            }
+                        } else {
+                            State::BlobAddress { index: index + 1 }
+                        };
+                    }
+                    return Ok((used, Event::None));
+                }
+                State::Entry => {
+                    let Some(entry) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.check_bounds(entry, 0)?;
+                    self.entry = entry;
+                    self.state = State::SegmentCount;
+                    return Ok((used, Event::None));
+                }
+                State::SegmentCount => {
+                    let Some(count) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if count as usize > MAX_SEGMENTS {
+                        return Err(Error::TooManySegments { count });
+                    }
+                    self.segment_count = count as usize;
+                    self.length = 0;
+                    self.state = if count == 0 {
+                        State::Checksum
+                    } else {
+                        State::SegmentAddress { index: 0 }
+                    };
+                    return Ok((used, Event::None));
+                }
+                State::SegmentAddress { index } => {
+                    let index = *index;
+                    let Some(address) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.segments[index].address = address;
+                    self.state = State::SegmentLength { index };
+                    return Ok((used, Event::None));
+                }
+                State::SegmentLength { index } => {
+                    let index = *index;
+                    let Some(length) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.segments[index].length = length;
+                    self.state = State::SegmentMemoryLength { index };
+                    return Ok((used, Event::None));
+                }
+                State::SegmentMemoryLength { index } => {
+                    let index = *index;
+                    let Some(memory_length) = self.word.push(byte) else {
+                        continue;
+                    };
+                    let segment = &mut self.segments[index];
+                    // Never shorter than the code.
+                    segment.memory_length = memory_length.max(segment.length);
+                    let segment = *segment;
+                    // If there's not enough space, error.
+                    self.check_bounds(segment.address, segment.memory_length)?;
+                    self.length += segment.length;
+                    self.state = if index + 1 == self.segment_count {
+                        State::Checksum
+                    } else {
+                        State::SegmentAddress { index: index + 1 }
+                    };
+                    return Ok((used, Event::None));
+                }
+                State::Checksum => {
+                    let Some(checksum) = self.word.push(byte) else {
+                        continue;
+                    };
+                    self.checksum = checksum;
+                    self.place_blobs()?;
+                    if self.update {
+                        self.check_update()?;
+                    }
+                    if self.compressed_length.is_some() {
+                        self.state = State::CompressedLength;
+                    } else {
+                        self.start_code()?;
+                    }
+                    return Ok((used, Event::None));
+                }
+                State::CompressedLength => {
+                    let Some(length) = self.word.push(byte) else {
+                        continue;
+                    };
+                    // Room for both the compressed and decompressed code.
+                    let staged = length as u64 + self.length as u64;
+                    let available = self.staging_room();
+                    if staged > available as u64 {
+                        return Err(Error::TooLarge {
+                            length: staged.min(u32::MAX as u64) as u32,
+                            available,
+                        });
+                    }
+                    self.compressed_length = Some(length);
+                    self.start_code()?;
+                    return Ok((used, Event::None));
+                }
+                State::Block { word } => {
+                    if word.shift(byte) == INSTALLER_CODE {
+                        self.state = State::BlockIndex;
+                        return Ok((used, Event::None));
+                    }
+                }
+                State::BlockIndex => {
+                    let Some(index) = self.word.push(byte) else {
+                        continue;
+                    };
+                    if index >= block_count(total) {
+                        self.resend()?;
+                    } else {
+                        self.state = State::BlockCode { index, received: 0 };
+                    }
+                    return Ok((used, Event::None));
+                }
+                State::BlockChecksum { index } => {
+                    let index = *index;
+                    let Some(checksum) = self.word.push(byte) else {
+                        continue;
+                    };
+                    let length = block_length(total, index) as usize;
+                    if checksum != block_checksum(index, &self.block[..length])
+                        || index > self.expected
+                    {
+                        self.resend()?;
+                        return Ok((used, Event::None));
+                    }
+                    self.out.push(PI_BLOCK_OK);
+                    self.out.push(index);
+                    self.state = State::Block {
+                        word: Word::default(),
+                    };
+                    // A block resent after its acknowledgement got lost.
+                    if index < self.expected {
+                        return Ok((used, Event::None));
+                    }
+
+                    if self.update && index == 0 && !is_bootloader(&self.block[..length]) {
+                        return Err(Error::NotBootloader);
+                    }
+                    self.expected += 1;
+                    self.retries = 0;
+                    if self.compressed_length.is_none() {
+                        self.digest.update(&self.block[..length]);
+                        if let Some(signer) = &mut self.signer {
+                            signer.update(&self.block[..length]);
+                        }
+                    }
+                    if self.expected == block_count(total) {
+                        self.finish_receive()?;
+                    }
+                    let code = Code {
+                        segments: (self.compressed_length.is_none() && !self.update)
+                            .then(|| self.regions()),
+                        staging: self.layout.staging,
+                        offset: index * BLOCK_SIZE,
+                        data: &self.block[..length],
+                    };
+                    return Ok((used, Event::Code(code)));
+                }
+                State::Success { word } => {
+                    if word.shift(byte) == INSTALLER_SUCCESS {
+                        self.state = State::Done;
+                        self.baud = DEFAULT_BAUD;
+                        if self.update {
+                            let (address, length) = (self.layout.staging, self.length);
+                            return Ok((used, Event::Update { address, length }));
+                        }
+                        let entry = self.entry;
+                        return Ok((used, Event::Done { entry }));
+                    }
+                }
+                State::BlockCode { .. } => unreachable!(),
+                // Nothing should arrive before answering.
+                State::Decompress => return Ok((input.len(), Event::None)),
+                State::Done => return Ok((input.len(), Event::None)),
+            }
+        }
+        Ok((input.len(), Event::None))
+    }
+
+    /// Check that `length` bytes at `address` lie in loadable memory.
+    fn check_bounds(&self, address: u32, length: u32) -> Result<(), Error> {
+        let end = address as u64 + length as u64;
+        if address < BASE || end >= self.layout.limit as u64 {
+            return Err(Error::OutOfBounds {
+                address,
+                length,
+                limit: self.layout.limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Find room for blobs without an address below [Layout::limit], check
+    /// them all, and add them after the segments.
+    fn place_blobs(&mut self) -> Result<(), Error> {
+        // Only ever lowered, and the last byte must be below the limit.
+        let mut top = self.layout.limit - 1;
+        for index in 0..self.blob_count {
+            let Blob {
+                mut address,
+                length,
+                ..
+            } = self.blobs[index];
+            if address == 0 {
+                address = top.saturating_sub(length) / BLOB_ALIGN * BLOB_ALIGN;
+                top = address;
+            }
+            self.check_bounds(address, length)?;
+            self.check_overlap(address, length, self.segment_count + index)?;
+            self.segments[self.segment_count + index] = Segment {
+                address,
+                length,
+                memory_length: length,
+            };
+            self.length += length;
+        }
+        Ok(())
+    }
+
+    /// Check that the blob of `length` bytes at `address` is clear of the
+    /// first `placed` regions and of the staging area.
+    fn check_overlap(&self, address: u32, length: u32, placed: usize) -> Result<(), Error> {
+        let staging = Segment {
+            address: self.layout.staging,
+            length: 0,
+            memory_length: self.layout.staging_limit - self.layout.staging,
+        };
+        let end = address as u64 + length as u64;
+        for region in self.segments[..placed].iter().chain([&staging]) {
+            let region_end = region.address as u64 + region.memory_length as u64;
+            if (address as u64) < region_end && end > region.address as u64 && length > 0 {
+                return Err(Error::Overlap {
+                    address,
+                    length,
+                    other: region.address,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that an update fits in the staging area and could be a
+    /// bootloader.
+    fn check_update(&self) -> Result<(), Error> {
+        let available = self.staging_room().min(MAX_BOOTLOADER_LENGTH);
+        if self.length > available {
+            return Err(Error::TooLarge {
+                length: self.length,
+                available,
+            });
+        }
+        // Too short to hold the header checked once the first block arrives.
+        if self.length < 8 {
+            return Err(Error::NotBootloader);
+        }
+        Ok(())
+    }
+
+    /// Go back to [DEFAULT_BAUD] and wait for the installer there.
+    fn fall_back(&mut self, now: Micros) {
+        self.baud = DEFAULT_BAUD;
+        self.state = State::ProgInfo {
+            word: Word::default(),
+        };
+        self.last = now;
+    }
+
+    /// Most bytes that fit in the staging area.
+    fn staging_room(&self) -> u32 {
+        self.layout.staging_limit - self.layout.staging - 1
+    }
+
+    /// Length of the code being received.
+    fn transfer_length(&self) -> u32 {
+        self.compressed_length.unwrap_or(self.length)
+    }
+
+    /// Ask for the next block again.
+    fn resend(&mut self) -> Result<(), Error> {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            return Err(Error::TooManyRetries {
+                block: self.expected,
+            });
+        }
+        self.out.push(PI_BLOCK_RESEND);
+        self.out.push(self.expected);
+        self.word = Word::default();
+        self.state = State::Block {
+            word: Word::default(),
+        };
+        Ok(())
+    }
+
+    fn start_code(&mut self) -> Result<(), Error> {
+        let blobs = &self.blobs[..self.blob_count];
+        self.signer = self
+            .key
+            .map(|key| Signer::new(&key, self.update, self.entry, self.segments(), blobs));
+        // Request code and have other side validate checksum.
+        self.out.push(PI_GET_CODE);
+        self.out.push(self.checksum);
+        self.state = State::Block {
+            word: Word::default(),
+        };
+        if self.transfer_length() == 0 {
+            self.finish_receive()?;
+        }
+        Ok(())
+    }
+
+    fn finish_receive(&mut self) -> Result<(), Error> {
+        if self.compressed_length.is_some() {
+            self.state = State::Decompress;
+            return Ok(());
+        }
+        self.finish_code()
+    }
+
+    /// Verify the checksum once all code has arrived.
+    fn finish_code(&mut self) -> Result<(), Error> {
+        let calculated = self.digest.clone().finalize();
+        if calculated != self.checksum {
+            return Err(Error::ChecksumMismatch {
+                expected: self.checksum,
+                got: calculated,
+            });
+        }
+        if let Some(signer) = self.signer.take() {
+            if !self
+                .signature
+                .is_some_and(|signature| signer.verify(&signature))
+            {
+                return Err(Error::BadSignature);
+            }
+        }
+        self.out.push(PI_SUCCESS);
+        self.retries = 0;
+        self.state = State::Success {
+            word: Word::default(),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+//! Calls from the host to functions the program registered, for
+//! hardware-in-the-loop tests.
+//!
+//! Both sides send [Rpc] messages in postcard, each in a frame on
+//! [Channel::Control]. Once the program serves calls it sends [Rpc::Ready],
+//! then answers each [Rpc::Call] with an [Rpc::Return] until it gets
+//! [Rpc::Done]. Arguments and results are postcard too, so a function taking
+//! `(u32, bool)` gets the encoding of such a tuple. Run a [Server] on the Pi.
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::channel::{self, Channel, Demux, MAX_CHANNEL_DATA, MAX_CHANNEL_FRAME};
+
+/// Most bytes of arguments or of a result, leaving room in the frame for the
+/// rest of the message.
+pub const MAX_RPC_DATA: usize = MAX_CHANNEL_DATA - 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rpc<'a> {
+    /// The program serves calls.
+    Ready,
+    /// Call the function called `name` with `args`.
+    Call {
+        id: u32,
+        name: &'a str,
+        args: &'a [u8],
+    },
+    /// What the function of call `id` returned.
+    Return {
+        id: u32,
+        #[serde(borrow)]
+        result: Result<&'a [u8], RpcError>,
+    },
+    /// The host is done calling, so the program carries on.
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// No function has the name called.
+    UnknownFunction,
+    /// The arguments don't decode as what the function takes.
+    BadArguments,
+    /// The result is longer than [MAX_RPC_DATA].
+    ResultTooLong,
+}
+
+impl core::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::UnknownFunction => "no function has that name",
+            Self::BadArguments => "the arguments don't match what the function takes",
+            Self::ResultTooLong => "the result is too long to send",
+        })
+    }
+}
+
+impl<'a> Rpc<'a> {
+    /// Encode the message in a frame into `buf`, returning the frame, or
+    /// `None` if it doesn't fit.
+    pub fn encode<'b>(&self, buf: &'b mut [u8; MAX_CHANNEL_FRAME]) -> Option<&'b [u8]> {
+        let mut payload = [0; MAX_CHANNEL_DATA];
+        let payload = postcard::to_slice(self, &mut payload).ok()?;
+        Some(channel::encode(Channel::Control, payload, buf))
+    }
+
+    /// Decode the data of a frame on [Channel::Control].
+    pub fn decode(data: &'a [u8]) -> Option<Self> {
+        postcard::from_bytes(data).ok()
+    }
+}
+
+/// The functions a [Server] calls.
+pub trait Functions {
+    /// Call the function called `name` with `args`, writing its result to
+    /// `result` and returning the length.
+    fn call(&mut self, name: &str, args: &[u8], result: &mut [u8]) -> Result<usize, RpcError>;
+}
+
+/// Call `f` with `args` decoded, encoding what it returns to `result` and
+/// returning the length.
+pub fn call_with<A: DeserializeOwned, R: Serialize>(
+    f: impl FnOnce(A) -> R,
+    args: &[u8],
+    result: &mut [u8],
+) -> Result<usize, RpcError> {
+    let args = postcard::from_bytes(args).map_err(|_| RpcError::BadArguments)?;
+    let result = postcard::to_slice(&f(args), result).map_err(|_| RpcError::ResultTooLong)?;
+    Ok(result.len())
+}
+
+/// The program's side of the calls: pass it the bytes from the host and send
+/// what [Server::transmit] returns.
+#[derive(Debug, Clone)]
+pub struct Server {
+    demux: Demux,
+    output: [u8; MAX_CHANNEL_FRAME],
+    output_length: usize,
+    serving: bool,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    /// Start serving, announcing it with [Rpc::Ready].
+    pub fn new() -> Self {
+        let mut server = Self {
+            demux: Demux::new(),
+            output: [0; MAX_CHANNEL_FRAME],
+            output_length: 0,
+            serving: true,
+        };
+        server.send(Rpc::Ready);
+        server
+    }
+
+    /// Bytes to send to the host, if any.
+    pub fn transmit(&mut self) -> Option<&[u8]> {
+        let length = core::mem::take(&mut self.output_length);
+        (length > 0).then_some(&self.output[..length])
+    }
+
+    /// Whether the host may still call, rather than being done.
+    pub fn is_serving(&self) -> bool {
+        self.serving
+    }
+
+    /// Add a byte from the host, calling `functions` when a call is complete.
+    pub fn receive(&mut self, byte: u8, functions: &mut impl Functions) {
+        let Some((Channel::Control, data)) = self.demux.push(byte) else {
+            return;
+        };
+        match Rpc::decode(data) {
+            Some(Rpc::Call { id, name, args }) => {
+                let mut result = [0; MAX_RPC_DATA];
+                let result = functions
+                    .call(name, args, &mut result)
+                    .map(|length| &result[..length]);
+                self.send(Rpc::Return { id, result });
+            }
+            Some(Rpc::Done) => self.serving = false,
+            _ => {}
+        }
+    }
+
+    fn send(&mut self, rpc: Rpc) {
+        // Fits, as results are at most MAX_RPC_DATA.
+        if let Some(frame) = rpc.encode(&mut self.output) {
+            self.output_length = frame.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Adds two numbers, or says hello.
+    struct Calculator;
+
+    impl Functions for Calculator {
+        fn call(&mut self, name: &str, args: &[u8], result: &mut [u8]) -> Result<usize, RpcError> {
+            match name {
+                "add" => call_with(|(a, b): (u32, u32)| a + b, args, result),
+                "hello" => call_with(|(): ()| "hello", args, result),
+                _ => Err(RpcError::UnknownFunction),
+            }
+        }
+    }
+
+    /// Send `rpc` to `server`, returning the message it sends back.
+    fn rpc_round_trip(server: &mut Server, rpc: Rpc) -> Option<Vec<u8>> {
+        for &byte in rpc.encode(&mut [0; MAX_CHANNEL_FRAME]).unwrap() {
+            server.receive(byte, &mut Calculator);
+        }
+        rpc_sent(server)
+    }
+
+    /// The data of the message `server` has to send, if any.
+    fn rpc_sent(server: &mut Server) -> Option<Vec<u8>> {
+        let frame = server.transmit()?.to_vec();
+        let mut demux = Demux::new();
+        let sent: Vec<_> = frame
+            .iter()
+            .filter_map(|&byte| {
+                demux
+                    .push(byte)
+                    .map(|(channel, data)| (channel, data.to_vec()))
+            })
+            .collect();
+        match sent.as_slice() {
+            [(Channel::Control, data)] => Some(data.clone()),
+            other => panic!("expected one control frame, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn calls_registered_functions() {
+        let mut server = Server::new();
+        let ready = rpc_sent(&mut server).unwrap();
+        assert_eq!(Rpc::decode(&ready), Some(Rpc::Ready));
+        assert_eq!(server.transmit(), None);
+
+        let args = postcard::to_allocvec(&(2u32, 40u32)).unwrap();
+        let reply = rpc_round_trip(
+            &mut server,
+            Rpc::Call {
+                id: 7,
+                name: "add",
+                args: &args,
+            },
+        )
+        .unwrap();
+        let Some(Rpc::Return {
+            id: 7,
+            result: Ok(result),
+        }) = Rpc::decode(&reply)
+        else {
+            panic!("expected a result, got {reply:?}");
+        };
+        assert_eq!(postcard::from_bytes::<u32>(result).unwrap(), 42);
+
+        let reply = rpc_round_trip(
+            &mut server,
+            Rpc::Call {
+                id: 8,
+                name: "hello",
+                args: &[],
+            },
+        )
+        .unwrap();
+        let Some(Rpc::Return {
+            id: 8,
+            result: Ok(result),
+        }) = Rpc::decode(&reply)
+        else {
+            panic!("expected a result, got {reply:?}");
+        };
+        assert_eq!(postcard::from_bytes::<&str>(result).unwrap(), "hello");
+
+        assert!(server.is_serving());
+        assert_eq!(rpc_round_trip(&mut server, Rpc::Done), None);
+        assert!(!server.is_serving());
+    }
+
+    #[test]
+    fn reports_failed_calls() {
+        let mut server = Server::new();
+        server.transmit();
+        for (id, name, args, error) in [
+            (1, "subtract", &[][..], RpcError::UnknownFunction),
+            (2, "add", &[1][..], RpcError::BadArguments),
+        ] {
+            let reply = rpc_round_trip(&mut server, Rpc::Call { id, name, args }).unwrap();
+            assert_eq!(
+                Rpc::decode(&reply),
+                Some(Rpc::Return {
+                    id,
+                    result: Err(error)
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn cuts_off_results_too_long_to_send() {
+        let mut result = [0; MAX_RPC_DATA];
+        let long = [1u8; MAX_RPC_DATA];
+        assert_eq!(
+            call_with(|(): ()| &long[..], &[], &mut result),
+            Err(RpcError::ResultTooLong)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Authenticates programs with HMAC-SHA256, so a bootloader built with a key
+//! only runs what was signed with the same key.
+//!
+//! The signature covers whether the image is a bootloader update, the entry
+//! point, the segments, the blobs and the uncompressed code, so none of them
+//! can be changed without the key. Keys are written as hexadecimal, as in a
+//! key file made with `openssl rand -hex 32`.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{boot_info::Blob, Segment, INSTALLER_PROG_INFO, INSTALLER_UPDATE_INFO};
+
+pub const KEY_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 32;
+
+pub type Signature = [u8; SIGNATURE_LENGTH];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key([u8; KEY_LENGTH]);
+
+impl Key {
+    pub const fn new(key: [u8; KEY_LENGTH]) -> Self {
+        Self(key)
+    }
+
+    /// Parse `2 * KEY_LENGTH` hex digits, ignoring surrounding whitespace.
+    pub const fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim_ascii().as_bytes();
+        if hex.len() != 2 * KEY_LENGTH {
+            return None;
+        }
+        let mut key = [0; KEY_LENGTH];
+        let mut i = 0;
+        while i < KEY_LENGTH {
+            let (Some(high), Some(low)) = (digit(hex[2 * i]), digit(hex[2 * i + 1])) else {
+                return None;
+            };
+            key[i] = high << 4 | low;
+            i += 1;
+        }
+        Some(Self(key))
+    }
+}
+
+// Keep the key out of logs.
+impl core::fmt::Debug for Key {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+const fn digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Signs or verifies a program as its code goes by.
+#[derive(Clone)]
+pub struct Signer(Hmac<Sha256>);
+
+impl Signer {
+    /// Starts with the program info. `blobs` are as sent, before the
+    /// bootloader places them.
+    pub fn new(key: &Key, update: bool, entry: u32, segments: &[Segment], blobs: &[Blob]) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.0).expect("HMAC takes any key length");
+        let kind = if update {
+            INSTALLER_UPDATE_INFO
+        } else {
+            INSTALLER_PROG_INFO
+        };
+        for word in [kind, entry, segments.len() as u32] {
+            mac.update(&word.to_le_bytes());
+        }
+        for segment in segments {
+            for word in [segment.address, segment.length, segment.memory_length] {
+                mac.update(&word.to_le_bytes());
+            }
+        }
+        mac.update(&(blobs.len() as u32).to_le_bytes());
+        for blob in blobs {
+            for word in [blob.address, blob.length] {
+                mac.update(&word.to_le_bytes());
+            }
+            mac.update(blob.name_bytes());
+        }
+        Self(mac)
+    }
+
+    /// Add the next part of the uncompressed code.
+    pub fn update(&mut self, code: &[u8]) {
+        self.0.update(code);
+    }
+
+    pub fn finalize(self) -> Signature {
+        self.0.finalize().into_bytes().into()
+    }
+
+    /// Whether `signature` matches, compared in constant time.
+    pub fn verify(self, signature: &Signature) -> bool {
+        self.0.verify_slice(signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_keys() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F\n";
+        let expected = Key::new(core::array::from_fn(|i| i as u8));
+        assert_eq!(Key::from_hex(hex), Some(expected));
+        assert_eq!(Key::from_hex(&hex[..62]), None);
+        assert_eq!(Key::from_hex(&hex.replace('a', "g")), None);
+    }
+}
@@ -0,0 +1,1202 @@
+//! Runs both sides of the protocol against each other over a simulated wire.
+use std::{collections::VecDeque, vec, vec::Vec};
+
+use crate::{
+    baud_divisor,
+    boot_info::{Blob, BootInfo, BLOB_ALIGN, MAX_BLOBS},
+    installer::{self, Image, ImageError, Installer},
+    pi::{self, Bootloader, Layout},
+    sign::Key,
+    Error, Micros, Report, Segment, BASE, BLOCK_SIZE, BOOTLOADER_RESIDENT, DEFAULT_BAUD,
+    DEFAULT_CORE_CLOCK, MAX_SEGMENTS, PI_ERROR,
+};
+
+/// Simulated time per step, about a byte at 921600 baud.
+const STEP: Micros = 10;
+/// Bytes of program info for a flat binary.
+const PROG_INFO: usize = 4 * 7;
+const LAYOUT: Layout = Layout {
+    limit: 0x200000,
+    staging: 0x200000,
+    staging_limit: 0x300000,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ToPi,
+    ToInstaller,
+}
+
+#[derive(Debug)]
+struct Outcome {
+    pi: Result<(), Error>,
+    installer: Result<(), Error>,
+    /// Memory from [BASE] to the end of the staging area.
+    memory: Vec<u8>,
+    entry: Option<u32>,
+    /// Address and length of a received bootloader update.
+    update: Option<(u32, u32)>,
+    /// What the bootloader would leave for the program.
+    boot_info: Option<BootInfo>,
+    /// Bytes the installer received after it was done.
+    console: Vec<u8>,
+    /// Bytes sent to the Pi faster than [DEFAULT_BAUD].
+    fast: usize,
+}
+
+/// Run both sides until they finish or fail. `fault` sees every byte on the
+/// wire along with its index in that direction, and returns the bytes to
+/// deliver instead.
+fn run(
+    image: Image,
+    layout: Layout,
+    fault: impl FnMut(Direction, usize, u8) -> Vec<u8>,
+) -> Outcome {
+    simulate(
+        Bootloader::new(layout, 0),
+        Installer::new(image, 0),
+        layout,
+        false,
+        DEFAULT_BAUD,
+        fault,
+    )
+}
+
+/// Like [run], with a bootloader set up by the caller. With `report_errors`,
+/// the Pi sends `PI_ERROR` and an [Error::report] when it fails, as the
+/// bootloader does.
+///
+/// Each side switches its UART to its new baud rate after sending what it
+/// transmitted. Bytes arrive garbled when the receiver runs at another rate
+/// than the sender did, or when the sender ran faster than `max_baud`.
+fn simulate(
+    mut pi: Bootloader,
+    mut installer: Installer,
+    layout: Layout,
+    report_errors: bool,
+    max_baud: u32,
+    mut fault: impl FnMut(Direction, usize, u8) -> Vec<u8>,
+) -> Outcome {
+    let mut now: Micros = 0;
+    let mut pi_result = None;
+    let mut installer_result = None;
+    let mut to_pi = VecDeque::new();
+    let mut to_installer = VecDeque::new();
+    let mut sent = [0, 0];
+    let mut memory = vec![0xAA; (layout.staging_limit - BASE) as usize];
+    let mut entry = None;
+    let mut update = None;
+    let mut boot_info = None;
+    let mut console = Vec::new();
+    let mut fast = 0;
+    let (mut pi_baud, mut installer_baud) = (DEFAULT_BAUD, DEFAULT_BAUD);
+
+    let mut send = |direction, baud: u32, bytes: &[u8], queue: &mut VecDeque<(u8, u32)>| {
+        for &byte in bytes {
+            let count = &mut sent[direction as usize];
+            queue.extend(
+                fault(direction, *count, byte)
+                    .into_iter()
+                    .map(|b| (b, baud)),
+            );
+            *count += 1;
+            if direction == Direction::ToPi && baud > DEFAULT_BAUD {
+                fast += 1;
+            }
+        }
+    };
+    let arrive = |(byte, baud): (u8, u32), receiver: u32| {
+        if baud == receiver && baud <= max_baud {
+            byte
+        } else {
+            byte ^ 0xA5
+        }
+    };
+
+    let installer_done = |r: &Option<Result<(), Error>>| matches!(r, Some(Ok(())));
+    while (pi_result.is_none()
+        || installer_result.is_none()
+        || (installer_done(&installer_result) && !to_installer.is_empty()))
+        && now < 60_000_000
+    {
+        now += STEP;
+        if pi_result.is_none() {
+            let result = (|| -> Result<(), Error> {
+                pi.poll(now)?;
+                if let Some(byte) = to_pi.pop_front() {
+                    let input = [arrive(byte, pi_baud)];
+                    let mut input = &input[..];
+                    while !input.is_empty() {
+                        let (used, event) = pi.receive(input, now)?;
+                        match event {
+                            pi::Event::Code(code) => place(&mut memory, code),
+                            pi::Event::Done { entry: start } => {
+                                for segment in pi.segments() {
+                                    let (address, length) = segment.bss();
+                                    let offset = (address - BASE) as usize;
+                                    memory[offset..offset + length as usize].fill(0);
+                                }
+                                entry = Some(start);
+                                boot_info = Some(pi.boot_info());
+                            }
+                            pi::Event::Update { address, length } => {
+                                update = Some((address, length));
+                            }
+                            pi::Event::None => {}
+                        }
+                        input = &input[used..];
+                    }
+                }
+                if let Some(decompress) = pi.decompress() {
+                    let start = (decompress.address - BASE) as usize;
+                    let compressed =
+                        memory[start..start + decompress.compressed_length as usize].to_vec();
+                    let start = (decompress.output - BASE) as usize;
+                    let mut code = memory[start..start + decompress.length as usize].to_vec();
+                    lz4_flex::block::decompress_into(&compressed, &mut code)
+                        .map_err(|_| Error::Decompress)?;
+                    let code = pi.decompressed(&code, now)?;
+                    place(&mut memory, code);
+                }
+                while let Some(bytes) = pi.transmit() {
+                    send(Direction::ToInstaller, pi_baud, bytes, &mut to_installer);
+                }
+                pi_baud = pi.baud();
+                Ok(())
+            })();
+            match result {
+                Err(e) => {
+                    if report_errors {
+                        let baud = pi_baud;
+                        send(
+                            Direction::ToInstaller,
+                            baud,
+                            &PI_ERROR.to_le_bytes(),
+                            &mut to_installer,
+                        );
+                        for word in e.report().words() {
+                            send(
+                                Direction::ToInstaller,
+                                baud,
+                                &word.to_le_bytes(),
+                                &mut to_installer,
+                            );
+                        }
+                    }
+                    pi_result = Some(Err(e));
+                }
+                Ok(()) if pi.is_done() => pi_result = Some(Ok(())),
+                Ok(()) => {}
+            }
+        }
+
+        if installer_done(&installer_result) {
+            console.extend(
+                to_installer
+                    .pop_front()
+                    .map(|byte| arrive(byte, installer_baud)),
+            );
+        } else if installer_result.is_none() {
+            let result = (|| {
+                installer.poll(now)?;
+                if let Some(byte) = to_installer.pop_front() {
+                    let byte = arrive(byte, installer_baud);
+                    let (used, event) = installer.receive(&[byte], now)?;
+                    if used == 0 {
+                        assert_eq!(event, installer::Event::None);
+                        console.push(byte);
+                    }
+                }
+                while let Some(bytes) = installer.transmit() {
+                    send(Direction::ToPi, installer_baud, bytes, &mut to_pi);
+                }
+                installer_baud = installer.baud();
+                Ok(())
+            })();
+            match result {
+                Err(e) => installer_result = Some(Err(e)),
+                Ok(()) if installer.is_done() => installer_result = Some(Ok(())),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    Outcome {
+        pi: pi_result.unwrap_or(Err(Error::Timeout)),
+        installer: installer_result.unwrap_or(Err(Error::Timeout)),
+        memory,
+        entry,
+        update,
+        boot_info,
+        console,
+        fast,
+    }
+}
+
+fn place(memory: &mut [u8], code: pi::Code) {
+    for (address, data) in code {
+        let offset = (address - BASE) as usize;
+        memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Outcome {
+    fn at(&self, address: u32, length: usize) -> &[u8] {
+        let offset = (address - BASE) as usize;
+        &self.memory[offset..offset + length]
+    }
+}
+
+/// Test data of `len` bytes, also used by the other modules' tests.
+pub(crate) fn program(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+fn clean(_: Direction, _: usize, byte: u8) -> Vec<u8> {
+    vec![byte]
+}
+
+#[test]
+fn loads_program() {
+    let program = program(5000);
+    let outcome = run(Image::raw(&program), LAYOUT, clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn loads_empty_program() {
+    let outcome = run(Image::raw(&[]), LAYOUT, clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+}
+
+#[test]
+fn ignores_garbage_before_handshake() {
+    let program = program(300);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if index == 0 {
+            // Leftover output from a previous run, and line noise.
+            match direction {
+                Direction::ToInstaller => b"DONE!!!\n\xEE\xFF\x00"
+                    .iter()
+                    .chain([&byte])
+                    .copied()
+                    .collect(),
+                Direction::ToPi => vec![0xAD, 0xBE, 0x13, byte],
+            }
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn recovers_from_dropped_request() {
+    let program = program(300);
+    // Drop part of the first program info request, the next one gets through.
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToInstaller && index == 1 {
+            vec![]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn recovers_from_dropped_code_byte() {
+    let program = program(300);
+    // Program info and 8 bytes of block header, then the code.
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToPi && index == PROG_INFO + 8 + 100 {
+            vec![]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn recovers_from_corrupted_code() {
+    let program = program(300);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToPi && index == PROG_INFO + 8 + 100 {
+            vec![!byte]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn recovers_from_corrupted_block_checksum() {
+    let program = program(5000);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToPi && index == PROG_INFO + 8 + BLOCK_SIZE as usize + 1 {
+            vec![!byte]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn recovers_from_lost_block_ack() {
+    let program = program(5000);
+    // 4 bytes of program info request and 8 of GET_CODE, then the first ack.
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToInstaller && index == 4 + 8 + 1 {
+            vec![]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn recovers_from_lost_final_block_ack() {
+    let program = program(300);
+    // The only block's ack follows the program info request and GET_CODE,
+    // losing a byte of PI_BLOCK_OK or of the block index.
+    for lost in [4 + 8 + 1, 4 + 8 + 4 + 1] {
+        let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+            if direction == Direction::ToInstaller && index == lost {
+                vec![]
+            } else {
+                vec![byte]
+            }
+        });
+        assert_eq!(outcome.pi, Ok(()), "lost byte {lost}");
+        assert_eq!(outcome.installer, Ok(()), "lost byte {lost}");
+        assert_eq!(outcome.at(BASE, program.len()), program);
+    }
+}
+
+#[test]
+fn recovers_from_noisy_line() {
+    let program = program(20_000);
+    let outcome = run(
+        Image::raw(&program),
+        LAYOUT,
+        |direction, index, byte| match direction {
+            Direction::ToPi if index % 5000 == 700 => vec![byte ^ 0x04],
+            Direction::ToPi if index % 7000 == 3900 => vec![],
+            Direction::ToPi if index % 9000 == 6100 => vec![byte, byte],
+            _ => vec![byte],
+        },
+    );
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn gives_up_on_persistently_corrupted_block() {
+    let program = program(5000);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToPi && index >= PROG_INFO + 8 {
+            vec![!byte]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Err(Error::TooManyRetries { block: 0 }));
+}
+
+#[test]
+fn gives_up_when_installer_disappears() {
+    let program = program(5000);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToPi && index >= PROG_INFO + 8 + BLOCK_SIZE as usize + 4 + 500 {
+            vec![]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Err(Error::TooManyRetries { block: 1 }));
+    assert_eq!(outcome.installer, Err(Error::TooManyRetries { block: 1 }));
+}
+
+#[test]
+fn rejects_corrupted_checksum_echo() {
+    let program = program(300);
+    // 4 bytes of program info request, then GET_CODE and the checksum.
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        if direction == Direction::ToInstaller && index == 4 + 4 + 2 {
+            vec![!byte]
+        } else {
+            vec![byte]
+        }
+    });
+    let Err(Error::ChecksumMismatch { expected, .. }) = outcome.installer else {
+        panic!("{:?}", outcome.installer);
+    };
+    assert_eq!(expected, Image::raw(&program).checksum());
+}
+
+#[test]
+fn rejects_too_large() {
+    let program = program(300);
+    let layout = Layout {
+        limit: BASE + 300,
+        ..LAYOUT
+    };
+    let outcome = run(Image::raw(&program), layout, clean);
+    assert_eq!(
+        outcome.pi,
+        Err(Error::OutOfBounds {
+            address: BASE,
+            length: 300,
+            limit: BASE + 300,
+        })
+    );
+    assert_eq!(outcome.installer, Err(Error::Timeout));
+}
+
+#[test]
+fn times_out_on_missing_final_ack() {
+    let program = program(300);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        // Program info, then the block header, code and checksum.
+        if direction == Direction::ToPi && index >= PROG_INFO + 8 + 300 + 4 {
+            vec![]
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.pi, Err(Error::MissingAck));
+    assert_eq!(outcome.installer, Ok(()));
+}
+
+#[test]
+fn keeps_asking_without_installer() {
+    let mut now = 0;
+    let mut pi = Bootloader::new(LAYOUT, now);
+    let mut requests = 0;
+    while now < 10 * pi::PROG_INFO_INTERVAL {
+        now += STEP;
+        pi.poll(now).unwrap();
+        if pi.transmit().is_some() {
+            requests += 1;
+        }
+    }
+    assert_eq!(requests, 11);
+}
+
+#[test]
+fn installer_times_out_without_pi() {
+    let program = program(300);
+    let mut installer = Installer::new(Image::raw(&program), 0);
+    assert_eq!(installer.poll(installer::STEP_TIMEOUT), Ok(()));
+    assert_eq!(
+        installer.poll(installer::STEP_TIMEOUT + 1),
+        Err(Error::Timeout)
+    );
+}
+
+#[test]
+fn passes_program_output_through() {
+    let program = program(300);
+    let outcome = run(Image::raw(&program), LAYOUT, |direction, index, byte| {
+        // Output of the loaded program right behind the block ack and
+        // PI_SUCCESS.
+        if direction == Direction::ToInstaller && index == 4 + 8 + 8 + 3 {
+            [byte].iter().chain(b"hello").copied().collect()
+        } else {
+            vec![byte]
+        }
+    });
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.console, b"hello");
+}
+
+#[test]
+fn loads_compressed_program() {
+    let program = program(5000);
+    let compressed = lz4_flex::block::compress(&program);
+    let outcome = run(
+        Image::raw(&program).lz4(&compressed).unwrap(),
+        LAYOUT,
+        clean,
+    );
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn rejects_malformed_compressed_program() {
+    let program = program(5000);
+    let mut compressed = lz4_flex::block::compress(&program);
+    compressed[40] ^= 0x10;
+    let outcome = run(
+        Image::raw(&program).lz4(&compressed).unwrap(),
+        LAYOUT,
+        clean,
+    );
+    assert!(matches!(
+        outcome.pi,
+        Err(Error::Decompress | Error::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn rejects_compressed_program_too_large_to_stage() {
+    let program = program(5000);
+    let compressed = lz4_flex::block::compress(&program);
+    let layout = Layout {
+        staging_limit: LAYOUT.staging + 100,
+        ..LAYOUT
+    };
+    let outcome = run(
+        Image::raw(&program).lz4(&compressed).unwrap(),
+        layout,
+        clean,
+    );
+    assert_eq!(
+        outcome.pi,
+        Err(Error::TooLarge {
+            length: compressed.len() as u32 + 5000,
+            available: 99,
+        })
+    );
+}
+
+#[test]
+fn reports_errors_to_installer() {
+    let program = program(300);
+    let layout = Layout {
+        limit: BASE + 300,
+        ..LAYOUT
+    };
+    let pi = Bootloader::new(layout, 0);
+    let outcome = simulate(
+        pi,
+        Installer::new(Image::raw(&program), 0),
+        layout,
+        true,
+        DEFAULT_BAUD,
+        clean,
+    );
+    let error = Error::OutOfBounds {
+        address: BASE,
+        length: 300,
+        limit: BASE + 300,
+    };
+    assert_eq!(outcome.pi, Err(error));
+    let Err(Error::Pi(report)) = outcome.installer else {
+        panic!("{:?}", outcome.installer);
+    };
+    assert_eq!(report.error(), Some(error));
+}
+
+#[test]
+fn reports_errors_while_sending_code() {
+    let program = program(5000);
+    let outcome = simulate(
+        Bootloader::new(LAYOUT, 0),
+        Installer::new(Image::raw(&program), 0),
+        LAYOUT,
+        true,
+        DEFAULT_BAUD,
+        |direction, index, byte| match direction {
+            Direction::ToPi if index >= PROG_INFO + 8 => vec![!byte],
+            _ => vec![byte],
+        },
+    );
+    assert_eq!(outcome.pi, Err(Error::TooManyRetries { block: 0 }));
+    assert_eq!(
+        outcome.installer,
+        Err(Error::Pi(Error::TooManyRetries { block: 0 }.report()))
+    );
+}
+
+#[test]
+fn round_trips_error_reports() {
+    let errors = [
+        Error::Timeout,
+        Error::TooLarge {
+            length: 5,
+            available: 4,
+        },
+        Error::OutOfBounds {
+            address: 1,
+            length: 2,
+            limit: 3,
+        },
+        Error::TooManySegments { count: 9 },
+        Error::Unexpected {
+            expected: 1,
+            got: 2,
+        },
+        Error::ChecksumMismatch {
+            expected: 3,
+            got: 4,
+        },
+        Error::Decompress,
+        Error::TooManyRetries { block: 7 },
+        Error::NotBootloader,
+        Error::BadSignature,
+        Error::MissingAck,
+        Error::TooManyBlobs { count: 9 },
+        Error::Overlap {
+            address: 5,
+            length: 6,
+            other: 7,
+        },
+    ];
+    for error in errors {
+        let report = Report::from_words(error.report().words());
+        assert_eq!(report.error(), Some(error));
+    }
+    assert_eq!(Report::from_words([0xEEEEFFFF, 0, 0, 0]).error(), None);
+}
+
+const FAST_BAUD: u32 = 2_000_000;
+
+/// Propose [FAST_BAUD] over a line that carries up to `max_baud`.
+fn run_fast(image: Image, max_baud: u32) -> Outcome {
+    simulate(
+        Bootloader::new(LAYOUT, 0),
+        Installer::new(image, 0).with_baud(FAST_BAUD),
+        LAYOUT,
+        true,
+        max_baud,
+        clean,
+    )
+}
+
+#[test]
+fn loads_at_negotiated_baud() {
+    let program = program(5000);
+    let outcome = run_fast(Image::raw(&program), FAST_BAUD);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, 5000), &program[..]);
+    assert!(outcome.fast > 5000, "{}", outcome.fast);
+}
+
+#[test]
+fn falls_back_when_baud_fails() {
+    let program = program(5000);
+    let outcome = run_fast(Image::raw(&program), DEFAULT_BAUD);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, 5000), &program[..]);
+    // Only the garbled check went out fast.
+    assert!(outcome.fast < 100, "{}", outcome.fast);
+}
+
+#[test]
+fn refuses_baud_the_uart_cant_run_at() {
+    let program = program(5000);
+    let outcome = simulate(
+        Bootloader::new(LAYOUT, 0),
+        Installer::new(Image::raw(&program), 0).with_baud(100_000_000),
+        LAYOUT,
+        true,
+        u32::MAX,
+        clean,
+    );
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, 5000), &program[..]);
+    assert_eq!(outcome.fast, 0);
+}
+
+#[test]
+fn computes_baud_divisors() {
+    assert_eq!(baud_divisor(DEFAULT_CORE_CLOCK, DEFAULT_BAUD), Some(32));
+    assert_eq!(baud_divisor(DEFAULT_CORE_CLOCK, 9600), Some(3254));
+    assert_eq!(baud_divisor(DEFAULT_CORE_CLOCK, 0), None);
+    // Too fast for the register to be above 0.
+    assert_eq!(baud_divisor(DEFAULT_CORE_CLOCK, 20_000_000), None);
+    // Too slow for the register.
+    assert_eq!(baud_divisor(DEFAULT_CORE_CLOCK, 300), None);
+}
+
+#[test]
+fn returns_to_default_baud_when_done() {
+    let program = program(100);
+    let mut installer = Installer::new(Image::raw(&program), 0).with_baud(FAST_BAUD);
+    let mut pi = Bootloader::new(LAYOUT, 0);
+    let mut now = 0;
+    let mut switched = false;
+    while !installer.is_done() {
+        now += STEP;
+        pi.poll(now).unwrap();
+        installer.poll(now).unwrap();
+        while let Some(bytes) = pi.transmit() {
+            let bytes = bytes.to_vec();
+            let mut input = &bytes[..];
+            while !input.is_empty() {
+                input = &input[installer.receive(input, now).unwrap().0..];
+            }
+        }
+        while let Some(bytes) = installer.transmit() {
+            let bytes = bytes.to_vec();
+            let mut input = &bytes[..];
+            while !input.is_empty() {
+                input = &input[pi.receive(input, now).unwrap().0..];
+            }
+        }
+        switched |= installer.baud() == FAST_BAUD && pi.baud() == FAST_BAUD;
+    }
+    assert!(switched);
+    assert!(pi.is_done());
+    assert_eq!(pi.baud(), DEFAULT_BAUD);
+    assert_eq!(installer.baud(), DEFAULT_BAUD);
+}
+
+/// Two segments with a gap between them, the second with BSS.
+fn segments() -> [Segment; 2] {
+    [
+        Segment {
+            address: BASE,
+            length: 100,
+            memory_length: 100,
+        },
+        Segment {
+            address: BASE + 0x10000,
+            length: 3000,
+            memory_length: 4000,
+        },
+    ]
+}
+
+fn check_segments(outcome: &Outcome, code: &[u8]) {
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.entry, Some(BASE + 0x10004));
+    assert_eq!(outcome.at(BASE, 100), &code[..100]);
+    assert_eq!(outcome.at(BASE + 0x10000, 3000), &code[100..]);
+    assert!(outcome
+        .at(BASE + 0x10000 + 3000, 1000)
+        .iter()
+        .all(|&b| b == 0));
+    // The gap is left alone.
+    assert!(outcome.at(BASE + 100, 100).iter().all(|&b| b == 0xAA));
+}
+
+#[test]
+fn loads_segments() {
+    let code = program(3100);
+    let image = Image::new(BASE + 0x10004, &segments(), &code).unwrap();
+    let outcome = run(image, LAYOUT, clean);
+    check_segments(&outcome, &code);
+}
+
+#[test]
+fn loads_compressed_segments() {
+    let code = program(3100);
+    let compressed = lz4_flex::block::compress(&code);
+    let image = Image::new(BASE + 0x10004, &segments(), &code).unwrap();
+    let outcome = run(image.lz4(&compressed).unwrap(), LAYOUT, clean);
+    check_segments(&outcome, &code);
+}
+
+#[test]
+fn rejects_segment_out_of_bounds() {
+    let code = program(100);
+    let segment = Segment {
+        address: BASE - 4,
+        length: 100,
+        memory_length: 100,
+    };
+    let image = Image::new(BASE, &[segment], &code).unwrap();
+    let outcome = run(image, LAYOUT, clean);
+    assert_eq!(
+        outcome.pi,
+        Err(Error::OutOfBounds {
+            address: BASE - 4,
+            length: 100,
+            limit: LAYOUT.limit,
+        })
+    );
+}
+
+#[test]
+fn rejects_too_many_segments() {
+    let segments = [Segment::default(); MAX_SEGMENTS + 1];
+    assert!(matches!(
+        Image::new(BASE, &segments, &[]),
+        Err(ImageError::TooManySegments { .. })
+    ));
+}
+
+#[test]
+fn rejects_images_put_together_wrong() {
+    let code = program(200);
+    let segment = Segment {
+        address: BASE,
+        length: 100,
+        memory_length: 100,
+    };
+    assert_eq!(
+        Image::new(BASE, &[segment], &code).err(),
+        Some(ImageError::WrongLength {
+            expected: 100,
+            got: 200
+        })
+    );
+    let blobs = [Blob::new("blob", None, 50).unwrap()];
+    assert_eq!(
+        Image::raw(&code[..100]).with_blobs(&blobs, &code).err(),
+        Some(ImageError::WrongLength {
+            expected: 150,
+            got: 200
+        })
+    );
+    let compressed = Image::raw(&code).lz4(&code).unwrap();
+    assert_eq!(
+        compressed.update().err(),
+        Some(ImageError::CompressedUpdate)
+    );
+    assert_eq!(
+        compressed.sign(&KEY).err(),
+        Some(ImageError::SignedCompressed)
+    );
+    assert_eq!(
+        compressed.with_blobs(&blobs, &code).err(),
+        Some(ImageError::BlobsTooLate)
+    );
+    let update = Image::raw(&code).update().unwrap();
+    assert_eq!(update.lz4(&code).err(), Some(ImageError::CompressedUpdate));
+    assert_eq!(
+        update.with_blobs(&blobs, &code).err(),
+        Some(ImageError::BlobsTooLate)
+    );
+}
+
+/// A program with the header of a resident bootloader.
+pub(crate) fn bootloader(len: usize) -> Vec<u8> {
+    let mut bootloader = program(len);
+    bootloader[4..8].copy_from_slice(&BOOTLOADER_RESIDENT.to_le_bytes());
+    bootloader
+}
+
+#[test]
+fn stages_bootloader_update() {
+    let bootloader = bootloader(5000);
+    let outcome = run(Image::raw(&bootloader).update().unwrap(), LAYOUT, clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.entry, None);
+    assert_eq!(outcome.update, Some((LAYOUT.staging, 5000)));
+    assert_eq!(outcome.at(LAYOUT.staging, bootloader.len()), bootloader);
+    // Nothing is placed where programs run.
+    assert!(outcome.at(BASE, 5000).iter().all(|&b| b == 0xAA));
+}
+
+#[test]
+fn rejects_update_that_is_not_a_bootloader() {
+    let program = program(5000);
+    let outcome = run(Image::raw(&program).update().unwrap(), LAYOUT, clean);
+    assert_eq!(outcome.pi, Err(Error::NotBootloader));
+    assert_eq!(outcome.update, None);
+
+    let outcome = run(Image::raw(&[]).update().unwrap(), LAYOUT, clean);
+    assert_eq!(outcome.pi, Err(Error::NotBootloader));
+}
+
+#[test]
+fn rejects_update_too_large_to_stage() {
+    let bootloader = bootloader(5000);
+    let layout = Layout {
+        staging_limit: LAYOUT.staging + 100,
+        ..LAYOUT
+    };
+    let outcome = run(Image::raw(&bootloader).update().unwrap(), layout, clean);
+    assert_eq!(
+        outcome.pi,
+        Err(Error::TooLarge {
+            length: 5000,
+            available: 99
+        })
+    );
+}
+
+const KEY: Key = Key::new([7; 32]);
+
+/// Run against a bootloader with [KEY].
+fn run_signed(image: Image, fault: impl FnMut(Direction, usize, u8) -> Vec<u8>) -> Outcome {
+    let pi = Bootloader::new(LAYOUT, 0).with_key(KEY);
+    simulate(
+        pi,
+        Installer::new(image, 0),
+        LAYOUT,
+        true,
+        DEFAULT_BAUD,
+        fault,
+    )
+}
+
+#[test]
+fn loads_signed_program() {
+    let program = program(5000);
+    let outcome = run_signed(Image::raw(&program).sign(&KEY).unwrap(), clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn loads_signed_compressed_segments() {
+    let code = program(3100);
+    let compressed = lz4_flex::block::compress(&code);
+    let image = Image::new(BASE + 0x10004, &segments(), &code).unwrap();
+    let outcome = run_signed(image.sign(&KEY).unwrap().lz4(&compressed).unwrap(), clean);
+    check_segments(&outcome, &code);
+}
+
+#[test]
+fn stages_signed_bootloader_update() {
+    let bootloader = bootloader(5000);
+    let outcome = run_signed(
+        Image::raw(&bootloader)
+            .update()
+            .unwrap()
+            .sign(&KEY)
+            .unwrap(),
+        clean,
+    );
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.update, Some((LAYOUT.staging, 5000)));
+}
+
+#[test]
+fn ignores_signature_without_key() {
+    let program = program(5000);
+    let outcome = run(Image::raw(&program).sign(&KEY).unwrap(), LAYOUT, clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.at(BASE, program.len()), program);
+}
+
+#[test]
+fn rejects_unsigned_program() {
+    let outcome = run_signed(Image::raw(&program(5000)), clean);
+    assert_eq!(outcome.pi, Err(Error::BadSignature));
+    assert_eq!(
+        outcome.installer,
+        Err(Error::Pi(Error::BadSignature.report()))
+    );
+}
+
+#[test]
+fn rejects_program_signed_with_other_key() {
+    let program = program(5000);
+    let outcome = run_signed(
+        Image::raw(&program).sign(&Key::new([8; 32])).unwrap(),
+        clean,
+    );
+    assert_eq!(outcome.pi, Err(Error::BadSignature));
+    assert_eq!(
+        outcome.installer,
+        Err(Error::Pi(Error::BadSignature.report()))
+    );
+    assert_eq!(outcome.entry, None);
+}
+
+#[test]
+fn rejects_signed_program_with_changed_entry() {
+    let code = program(3100);
+    let image = Image::new(BASE + 0x10004, &segments(), &code).unwrap();
+    // The low byte of the entry point, after the signature and the kind.
+    let entry = 4 + 32 + 4;
+    let outcome = run_signed(
+        image.sign(&KEY).unwrap(),
+        |direction, index, byte| match direction {
+            Direction::ToPi if index == entry => vec![0],
+            _ => vec![byte],
+        },
+    );
+    assert_eq!(outcome.pi, Err(Error::BadSignature));
+}
+
+/// A 3000 byte program followed by a blob at a fixed address and one placed
+/// by the bootloader.
+fn program_with_blobs() -> (Vec<u8>, [Blob; 2]) {
+    let code = program(3000 + 500 + 2000);
+    let blobs = [
+        Blob::new("table", Some(BASE + 0x10000), 500).unwrap(),
+        Blob::new("font", None, 2000).unwrap(),
+    ];
+    (code, blobs)
+}
+
+#[test]
+fn loads_blobs() {
+    let (code, blobs) = program_with_blobs();
+    let image = Image::raw(&code[..3000]).with_blobs(&blobs, &code).unwrap();
+    let outcome = run(image, LAYOUT, clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE, 3000), &code[..3000]);
+    assert_eq!(outcome.at(BASE + 0x10000, 500), &code[3000..3500]);
+    let placed = (LAYOUT.limit - 1 - 2000) / BLOB_ALIGN * BLOB_ALIGN;
+    assert_eq!(outcome.at(placed, 2000), &code[3500..]);
+
+    let info = outcome.boot_info.unwrap();
+    assert!(info.is_valid());
+    assert_eq!(info.blobs().len(), 2);
+    let table = info.blob("table").unwrap();
+    assert_eq!((table.address, table.length), (BASE + 0x10000, 500));
+    let font = info.blob("font").unwrap();
+    assert_eq!((font.address, font.length), (placed, 2000));
+    assert_eq!(info.blob("missing"), None);
+}
+
+#[test]
+fn loads_signed_compressed_blobs() {
+    let (code, blobs) = program_with_blobs();
+    let compressed = lz4_flex::block::compress(&code);
+    let image = Image::raw(&code[..3000])
+        .with_blobs(&blobs, &code)
+        .unwrap()
+        .sign(&KEY)
+        .unwrap()
+        .lz4(&compressed)
+        .unwrap();
+    let outcome = run_signed(image, clean);
+    assert_eq!(outcome.pi, Ok(()));
+    assert_eq!(outcome.installer, Ok(()));
+    assert_eq!(outcome.at(BASE + 0x10000, 500), &code[3000..3500]);
+    let info = outcome.boot_info.unwrap();
+    assert_eq!(info.blobs().len(), 2);
+    assert_eq!(info.blob("table"), Some(&blobs[0]));
+}
+
+#[test]
+fn rejects_signed_program_with_renamed_blob() {
+    let (code, blobs) = program_with_blobs();
+    let image = Image::raw(&code[..3000]).with_blobs(&blobs, &code).unwrap();
+    // The first byte of the first name, after the signature, the blob count,
+    // address and length.
+    let name = 4 + 32 + 4 + 4 + 4 + 4;
+    let outcome = run_signed(
+        image.sign(&KEY).unwrap(),
+        |direction, index, byte| match direction {
+            Direction::ToPi if index == name => vec![b'T'],
+            _ => vec![byte],
+        },
+    );
+    assert_eq!(outcome.pi, Err(Error::BadSignature));
+}
+
+#[test]
+fn rejects_blob_out_of_bounds() {
+    let code = program(200);
+    let blobs = [Blob::new("low", Some(0x1000), 100).unwrap()];
+    let image = Image::raw(&code[..100]).with_blobs(&blobs, &code).unwrap();
+    let outcome = run(image, LAYOUT, clean);
+    assert_eq!(
+        outcome.pi,
+        Err(Error::OutOfBounds {
+            address: 0x1000,
+            length: 100,
+            limit: LAYOUT.limit
+        })
+    );
+}
+
+/// What the Pi makes of a 100 byte program at [BASE] sent with `blobs`.
+fn place_blobs(blobs: &[Blob], layout: Layout) -> Result<(), Error> {
+    let length = 100 + blobs.iter().map(|blob| blob.length as usize).sum::<usize>();
+    let code = program(length);
+    let image = Image::raw(&code[..100]).with_blobs(blobs, &code).unwrap();
+    run(image, layout, clean).pi
+}
+
+#[test]
+fn rejects_overlapping_blobs() {
+    let over_code = [Blob::new("code", Some(BASE + 50), 100).unwrap()];
+    assert_eq!(
+        place_blobs(&over_code, LAYOUT),
+        Err(Error::Overlap {
+            address: BASE + 50,
+            length: 100,
+            other: BASE
+        })
+    );
+    let over_blob = [
+        Blob::new("first", Some(BASE + 0x1000), 100).unwrap(),
+        Blob::new("second", Some(BASE + 0x1010), 100).unwrap(),
+    ];
+    assert_eq!(
+        place_blobs(&over_blob, LAYOUT),
+        Err(Error::Overlap {
+            address: BASE + 0x1010,
+            length: 100,
+            other: BASE + 0x1000
+        })
+    );
+    // Placed in the last page below the limit, which the first blob covers.
+    let top = LAYOUT.limit - 0x1800;
+    let over_placed = [
+        Blob::new("fixed", Some(top), 0x1000).unwrap(),
+        Blob::new("placed", None, 0x200).unwrap(),
+    ];
+    assert!(matches!(
+        place_blobs(&over_placed, LAYOUT),
+        Err(Error::Overlap { other, .. }) if other == top
+    ));
+    // Staging starting below the limit, where blobs are placed.
+    let layout = Layout {
+        limit: 0x200000,
+        staging: 0x180000,
+        staging_limit: 0x300000,
+    };
+    let over_staging = [Blob::new("staged", Some(0x180100), 100).unwrap()];
+    assert_eq!(
+        place_blobs(&over_staging, layout),
+        Err(Error::Overlap {
+            address: 0x180100,
+            length: 100,
+            other: 0x180000
+        })
+    );
+    let placed = [Blob::new("placed", None, 100).unwrap()];
+    assert!(matches!(
+        place_blobs(&placed, layout),
+        Err(Error::Overlap {
+            other: 0x180000,
+            ..
+        })
+    ));
+    let clear = [Blob::new("below", Some(0x17f000), 0x1000).unwrap()];
+    assert_eq!(place_blobs(&clear, layout), Ok(()));
+}
+
+#[test]
+fn rejects_too_many_blobs() {
+    let code = program(100 + MAX_BLOBS + 1);
+    let blobs = vec![Blob::new("one", None, 1).unwrap(); MAX_BLOBS + 1];
+    assert_eq!(
+        Image::raw(&code[..100]).with_blobs(&blobs, &code).err(),
+        Some(ImageError::TooManyBlobs {
+            count: MAX_BLOBS as u32 + 1
+        })
+    );
+    assert_eq!(Blob::new("", None, 1), None);
+    assert_eq!(Blob::new("a name that is too long", None, 1), None);
+}
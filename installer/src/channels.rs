@@ -0,0 +1,104 @@
+//! Routes the channels in the program's output, see
+//! [bootloader_shared::channel].
+//!
+//! The console and log channels are shown and the others dropped, unless
+//! `--channel <name>=<path>` saves a channel to a file instead. The installer
+//! itself can also [keep](Channels::keep) a channel to read it.
+use std::{collections::VecDeque, fs::File, io::Write, path::PathBuf};
+
+use bootloader_shared::channel::{Channel, Demux};
+use eyre::{eyre, Context};
+
+pub struct Channels {
+    demux: Demux,
+    /// Where each channel is saved, if it is.
+    files: [Option<File>; Channel::ALL.len()],
+    /// Channels whose data was dropped, told once.
+    dropped: [bool; Channel::ALL.len()],
+    /// The frames of each kept channel, oldest first.
+    kept: [Option<VecDeque<Vec<u8>>>; Channel::ALL.len()],
+}
+
+/// Parse `<name>=<path>`, for saving the channel called `name` to `path`.
+pub fn parse_subscription(arg: &str) -> Result<(Channel, PathBuf), eyre::Report> {
+    let (name, path) = arg
+        .split_once('=')
+        .ok_or_else(|| eyre!("invalid channel {arg:?}, expected <name>=<path>"))?;
+    let channel = Channel::ALL
+        .into_iter()
+        .find(|channel| channel.name() == name)
+        .ok_or_else(|| {
+            let names: Vec<_> = Channel::ALL.iter().map(|channel| channel.name()).collect();
+            eyre!(
+                "unknown channel {name:?}, expected one of {}",
+                names.join(", ")
+            )
+        })?;
+    Ok((channel, path.into()))
+}
+
+impl Channels {
+    pub fn new(subscriptions: &[(Channel, PathBuf)]) -> Result<Self, eyre::Report> {
+        let mut files: [Option<File>; Channel::ALL.len()] = std::array::from_fn(|_| None);
+        for (channel, path) in subscriptions {
+            let file = File::create(path).with_context(|| {
+                format!(
+                    "creating {} for the {} channel",
+                    path.display(),
+                    channel.name()
+                )
+            })?;
+            files[*channel as usize] = Some(file);
+        }
+        Ok(Self {
+            demux: Demux::new(),
+            files,
+            dropped: [false; Channel::ALL.len()],
+            kept: std::array::from_fn(|_| None),
+        })
+    }
+
+    /// Keep the data on `channel` for [Channels::take], rather than showing,
+    /// saving or dropping it.
+    pub fn keep(&mut self, channel: Channel) {
+        self.kept[channel as usize].get_or_insert_with(VecDeque::new);
+    }
+
+    /// Stop keeping `channel`, forgetting the frames not taken.
+    pub fn stop_keeping(&mut self, channel: Channel) {
+        self.kept[channel as usize] = None;
+    }
+
+    /// The oldest frame kept from `channel`, if any.
+    pub fn take(&mut self, channel: Channel) -> Option<Vec<u8>> {
+        self.kept[channel as usize].as_mut()?.pop_front()
+    }
+
+    /// Add a byte the program printed, returning what to show on the console.
+    pub fn push(&mut self, byte: u8) -> Result<&[u8], eyre::Report> {
+        let Some((channel, data)) = self.demux.push(byte) else {
+            return Ok(&[]);
+        };
+        if let Some(kept) = &mut self.kept[channel as usize] {
+            kept.push_back(data.to_vec());
+            return Ok(&[]);
+        }
+        if let Some(file) = &mut self.files[channel as usize] {
+            file.write_all(data)
+                .with_context(|| format!("saving the {} channel", channel.name()))?;
+            return Ok(&[]);
+        }
+        match channel {
+            Channel::Console | Channel::Log => Ok(data),
+            Channel::Binary | Channel::Control => {
+                if !std::mem::replace(&mut self.dropped[channel as usize], true) {
+                    eprintln!(
+                        "\n[dropping the {0} channel, save it with --channel {0}=<path>]",
+                        channel.name()
+                    );
+                }
+                Ok(&[])
+            }
+        }
+    }
+}
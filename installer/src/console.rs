@@ -0,0 +1,307 @@
+//! Interactive terminal to the loaded program.
+//!
+//! The program's output is printed until it sends its exit status or prints
+//! `DONE!!!`, while stdin is forwarded to it, in raw mode if stdin is a
+//! terminal. Typing the escape character and then `q` quits, `r` asks the
+//! program to return to the bootloader and quits, and the escape character
+//! again sends it through.
+//!
+//! Forwarded input, GDB's packets and replies to host file requests all go
+//! through one [SharedWriter], so a reply can't be split by a keystroke.
+use std::{
+    collections::VecDeque,
+    io::{IsTerminal, Read, Write},
+    os::fd::AsRawFd,
+    sync::mpsc::{self, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use bootloader_shared::{
+    message::{ExitStatus, Message, Scanner},
+    INSTALLER_REBOOT,
+};
+use eyre::{bail, Context};
+use termios::{tcsetattr, Termios, ECHO, ICANON, IEXTEN, ISIG, IXON, TCSANOW, VMIN, VTIME};
+
+use crate::{
+    channels::Channels,
+    core_dump::CoreDump,
+    gdb::Bridge,
+    hostfs::HostFs,
+    profile::Profile,
+    symbols::{Annotator, Symbols},
+    transport::{SharedWriter, Transport},
+};
+
+/// Ctrl-], as in telnet.
+pub const DEFAULT_ESCAPE: u8 = 0x1d;
+/// Give up after this long without output, unless stdin is a terminal.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const DONE: &[u8] = b"DONE!!!";
+
+/// Parse an escape character written as `^X` for a control character, or as
+/// the character itself.
+pub fn parse_escape(escape: &str) -> Result<u8, eyre::Report> {
+    match escape.as_bytes() {
+        [b'^', c] if (b'@'..=b'_').contains(&c.to_ascii_uppercase()) => {
+            Ok(c.to_ascii_uppercase() - b'@')
+        }
+        [c] => Ok(*c),
+        _ => bail!("invalid escape character {escape:?}, expected for example ^] or ~"),
+    }
+}
+
+fn describe(escape: u8) -> String {
+    if escape < b' ' {
+        format!("^{}", (escape + b'@') as char)
+    } else {
+        (escape as char).to_string()
+    }
+}
+
+/// What the user asked for with the escape character.
+enum Command {
+    Quit,
+    Reload,
+}
+
+/// What the console hands the program's messages and channels to.
+pub struct Handlers<'a> {
+    /// Gets profiler samples.
+    pub profile: &'a mut Profile,
+    /// Answers requests for host files.
+    pub hostfs: &'a mut HostFs,
+    /// Bridges the GDB stub, when debugging.
+    pub gdb: Option<&'a mut Bridge>,
+    /// Picks out the channels to show.
+    pub channels: &'a mut Channels,
+}
+
+/// How the program's run went, as far as the console saw.
+pub struct Finished {
+    /// The program's exit status, if it sent one.
+    pub status: Option<ExitStatus>,
+    /// The program's core dump, if it faulted and sent all of it.
+    pub core: Option<CoreDump>,
+}
+
+/// Run the console until the program is done or the user quits. `output` is
+/// what the program printed while the installer finished loading it.
+/// Addresses in the output are annotated with `symbols`, if any, messages and
+/// channels go to `handlers` and a core dump is collected.
+pub fn run(
+    uart: &mut dyn Transport,
+    output: &[u8],
+    escape: u8,
+    symbols: Option<&Symbols>,
+    handlers: Handlers<'_>,
+) -> Result<Finished, eyre::Report> {
+    let Handlers {
+        profile,
+        hostfs,
+        mut gdb,
+        channels,
+    } = handlers;
+    let raw = RawMode::enter().context("setting up terminal")?;
+    if raw.is_terminal() {
+        println!("[escape is {}, then ? for help]", describe(escape));
+    }
+    let mut writer = SharedWriter::new(uart.writer().context("opening console input")?);
+    if let Some(gdb) = &mut gdb {
+        gdb.start(Box::new(writer.clone()));
+    }
+    let input = writer.clone();
+    let (sender, commands) = mpsc::channel();
+    std::thread::spawn(move || {
+        let result = forward_stdin(input, escape);
+        // Nobody is listening anymore if the program finished first.
+        let _ = sender.send(result);
+    });
+
+    let mut stdout = std::io::stdout();
+    let mut scanner = Scanner::new();
+    let mut annotator = symbols.map(Annotator::new);
+    let mut printing = Vec::new();
+    let mut last_chars = VecDeque::new();
+    let mut buf = [0; 256];
+    let mut output = output;
+    let mut last_output = Instant::now();
+    let mut dumping = None;
+    let mut core = None;
+    loop {
+        match commands.try_recv() {
+            Ok(Ok(Command::Quit)) => {
+                println!("\n[quit]");
+                return Ok(Finished { status: None, core });
+            }
+            Ok(Ok(Command::Reload)) => {
+                println!("\n[asked the program to return to the bootloader]");
+                return Ok(Finished { status: None, core });
+            }
+            Ok(Err(e)) => return Err(e).context("forwarding stdin"),
+            // Stdin is closed or still open, either way keep printing.
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => {}
+        }
+
+        if output.is_empty() {
+            let n = uart.read(&mut buf)?;
+            if n == 0 {
+                // Output paused, so a held back address is complete.
+                if let Some(annotator) = &mut annotator {
+                    annotator.flush(&mut printing);
+                    stdout.write_all(&printing)?;
+                    stdout.flush()?;
+                    printing.clear();
+                }
+                if !raw.is_terminal() && last_output.elapsed() > IDLE_TIMEOUT {
+                    bail!(
+                        "no output for {} seconds, giving up",
+                        IDLE_TIMEOUT.as_secs()
+                    );
+                }
+                continue;
+            }
+            output = &buf[..n];
+        }
+        last_output = Instant::now();
+        for &byte in output {
+            let (printed, message) = scanner.push(byte);
+            let mut done = false;
+            for &byte in printed {
+                for &c in channels.push(byte)? {
+                    match &mut annotator {
+                        Some(annotator) => annotator.push(c, &mut printing),
+                        None => printing.push(c),
+                    }
+                    last_chars.push_back(c);
+                    if last_chars.len() > DONE.len() {
+                        last_chars.pop_front();
+                    }
+                    done |= last_chars.iter().eq(DONE);
+                }
+            }
+            if let Some(reply) = message.as_ref().and_then(|m| hostfs.answer(m)) {
+                writer.write_all(&reply)?;
+            }
+            let status = match message {
+                Some(Message::Exit(status)) => Some(status),
+                Some(Message::ProfileSamples { samples }) => {
+                    profile.add(samples);
+                    None
+                }
+                Some(Message::Gdb { data }) => {
+                    if let Some(gdb) = &gdb {
+                        gdb.send(data);
+                    }
+                    None
+                }
+                Some(Message::CoreRegisters { signal, registers }) => {
+                    dumping = Some(CoreDump::new(signal, registers));
+                    None
+                }
+                Some(message) => {
+                    if dumping.as_mut().is_some_and(|dump| dump.push(&message)) {
+                        core = dumping.take();
+                    }
+                    None
+                }
+                None => None,
+            };
+            if status.is_none() && !done {
+                continue;
+            }
+            if let Some(annotator) = &mut annotator {
+                annotator.flush(&mut printing);
+            }
+            stdout.write_all(&printing)?;
+            match status {
+                Some(status) if status.panicked => println!("\n[program panicked]"),
+                Some(status) => println!("\n[program exited with code {}]", status.code),
+                None => println!(),
+            }
+            return Ok(Finished { status, core });
+        }
+        stdout.write_all(&printing)?;
+        stdout.flush()?;
+        printing.clear();
+        output = &[];
+    }
+}
+
+/// Forward stdin until it closes or the user enters a command.
+fn forward_stdin(mut writer: SharedWriter, escape: u8) -> Result<Command, std::io::Error> {
+    let mut stdin = std::io::stdin().lock();
+    let mut escaped = false;
+    let mut buf = [0; 64];
+    loop {
+        let n = stdin.read(&mut buf)?;
+        if n == 0 {
+            // Block forever, stdin was closed but the program keeps running.
+            std::thread::park();
+            continue;
+        }
+        let mut forward = Vec::with_capacity(n);
+        for &c in &buf[..n] {
+            if !escaped {
+                if c == escape {
+                    escaped = true;
+                } else {
+                    forward.push(c);
+                }
+                continue;
+            }
+            escaped = false;
+            match c {
+                b'q' | b'.' => return Ok(Command::Quit),
+                b'r' => {
+                    forward.extend(INSTALLER_REBOOT.to_le_bytes());
+                    writer.write_all(&forward)?;
+                    return Ok(Command::Reload);
+                }
+                _ if c == escape => forward.push(c),
+                b'?' => eprintln!("\n[escape then: q quit, r reload, escape to send it]"),
+                _ => forward.extend([escape, c]),
+            }
+        }
+        writer.write_all(&forward)?;
+    }
+}
+
+/// Puts stdin into raw mode while alive, if it is a terminal.
+struct RawMode {
+    original: Option<Termios>,
+}
+
+impl RawMode {
+    fn enter() -> Result<Self, eyre::Report> {
+        let stdin = std::io::stdin();
+        if !stdin.is_terminal() {
+            return Ok(Self { original: None });
+        }
+        let fd = stdin.as_raw_fd();
+        let original = Termios::from_fd(fd)?;
+        let mut raw = original;
+        // Pass each key through as typed, without echo or signals. Output
+        // processing stays on so program output still prints normally.
+        raw.c_lflag &= !(ICANON | ECHO | ISIG | IEXTEN);
+        raw.c_iflag &= !IXON;
+        raw.c_cc[VMIN] = 1;
+        raw.c_cc[VTIME] = 0;
+        tcsetattr(fd, TCSANOW, &raw)?;
+        Ok(Self {
+            original: Some(original),
+        })
+    }
+
+    fn is_terminal(&self) -> bool {
+        self.original.is_some()
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            let _ = tcsetattr(std::io::stdin().as_raw_fd(), TCSANOW, original);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Collects a core dump from a faulted program and writes it as an ARM ELF
+//! core file, see [bootloader_shared::core_dump].
+//!
+//! The registers go in an `NT_PRSTATUS` note laid out as on ARM Linux, which
+//! is where GDB looks for them, and each region of memory in a `PT_LOAD`
+//! segment.
+use std::path::Path;
+
+use bootloader_shared::{core_dump::CORE_REGISTERS, message::Message};
+use eyre::Context;
+use object::elf::{
+    ELFCLASS32, ELFDATA2LSB, ELFMAG, ELFOSABI_NONE, EM_ARM, ET_CORE, EV_CURRENT, NT_PRSTATUS, PF_R,
+    PF_W, PF_X, PT_LOAD, PT_NOTE,
+};
+
+/// Largest region accepted, more than the Pi has.
+const MAX_REGION: u32 = 512 * 1024 * 1024;
+const HEADER_LENGTH: usize = 52;
+const PROGRAM_HEADER_LENGTH: usize = 32;
+/// `struct elf_prstatus` on 32-bit ARM.
+const PRSTATUS_LENGTH: usize = 148;
+/// Where the registers are in the `elf_prstatus`, followed by `orig_r0`.
+const PRSTATUS_REGISTERS: usize = 72;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 24;
+/// `CORE`, zero terminated and padded.
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+
+pub struct CoreDump {
+    signal: u32,
+    registers: [u32; CORE_REGISTERS],
+    regions: Vec<Region>,
+}
+
+struct Region {
+    address: u32,
+    data: Vec<u8>,
+}
+
+impl CoreDump {
+    pub fn new(signal: u32, registers: [u32; CORE_REGISTERS]) -> Self {
+        Self {
+            signal,
+            registers,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Add a message of the dump, returning whether it was the last.
+    pub fn push(&mut self, message: &Message) -> bool {
+        match *message {
+            Message::CoreRegion { address, length } if length <= MAX_REGION => {
+                self.regions.push(Region {
+                    address,
+                    data: vec![0; length as usize],
+                });
+            }
+            Message::CoreMemory { address, data } => {
+                let region = self.regions.iter_mut().find(|region| {
+                    address >= region.address
+                        && (address - region.address) as usize + data.len() <= region.data.len()
+                });
+                if let Some(region) = region {
+                    let start = (address - region.address) as usize;
+                    region.data[start..start + data.len()].copy_from_slice(data);
+                }
+            }
+            Message::CoreEnd => return true,
+            _ => {}
+        }
+        false
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), eyre::Report> {
+        std::fs::write(path, self.elf())
+            .with_context(|| format!("writing core dump to {}", path.display()))
+    }
+
+    fn elf(&self) -> Vec<u8> {
+        let segments = 1 + self.regions.len();
+        let note_offset = HEADER_LENGTH + segments * PROGRAM_HEADER_LENGTH;
+        let note_length = 12 + NOTE_NAME.len() + PRSTATUS_LENGTH;
+        let mut elf = Vec::new();
+
+        elf.extend_from_slice(&ELFMAG);
+        elf.extend_from_slice(&[ELFCLASS32, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE]);
+        elf.resize(16, 0);
+        put_half(&mut elf, ET_CORE);
+        put_half(&mut elf, EM_ARM);
+        put_word(&mut elf, EV_CURRENT.into());
+        // Entry, program headers, section headers and flags.
+        for word in [0, HEADER_LENGTH as u32, 0, 0] {
+            put_word(&mut elf, word);
+        }
+        put_half(&mut elf, HEADER_LENGTH as u16);
+        put_half(&mut elf, PROGRAM_HEADER_LENGTH as u16);
+        put_half(&mut elf, segments as u16);
+        // No sections.
+        for half in [0, 0, 0] {
+            put_half(&mut elf, half);
+        }
+
+        let mut offset = (note_offset + note_length) as u32;
+        put_program_header(
+            &mut elf,
+            PT_NOTE,
+            note_offset as u32,
+            0,
+            note_length as u32,
+            0,
+        );
+        for region in &self.regions {
+            let length = region.data.len() as u32;
+            put_program_header(
+                &mut elf,
+                PT_LOAD,
+                offset,
+                region.address,
+                length,
+                PF_R | PF_W | PF_X,
+            );
+            offset += length;
+        }
+
+        put_word(&mut elf, 5);
+        put_word(&mut elf, PRSTATUS_LENGTH as u32);
+        put_word(&mut elf, NT_PRSTATUS);
+        elf.extend_from_slice(NOTE_NAME);
+        let mut prstatus = [0; PRSTATUS_LENGTH];
+        prstatus[0..4].copy_from_slice(&self.signal.to_le_bytes());
+        prstatus[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2]
+            .copy_from_slice(&(self.signal as u16).to_le_bytes());
+        prstatus[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&1u32.to_le_bytes());
+        // orig_r0 is r0, as no system call was interrupted.
+        let registers = self.registers.iter().chain(&self.registers[..1]);
+        for (chunk, register) in prstatus[PRSTATUS_REGISTERS..]
+            .chunks_exact_mut(4)
+            .zip(registers)
+        {
+            chunk.copy_from_slice(&register.to_le_bytes());
+        }
+        elf.extend_from_slice(&prstatus);
+
+        for region in &self.regions {
+            elf.extend_from_slice(&region.data);
+        }
+        elf
+    }
+}
+
+fn put_half(elf: &mut Vec<u8>, half: u16) {
+    elf.extend_from_slice(&half.to_le_bytes());
+}
+
+fn put_word(elf: &mut Vec<u8>, word: u32) {
+    elf.extend_from_slice(&word.to_le_bytes());
+}
+
+fn put_program_header(
+    elf: &mut Vec<u8>,
+    kind: u32,
+    offset: u32,
+    address: u32,
+    length: u32,
+    flags: u32,
+) {
+    for word in [kind, offset, address, address, length, length, flags, 4] {
+        put_word(elf, word);
+    }
+}
@@ -0,0 +1,95 @@
+//! Explains why loading a program failed, with hints on what to do about it.
+use bootloader_shared::{
+    boot_info::MAX_BLOBS, installer::Image, Error, BASE, MAX_BOOTLOADER_LENGTH, MAX_SEGMENTS,
+};
+use eyre::eyre;
+
+/// Report `error`, along with a hint if there is one.
+pub fn explain(error: Error, image: &Image) -> eyre::Report {
+    let report = match hint(error, image) {
+        Some(hint) => eyre!("{error}\nhint: {hint}"),
+        None => eyre::Report::new(error),
+    };
+    report.wrap_err("loading program")
+}
+
+fn hint(error: Error, image: &Image) -> Option<String> {
+    let hint = match error {
+        Error::Timeout => {
+            "the Pi stopped answering, check that it is powered and that the serial adapter is \
+             wired to pins 14 and 15, then reset it"
+                .into()
+        }
+        Error::Pi(report) => match report.error() {
+            Some(Error::Timeout) => "the Pi stopped hearing from the installer partway through, \
+                                     check the wiring or try a lower --baud"
+                .into(),
+            Some(error) => return hint(error, image),
+            None => "the bootloader is a different version than the installer, rebuild both \
+                     and update it with `just update-boot`"
+                .into(),
+        },
+        Error::TooLarge { length, available } => {
+            let over = length - available;
+            if image.is_update() {
+                format!(
+                    "the bootloader is {over} bytes over the limit, it can be at most \
+                     {MAX_BOOTLOADER_LENGTH} bytes"
+                )
+            } else {
+                format!(
+                    "the image is {over} bytes over the limit, try without --compress, which \
+                     needs room for both the compressed and decompressed program"
+                )
+            }
+        }
+        Error::OutOfBounds {
+            address,
+            length,
+            limit,
+        } => {
+            if address < BASE {
+                format!(
+                    "it starts {} bytes below {BASE:#x}, link the program at {BASE:#x} or above",
+                    BASE - address
+                )
+            } else {
+                // The last byte must be below `limit`.
+                let over = (address as u64 + length as u64) - (limit as u64 - 1);
+                format!("the image is {over} bytes over the limit of {limit:#010x}")
+            }
+        }
+        Error::TooManySegments { .. } => format!(
+            "merge sections in the linker script so there are at most {MAX_SEGMENTS} loadable \
+             segments"
+        ),
+        Error::TooManyBlobs { .. } => {
+            format!("send at most {MAX_BLOBS} blobs, combining files into one if need be")
+        }
+        Error::Overlap { .. } => {
+            "give the blob an address clear of the program's segments and the other blobs, or \
+             leave it out for the bootloader to place it"
+                .into()
+        }
+        Error::Unexpected { .. } => {
+            "the bootloader may be a different version than the installer, rebuild both and \
+             update it with `just update-boot`"
+                .into()
+        }
+        Error::ChecksumMismatch { .. } => {
+            "the program got corrupted on the way, check the wiring or try a lower --baud".into()
+        }
+        Error::Decompress => "try again without --compress".into(),
+        Error::TooManyRetries { .. } => {
+            "the line is too noisy, try a lower --baud or a shorter cable".into()
+        }
+        Error::NotBootloader => "send the flat boot.bin made by `just build-boot`".into(),
+        Error::BadSignature => {
+            "sign it with --key, or PI_KEY, using the key file the bootloader was built with".into()
+        }
+        Error::MissingAck => {
+            "the program arrived but the installer's acknowledgement didn't, try again".into()
+        }
+    };
+    Some(hint)
+}
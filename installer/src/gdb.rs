@@ -0,0 +1,85 @@
+//! Bridges GDB to the stub in the running program, see
+//! [bootloader_shared::gdb].
+//!
+//! GDB connects to a TCP port on localhost. What it sends goes to the program
+//! as input, and the stub's output arrives in messages for [Bridge::send].
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use eyre::Context;
+
+pub struct Bridge {
+    port: u16,
+    /// Taken once the program runs, so GDB can't disturb loading.
+    listener: Option<TcpListener>,
+    /// The connected GDB, if any.
+    client: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl Bridge {
+    /// Listen on `port`, before loading so a port in use fails early.
+    pub fn bind(port: u16) -> Result<Self, eyre::Report> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .with_context(|| format!("listening for gdb on port {port}"))?;
+        Ok(Self {
+            port,
+            listener: Some(listener),
+            client: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Start forwarding what GDB sends to `writer`, one connection at a time.
+    pub fn start(&mut self, writer: Box<dyn Write + Send>) {
+        let Some(listener) = self.listener.take() else {
+            return;
+        };
+        println!("[connect gdb with: target remote :{}]", self.port);
+        let client = self.client.clone();
+        std::thread::spawn(move || forward(listener, writer, &client));
+    }
+
+    /// Send the stub's output to GDB, dropping it while GDB isn't connected.
+    pub fn send(&self, data: &[u8]) {
+        let mut client = self.client.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            if stream.write_all(data).is_err() {
+                *client = None;
+            }
+        }
+    }
+}
+
+fn forward(
+    listener: TcpListener,
+    mut writer: Box<dyn Write + Send>,
+    client: &Mutex<Option<TcpStream>>,
+) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        // Packets are small and GDB waits for each reply.
+        let _ = stream.set_nodelay(true);
+        let Ok(sender) = stream.try_clone() else {
+            continue;
+        };
+        *client.lock().unwrap() = Some(sender);
+        println!("\n[gdb connected]");
+        let mut buf = [0; 1024];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if writer.write_all(&buf[..n]).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        *client.lock().unwrap() = None;
+        println!("\n[gdb disconnected]");
+    }
+}
@@ -0,0 +1,156 @@
+//! Serves files under one directory to the running program, see
+//! [bootloader_shared::hostfs].
+//!
+//! Paths are relative to the directory. Absolute paths, `..` and symlinks
+//! leading out of it are refused, and so are dangling symlinks, as where they
+//! lead can't be checked before creating it.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use bootloader_shared::{
+    hostfs::{HostError, Mode, Reply, MAX_DATA, MAX_REPLY_LENGTH},
+    message::Message,
+};
+use eyre::Context;
+
+/// Most files the program may have open at once.
+const MAX_FILES: usize = 16;
+
+pub struct HostFs {
+    /// The served directory, or `None` to refuse every request.
+    root: Option<PathBuf>,
+    files: HashMap<u32, File>,
+    next_handle: u32,
+}
+
+impl HostFs {
+    pub fn new(root: Option<&Path>) -> Result<Self, eyre::Report> {
+        let root = root
+            .map(|root| {
+                root.canonicalize()
+                    .with_context(|| format!("opening --hostfs directory {}", root.display()))
+            })
+            .transpose()?;
+        Ok(Self {
+            root,
+            files: HashMap::new(),
+            next_handle: 1,
+        })
+    }
+
+    /// The reply to send if `message` is a request for host files.
+    pub fn answer(&mut self, message: &Message) -> Option<Vec<u8>> {
+        let mut data = [0; MAX_DATA];
+        let (result, length) = match *message {
+            Message::HostOpen { mode, path } => (self.open(path, mode), 0),
+            Message::HostRead { handle, length } => {
+                let length = (length as usize).min(MAX_DATA);
+                match self.read(handle, &mut data[..length]) {
+                    Ok(n) => (Ok(0), n),
+                    Err(e) => (Err(e), 0),
+                }
+            }
+            Message::HostWrite { handle, data } => (self.write(handle, data), 0),
+            Message::HostClose { handle } => (self.close(handle), 0),
+            _ => return None,
+        };
+        let reply = Reply {
+            result,
+            data: &data[..length],
+        };
+        Some(reply.encode(&mut [0; MAX_REPLY_LENGTH]).to_vec())
+    }
+
+    fn open(&mut self, path: &str, mode: Mode) -> Result<u32, HostError> {
+        let path = self.resolve(path)?;
+        if self.files.len() >= MAX_FILES {
+            return Err(HostError::TooManyFiles);
+        }
+        let mut options = File::options();
+        // Resolving followed every symlink, so one in its place now was
+        // swapped in since.
+        options.custom_flags(libc::O_NOFOLLOW);
+        match mode {
+            Mode::Read => options.read(true),
+            Mode::Write => options.write(true).create(true).truncate(true),
+            Mode::Append => options.append(true).create(true),
+            Mode::ReadWrite => options.read(true).write(true),
+        };
+        let file = options.open(&path).map_err(host_error)?;
+        // As might a directory on the way.
+        let root = self.root.as_ref().ok_or(HostError::Disabled)?;
+        if !path.canonicalize().map_err(host_error)?.starts_with(root) {
+            return Err(HostError::OutsideRoot);
+        }
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, file);
+        Ok(handle)
+    }
+
+    fn read(&mut self, handle: u32, buf: &mut [u8]) -> Result<usize, HostError> {
+        let file = self.files.get_mut(&handle).ok_or(HostError::BadHandle)?;
+        file.read(buf).map_err(host_error)
+    }
+
+    fn write(&mut self, handle: u32, data: &[u8]) -> Result<u32, HostError> {
+        let file = self.files.get_mut(&handle).ok_or(HostError::BadHandle)?;
+        file.write_all(data).map_err(host_error)?;
+        Ok(data.len() as u32)
+    }
+
+    fn close(&mut self, handle: u32) -> Result<u32, HostError> {
+        let file = self.files.remove(&handle).ok_or(HostError::BadHandle)?;
+        file.sync_all().map_err(host_error)?;
+        Ok(0)
+    }
+
+    /// Where `path` is under the root, if it stays there.
+    fn resolve(&self, path: &str) -> Result<PathBuf, HostError> {
+        let root = self.root.as_ref().ok_or(HostError::Disabled)?;
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(HostError::OutsideRoot);
+        }
+        let path = root.join(relative);
+        // A file about to be created has no canonical path yet, its directory
+        // does.
+        let (directory, name) = match path.canonicalize() {
+            Ok(path) => (path, None),
+            // A symlink to nowhere, which creating the file would follow.
+            Err(_) if path.symlink_metadata().is_ok() => return Err(HostError::OutsideRoot),
+            Err(_) => {
+                let name = path.file_name().ok_or(HostError::NotFound)?;
+                let directory = path.parent().ok_or(HostError::NotFound)?;
+                (directory.canonicalize().map_err(host_error)?, Some(name))
+            }
+        };
+        if !directory.starts_with(root) {
+            return Err(HostError::OutsideRoot);
+        }
+        Ok(match name {
+            Some(name) => directory.join(name),
+            None => directory,
+        })
+    }
+}
+
+fn host_error(error: std::io::Error) -> HostError {
+    // Opening a symlink with O_NOFOLLOW.
+    if error.raw_os_error() == Some(libc::ELOOP) {
+        return HostError::OutsideRoot;
+    }
+    match error.kind() {
+        ErrorKind::NotFound => HostError::NotFound,
+        ErrorKind::PermissionDenied => HostError::PermissionDenied,
+        _ => HostError::Io,
+    }
+}
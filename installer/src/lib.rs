@@ -0,0 +1,100 @@
+//! Loads programs onto a Pi running the bootloader and talks to them, as the
+//! `install` binary does.
+//!
+//! For automation, a [Session] loads a program and then follows its output:
+//!
+//! ```ignore
+//! let program = Program::read(Path::new("target/app.elf"))?;
+//! let mut session = Session::open("tcp:127.0.0.1:4444")?;
+//! session.load(program.image()?, DEFAULT_BAUD)?;
+//! session.expect("ready", Duration::from_secs(5))?;
+//! session.send(b"start\n")?;
+//! let status = session.wait_exit(Duration::from_secs(30))?;
+//! ```
+pub mod channels;
+pub mod console;
+pub mod core_dump;
+mod explain;
+pub mod gdb;
+pub mod hostfs;
+pub mod profile;
+pub mod program;
+pub mod rpc;
+pub mod session;
+pub mod symbols;
+pub mod testing;
+#[cfg(test)]
+mod tests;
+pub mod transport;
+pub mod uart;
+
+use std::time::Instant;
+
+use bootloader_shared::{
+    installer::{Event, Image, Installer},
+    Micros, DEFAULT_BAUD,
+};
+pub use session::Session;
+use transport::Transport;
+
+/// How a load is going, as [transmit] reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Waiting for the Pi to ask for the program.
+    Listening,
+    /// A step of the load, as the installer protocol tells it.
+    Event(Event),
+    /// Switching to `baud` for the load.
+    TryingBaud { baud: u32 },
+    /// `baud` didn't work, so the load carries on at [DEFAULT_BAUD].
+    FellBack { baud: u32 },
+}
+
+/// Load the program, at `baud` if the Pi manages it, returning what it
+/// printed in the meantime. How the load goes is passed to `progress`.
+pub fn transmit(
+    uart: &mut dyn Transport,
+    image: Image,
+    baud: u32,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Vec<u8>, eyre::Report> {
+    let start = Instant::now();
+    let now = || start.elapsed().as_micros() as Micros;
+    let mut installer = Installer::new(image, now()).with_baud(baud);
+    let mut current = DEFAULT_BAUD;
+    let mut buf = [0; 256];
+    progress(Progress::Listening);
+    loop {
+        installer
+            .poll(now())
+            .map_err(|e| explain::explain(e, &image))?;
+        let n = uart.read(&mut buf)?;
+        let mut input = &buf[..n];
+        while !input.is_empty() && !installer.is_done() {
+            let (used, event) = installer
+                .receive(input, now())
+                .map_err(|e| explain::explain(e, &image))?;
+            if event != Event::None {
+                progress(Progress::Event(event));
+            }
+            input = &input[used..];
+        }
+        while let Some(bytes) = installer.transmit() {
+            uart.put_bytes(bytes)?;
+        }
+        if installer.baud() != current {
+            if installer.baud() != DEFAULT_BAUD {
+                progress(Progress::TryingBaud {
+                    baud: installer.baud(),
+                });
+            } else if !installer.is_done() {
+                progress(Progress::FellBack { baud: current });
+            }
+            current = installer.baud();
+            uart.set_baud(current)?;
+        }
+        if installer.is_done() {
+            return Ok(input.to_vec());
+        }
+    }
+}
@@ -1,136 +1,413 @@
-mod uart;
-
-use std::{
-    collections::VecDeque,
-    fs::File,
-    io::{Read, Write},
-    time::{Duration, Instant},
-};
+use std::path::PathBuf;
 
 use bootloader_shared::{
-    is_pi_get_prog_info_byte, CRC_ALGORITHM, INSTALLER_PROG_INFO, INSTALLER_SUCCESS, PI_GET_CODE,
-    PI_GET_PROG_INFO, PI_SUCCESS,
+    channel::Channel,
+    installer::{Event, Image},
+    sign::Key,
+    DEFAULT_BAUD,
+};
+use eyre::{bail, eyre, Context};
+use installer::{
+    channels::{self, Channels},
+    console, gdb,
+    hostfs::HostFs,
+    profile::Profile,
+    program::Program,
+    rpc,
+    symbols::Symbols,
+    testing, transmit, transport, uart, Progress,
 };
-use eyre::{ensure, eyre, Context};
-use uart::Uart;
 
-fn main() {
-    let mut args = std::env::args();
-    let target = args.nth(1).expect("requires a file to install");
-    let mut program = Vec::new();
-    File::open(target)
-        .expect("file does not exist")
-        .read_to_end(&mut program)
-        .expect("could not read file");
+const USAGE: &str = "\
+usage: install [<option>...] <program>
+options:
+    --device <path>|tcp:<addr>|unix:<path>|pty:<path>
+    --baud <rate>
+    --compress
+    --escape <char>
+    --test [--junit <path>]
+    --symbols <elf>
+    --folded <path>
+    --update-bootloader
+    --key <path>
+    --blob <name>[@<address>]=<path>      (repeatable)
+    --hostfs <dir>
+    --gdb <port>
+    --core <path>
+    --channel <name>=<path>               (repeatable)
+    --call <name>[=<n>,...]               (repeatable)";
+/// Device to use instead of autodetecting a serial adapter, in any of the
+/// forms accepted by [transport::open].
+const DEVICE_ENV: &str = "PI_DEVICE";
+/// Baud rate to load at instead of [DEFAULT_BAUD], if the Pi manages it.
+const BAUD_ENV: &str = "PI_BAUD";
+/// Console escape character to use instead of [console::DEFAULT_ESCAPE].
+const ESCAPE_ENV: &str = "PI_ESCAPE";
+/// Key file to sign programs with.
+const KEY_ENV: &str = "PI_KEY";
 
-    transmit(&program).unwrap();
+struct Args {
+    program: PathBuf,
+    device: String,
+    /// Rate to propose for loading the program.
+    baud: u32,
+    /// Send the program LZ4 compressed.
+    compress: bool,
+    escape: u8,
+    /// Run the program's tests instead of a console.
+    test: bool,
+    /// Where to write a JUnit XML report of the tests.
+    junit: Option<PathBuf>,
+    /// ELF to resolve addresses in the output with, instead of the program.
+    symbols: Option<PathBuf>,
+    /// Where to write the program's profile as folded stacks.
+    folded: Option<PathBuf>,
+    /// The program is a flat bootloader binary for the Pi to write to its SD
+    /// card, instead of something to run.
+    update_bootloader: bool,
+    /// Key file to sign the program with, for a bootloader built with the
+    /// key in `BOOTLOADER_KEY`.
+    key: Option<PathBuf>,
+    /// Files to send along with the program.
+    blobs: Vec<BlobArg>,
+    /// Directory the program may open files in.
+    hostfs: Option<PathBuf>,
+    /// Port for GDB to debug the program on.
+    gdb: Option<u16>,
+    /// Where to write the program's core dump if it faults.
+    core: PathBuf,
+    /// Channels to save to files instead of showing or dropping them.
+    channels: Vec<(Channel, PathBuf)>,
+    /// Functions to call once the program serves calls, before the console.
+    calls: Vec<CallArg>,
 }
 
-fn transmit(program: &[u8]) -> Result<(), eyre::Report> {
-    let mut checksum = CRC_ALGORITHM.digest_with_initial(0);
-    checksum.update(program);
-    let checksum = checksum.finalize();
+/// A file to send as a blob, see [bootloader_shared::boot_info].
+struct BlobArg {
+    name: String,
+    /// Where to load it, instead of where the bootloader places it.
+    address: Option<u32>,
+    path: PathBuf,
+}
 
-    let mut uart = Uart::open().context("opening uart")?;
-    let mut v: u32 = 0;
-    let mut count = 0;
-    let start = Instant::now();
-    println!("listening for prog info req");
-    loop {
-        let new_value = uart
-            .getu8(Duration::from_secs(10))
-            .context("waiting for prog info req")?;
-        v = (v >> 8) + ((new_value as u32) << 24);
-        count += 1;
-        if count > 4 {
-            println!("got {:#010x}", v);
-        }
-        if v == PI_GET_PROG_INFO {
-            break;
-        }
+impl BlobArg {
+    /// Parse `<name>[@<address>]=<path>`, with the address in hex or decimal.
+    fn parse(arg: &str) -> Result<Self, eyre::Report> {
+        let (name, path) = arg
+            .split_once('=')
+            .ok_or_else(|| eyre!("invalid blob {arg:?}, expected <name>[@<address>]=<path>"))?;
+        let (name, address) = match name.split_once('@') {
+            Some((name, address)) => {
+                let parsed = match address.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => address.parse(),
+                };
+                let address =
+                    parsed.with_context(|| format!("invalid blob address {address:?}"))?;
+                // 0 is how the bootloader is asked to place a blob itself.
+                if address == 0 {
+                    bail!("blob {name:?} can't be at 0, leave out @0 to have it placed for you");
+                }
+                (name, Some(address))
+            }
+            None => (name, None),
+        };
+        Ok(Self {
+            name: name.into(),
+            address,
+            path: path.into(),
+        })
+    }
+}
+
+/// A function of the program to call, see [rpc].
+struct CallArg {
+    name: String,
+    /// The arguments in postcard.
+    args: Vec<u8>,
+}
 
-        if Instant::now().duration_since(start) > Duration::from_secs(3) {
-            return Err(eyre!(
-                "timeout of 3 seconds elapsed while waiting for prog info req match"
-            ));
+impl CallArg {
+    /// Parse `<name>[=<n>,...]`. Each number is encoded as postcard encodes
+    /// `u16` to `u64`, so the function takes a tuple of those.
+    fn parse(arg: &str) -> Result<Self, eyre::Report> {
+        let (name, numbers) = arg.split_once('=').unwrap_or((arg, ""));
+        let mut args = Vec::new();
+        for number in numbers.split(',').filter(|number| !number.is_empty()) {
+            let number: u64 = number
+                .parse()
+                .with_context(|| format!("invalid argument {number:?} to {name}"))?;
+            args.extend(postcard::to_allocvec(&number)?);
         }
+        Ok(Self {
+            name: name.into(),
+            args,
+        })
     }
+}
 
-    println!("got prog info request");
-    uart.put32(INSTALLER_PROG_INFO)?;
+impl Args {
+    /// Command line flags take precedence over the environment.
+    fn parse() -> Result<Self, eyre::Report> {
+        let mut program = None;
+        let mut device = std::env::var(DEVICE_ENV).ok();
+        let mut baud = std::env::var(BAUD_ENV).ok();
+        let mut compress = false;
+        let mut escape = std::env::var(ESCAPE_ENV).ok();
+        let mut test = false;
+        let mut junit = None;
+        let mut symbols = None;
+        let mut folded = None;
+        let mut update_bootloader = false;
+        let mut key = std::env::var_os(KEY_ENV).map(PathBuf::from);
+        let mut blobs = Vec::new();
+        let mut hostfs = None;
+        let mut gdb = None;
+        let mut core = PathBuf::from("core");
+        let mut channels = Vec::new();
+        let mut calls = Vec::new();
 
-    uart.put32(program.len() as u32)?;
-    uart.put32(checksum)?;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--device" => device = Some(args.next().ok_or_else(|| eyre!(USAGE))?),
+                "--baud" => baud = Some(args.next().ok_or_else(|| eyre!(USAGE))?),
+                "--compress" => compress = true,
+                "--escape" => escape = Some(args.next().ok_or_else(|| eyre!(USAGE))?),
+                "--test" => test = true,
+                "--junit" => junit = Some(args.next().ok_or_else(|| eyre!(USAGE))?.into()),
+                "--symbols" => symbols = Some(args.next().ok_or_else(|| eyre!(USAGE))?.into()),
+                "--folded" => folded = Some(args.next().ok_or_else(|| eyre!(USAGE))?.into()),
+                "--update-bootloader" => update_bootloader = true,
+                "--key" => key = Some(args.next().ok_or_else(|| eyre!(USAGE))?.into()),
+                "--hostfs" => hostfs = Some(args.next().ok_or_else(|| eyre!(USAGE))?.into()),
+                "--gdb" => gdb = Some(args.next().ok_or_else(|| eyre!(USAGE))?),
+                "--core" => core = args.next().ok_or_else(|| eyre!(USAGE))?.into(),
+                "--channel" => channels.push(channels::parse_subscription(
+                    &args.next().ok_or_else(|| eyre!(USAGE))?,
+                )?),
+                "--call" => calls.push(CallArg::parse(&args.next().ok_or_else(|| eyre!(USAGE))?)?),
+                "--blob" => blobs.push(BlobArg::parse(&args.next().ok_or_else(|| eyre!(USAGE))?)?),
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ if program.is_none() && !arg.starts_with("--") => program = Some(arg.into()),
+                _ => bail!("unexpected argument {arg:?}\n{USAGE}"),
+            }
+        }
 
-    // Ignore trailing GET_PROG_INFO bytes.
-    let mut byte = uart.getu8(Duration::from_secs(300))?;
-    let mut trailing_bytes = vec![];
-    while is_pi_get_prog_info_byte(byte) {
-        trailing_bytes.push(byte);
-        byte = uart.getu8(Duration::from_secs(10)).with_context(|| {
-            format!(
-                "clearing prog info bytes: {}",
-                trailing_bytes
-                    .iter()
-                    .map(|b| format!("{:#04x}", b))
-                    .collect::<Vec<_>>()
-                    .join(",")
-            )
-        })?;
-    }
-    // Get remaining bytes.
-    let mut next = (byte as u32) << 24;
-    for _ in 1..4 {
-        let byte = uart.getu8(Duration::from_secs(10))?;
-        next = (next >> 8) + ((byte as u32) << 24);
+        let device = match device {
+            Some(device) => device,
+            None => uart::find_device()?.to_string_lossy().into_owned(),
+        };
+        let baud = match baud {
+            Some(baud) => baud
+                .parse()
+                .with_context(|| format!("invalid baud rate {baud:?}"))?,
+            None => DEFAULT_BAUD,
+        };
+        if junit.is_some() && !test {
+            bail!("--junit requires --test\n{USAGE}");
+        }
+        if gdb.is_some() && test {
+            bail!("--gdb can't be combined with --test\n{USAGE}");
+        }
+        if !calls.is_empty() && (test || update_bootloader) {
+            bail!("--call can't be combined with --test or --update-bootloader\n{USAGE}");
+        }
+        let gdb = gdb
+            .map(|port| {
+                port.parse()
+                    .with_context(|| format!("invalid gdb port {port:?}"))
+            })
+            .transpose()?;
+        if update_bootloader && (test || compress || !blobs.is_empty()) {
+            bail!(
+                "--update-bootloader can't be combined with --test, --compress or --blob\n{USAGE}"
+            );
+        }
+        let escape = match escape {
+            Some(escape) => console::parse_escape(&escape)?,
+            None => console::DEFAULT_ESCAPE,
+        };
+        Ok(Self {
+            program: program.ok_or_else(|| eyre!("requires a file to install\n{USAGE}"))?,
+            device,
+            baud,
+            compress,
+            escape,
+            test,
+            junit,
+            symbols,
+            folded,
+            update_bootloader,
+            key,
+            blobs,
+            hostfs,
+            gdb,
+            core,
+            channels,
+            calls,
+        })
     }
+}
 
-    ensure!(
-        next == PI_GET_CODE,
-        "expected get code, got {:#010x} but expected {:#010x}",
-        next,
-        PI_GET_CODE
-    );
-    let pi_checksum = uart.get32()?;
-
-    if pi_checksum != checksum {
-        return Err(eyre!(
-            "mismatched checksum: got {:#010x} but expected {:#010x}",
-            pi_checksum,
-            checksum
-        ));
+fn main() -> Result<(), eyre::Report> {
+    let args = Args::parse()?;
+    let mut program = Program::read(&args.program)?;
+    if args.update_bootloader && !bootloader_shared::is_bootloader(&program.code) {
+        bail!(
+            "{} is not a bootloader, expected a flat binary like boot.bin",
+            args.program.display()
+        );
+    }
+    let key = args.key.as_deref().map(read_key).transpose()?;
+    let symbols = Symbols::read(args.symbols.as_ref().unwrap_or(&args.program))?;
+    let mut hostfs = HostFs::new(args.hostfs.as_deref())?;
+    let mut gdb = args.gdb.map(gdb::Bridge::bind).transpose()?;
+    let mut channels = Channels::new(&args.channels)?;
+    for segment in &program.segments {
+        println!(
+            "segment at {:#010x}: {} bytes, {} in memory",
+            segment.address, segment.length, segment.memory_length
+        );
+    }
+    for blob in &args.blobs {
+        let data = std::fs::read(&blob.path)
+            .with_context(|| format!("reading blob {}", blob.path.display()))?;
+        program.add_blob(&blob.name, blob.address, &data)?;
+        match blob.address {
+            Some(address) => println!(
+                "blob {} at {address:#010x}: {} bytes",
+                blob.name,
+                data.len()
+            ),
+            None => println!(
+                "blob {}: {} bytes, placed by the bootloader",
+                blob.name,
+                data.len()
+            ),
+        }
     }
 
-    println!(
-        "matched checksum, sending program: {} KB",
-        program.len() / 1_000
-    );
-    uart.put_bytes(program)?;
-
-    let next = uart.get32()?;
-    ensure!(
-        next == PI_SUCCESS,
-        "expected success message: got {:#010x} but expected {:#010x}",
-        next,
-        PI_SUCCESS
-    );
-
-    uart.put32(INSTALLER_SUCCESS)?;
-    println!("successfully loaded, waiting for DONE!!!\n");
+    println!("using {} at {DEFAULT_BAUD} baud", args.device);
+    let mut uart = transport::open(&args.device).context("opening transport")?;
+    let mut image = program.image()?;
+    if args.update_bootloader {
+        image = image.update()?;
+    }
+    if let Some(key) = &key {
+        image = image.sign(key)?;
+    }
+    let compressed;
+    if args.compress {
+        compressed = lz4_flex::block::compress(&program.code);
+        println!(
+            "compressed program from {} KB to {} KB",
+            program.code.len() / 1_000,
+            compressed.len() / 1_000
+        );
+        image = image.lz4(&compressed)?;
+    }
+    let mut progress = |progress| show_progress(progress, &image);
+    if args.test {
+        let results = testing::run(
+            uart.as_mut(),
+            image,
+            args.baud,
+            symbols.as_ref(),
+            &mut hostfs,
+            &mut channels,
+            &mut progress,
+        )?;
+        testing::summarize(&results);
+        if let Some(path) = &args.junit {
+            testing::write_junit(path, &results)?;
+        }
+        if !results.iter().all(|result| result.passed()) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let mut output = transmit(uart.as_mut(), image, args.baud, &mut progress)?;
+    if !args.calls.is_empty() {
+        let mut stdout = std::io::stdout();
+        let mut client = rpc::Client::connect(uart.as_mut(), &output, &mut channels, &mut stdout)?;
+        for call in &args.calls {
+            let result = client.call_raw(&call.name, &call.args)?;
+            let hex: Vec<_> = result.iter().map(|byte| format!("{byte:02x}")).collect();
+            print!("\n[{} returned {}", call.name, hex.join(" "));
+            match postcard::take_from_bytes::<u64>(&result) {
+                Ok((number, [])) => println!(", {number} as a number]"),
+                _ => println!("]"),
+            }
+        }
+        output = client.finish()?;
+    }
+    let mut profile = Profile::default();
+    let finished = console::run(
+        uart.as_mut(),
+        &output,
+        args.escape,
+        symbols.as_ref(),
+        console::Handlers {
+            profile: &mut profile,
+            hostfs: &mut hostfs,
+            gdb: gdb.as_mut(),
+            channels: &mut channels,
+        },
+    )?;
+    if !profile.is_empty() {
+        profile.report(symbols.as_ref());
+        if let Some(path) = &args.folded {
+            profile.write_folded(path, symbols.as_ref())?;
+        }
+    }
+    if let Some(core) = &finished.core {
+        core.write(&args.core)?;
+        println!(
+            "[core dump written to {}, open it with gdb {} {0}]",
+            args.core.display(),
+            args.program.display()
+        );
+    }
+    if let Some(status) = finished.status {
+        // Codes that don't fit would wrap around, possibly to success.
+        std::process::exit(u8::try_from(status.code).unwrap_or(u8::MAX).into());
+    }
+    Ok(())
+}
 
-    let mut last_chars = VecDeque::new();
-    loop {
-        let c = uart.getu8(Duration::from_secs(300))?;
-        print!("{}", c as char);
-        std::io::stdout().flush()?;
-        last_chars.push_back(c as char);
-        if last_chars.len() > "DONE!!!".len() {
-            last_chars.pop_front();
+/// Print how loading `image` goes.
+fn show_progress(progress: Progress, image: &Image) {
+    match progress {
+        Progress::Listening => println!("listening for prog info req"),
+        Progress::Event(Event::ProgInfoRequested) => println!("got prog info request"),
+        Progress::Event(Event::SendingCode) => println!(
+            "matched checksum, sending program: {} KB",
+            image.wire_length() / 1_000
+        ),
+        Progress::Event(Event::Resending { block }) => println!("resending block {block}"),
+        Progress::Event(Event::Done) if image.is_update() => {
+            println!("sent bootloader, the Pi is writing it to its SD card\n")
+        }
+        Progress::Event(Event::Done) => println!("successfully loaded, running program\n"),
+        Progress::Event(Event::Baud { baud }) => println!("loading at {baud} baud"),
+        Progress::Event(Event::BaudUnsupported { baud }) => {
+            println!("the Pi can't run at {baud} baud, staying at {DEFAULT_BAUD}")
         }
-        if last_chars.iter().cloned().eq("DONE!!!".chars()) {
-            break;
+        Progress::Event(Event::None) => {}
+        Progress::TryingBaud { baud } => println!("trying {baud} baud"),
+        Progress::FellBack { baud } => {
+            println!("{baud} baud didn't work, falling back to {DEFAULT_BAUD}")
         }
     }
-    println!("");
-    return Ok(());
+}
+
+/// Read a key file of hex digits, as made with `openssl rand -hex 32`.
+fn read_key(path: &std::path::Path) -> Result<Key, eyre::Report> {
+    let hex =
+        std::fs::read_to_string(path).with_context(|| format!("reading key {}", path.display()))?;
+    Key::from_hex(&hex).ok_or_else(|| eyre!("{} must hold a key of 64 hex digits", path.display()))
 }
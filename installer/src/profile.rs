@@ -0,0 +1,92 @@
+//! Reports where a profiled program spent its time, from the program counter
+//! samples it sends.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::Path,
+};
+
+use bootloader_shared::message::profile_samples;
+use eyre::Context;
+
+use crate::symbols::Symbols;
+
+/// Hot spots listed in each part of the report.
+const TOP: usize = 20;
+
+#[derive(Default)]
+pub struct Profile {
+    /// Samples per address.
+    counts: BTreeMap<u32, u64>,
+}
+
+impl Profile {
+    /// Add the samples of a [bootloader_shared::message::Message::ProfileSamples].
+    pub fn add(&mut self, samples: &[u8]) {
+        for (address, count) in profile_samples(samples) {
+            *self.counts.entry(address).or_default() += u64::from(count);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Print the functions and lines with the most samples.
+    pub fn report(&self, symbols: Option<&Symbols>) {
+        let total = self.counts.values().sum::<u64>();
+        println!(
+            "\nprofile: {total} samples at {} addresses",
+            self.counts.len()
+        );
+        let functions = self.group(|address| {
+            let (name, _) = symbols?.function(address)?;
+            Some(name.to_string())
+        });
+        print_top("functions", &functions, total);
+        let lines = self.group(|address| symbols?.line(address));
+        print_top("lines", &lines, total);
+    }
+
+    /// Write the samples as folded stacks, as read by flamegraph tools. Only
+    /// the program counter is sampled, so the stacks are the functions inlined
+    /// at each address.
+    pub fn write_folded(&self, path: &Path, symbols: Option<&Symbols>) -> Result<(), eyre::Report> {
+        let stacks = self.group(|address| {
+            let symbols = symbols?;
+            let mut frames = symbols.inlined(address);
+            if frames.is_empty() {
+                frames.push(symbols.function(address)?.0.to_string());
+            }
+            Some(frames.join(";"))
+        });
+        let mut folded = String::new();
+        for (stack, count) in stacks {
+            writeln!(folded, "{stack} {count}")?;
+        }
+        std::fs::write(path, folded).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Sum the samples by `key`, or by address where it has none, most first.
+    fn group(&self, key: impl Fn(u32) -> Option<String>) -> Vec<(String, u64)> {
+        let mut groups = HashMap::<_, u64>::new();
+        for (&address, &count) in &self.counts {
+            let key = key(address).unwrap_or_else(|| format!("{address:#010x}"));
+            *groups.entry(key).or_default() += count;
+        }
+        let mut groups = groups.into_iter().collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        groups
+    }
+}
+
+fn print_top(kind: &str, groups: &[(String, u64)], total: u64) {
+    println!("\nhottest {kind}:");
+    for (name, count) in groups.iter().take(TOP) {
+        let percent = 100.0 * *count as f64 / total as f64;
+        println!("{percent:>7.2}% {count:>8}  {name}");
+    }
+    if groups.len() > TOP {
+        println!("    ... and {} more", groups.len() - TOP);
+    }
+}
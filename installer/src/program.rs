@@ -0,0 +1,102 @@
+//! Programs to install, read from an ELF or a flat binary.
+use std::{fs::File, io::Read, path::Path};
+
+use bootloader_shared::{
+    boot_info::{Blob, BLOB_NAME_LENGTH},
+    installer::Image,
+    Segment, BASE,
+};
+use eyre::{eyre, Context};
+use object::{
+    elf::{FileHeader32, PT_LOAD},
+    read::elf::{FileHeader, ProgramHeader},
+    Endianness,
+};
+
+pub struct Program {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    /// Contents of the segments back to back, followed by those of the
+    /// blobs.
+    pub code: Vec<u8>,
+    /// Sent along with the program, see [bootloader_shared::boot_info].
+    pub blobs: Vec<Blob>,
+}
+
+impl Program {
+    /// Read an ELF, or a flat binary to be loaded and started at [BASE].
+    pub fn read(path: &Path) -> Result<Self, eyre::Report> {
+        let mut data = Vec::new();
+        File::open(path)
+            .with_context(|| format!("opening {}", path.display()))?
+            .read_to_end(&mut data)
+            .context("reading program")?;
+        if !data.starts_with(b"\x7fELF") {
+            let length = data.len() as u32;
+            return Ok(Self {
+                entry: BASE,
+                segments: vec![Segment {
+                    address: BASE,
+                    length,
+                    memory_length: length,
+                }],
+                code: data,
+                blobs: Vec::new(),
+            });
+        }
+        Self::parse_elf(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Collect the loadable segments, at their physical addresses.
+    fn parse_elf(data: &[u8]) -> Result<Self, eyre::Report> {
+        let header = FileHeader32::<Endianness>::parse(data)?;
+        let endian = header.endian()?;
+        let mut segments = Vec::new();
+        let mut code = Vec::new();
+        for segment in header.program_headers(endian, data)? {
+            if segment.p_type(endian) != PT_LOAD || segment.p_memsz(endian) == 0 {
+                continue;
+            }
+            let contents = segment
+                .data(endian, data)
+                .map_err(|()| eyre!("segment data out of bounds"))?;
+            segments.push(Segment {
+                address: segment.p_paddr(endian),
+                length: contents.len() as u32,
+                memory_length: segment.p_memsz(endian),
+            });
+            code.extend_from_slice(contents);
+        }
+        Ok(Self {
+            entry: header.e_entry(endian),
+            segments,
+            code,
+            blobs: Vec::new(),
+        })
+    }
+
+    /// Send `data` along as a blob called `name`, at `address` or wherever the
+    /// bootloader places it.
+    pub fn add_blob(
+        &mut self,
+        name: &str,
+        address: Option<u32>,
+        data: &[u8],
+    ) -> Result<(), eyre::Report> {
+        let blob = Blob::new(name, address, data.len() as u32).ok_or_else(|| {
+            eyre!("blob name {name:?} must be 1 to {BLOB_NAME_LENGTH} bytes, without NUL")
+        })?;
+        self.blobs.push(blob);
+        self.code.extend_from_slice(data);
+        Ok(())
+    }
+
+    pub fn image(&self) -> Result<Image<'_>, eyre::Report> {
+        let length = self.segments.iter().map(|s| s.length as usize).sum();
+        let image = Image::new(self.entry, &self.segments, &self.code[..length])?;
+        if self.blobs.is_empty() {
+            return Ok(image);
+        }
+        Ok(image.with_blobs(&self.blobs, &self.code)?)
+    }
+}
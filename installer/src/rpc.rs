@@ -0,0 +1,158 @@
+//! Calls functions the program registered with `pi0_lib::rpc`, see
+//! [bootloader_shared::rpc].
+//!
+//! What the program prints meanwhile is shown, but other messages are ignored
+//! until the console or [Session](crate::Session) takes over.
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use bootloader_shared::{
+    channel::{Channel, MAX_CHANNEL_FRAME},
+    message::{Message, Scanner},
+    rpc::Rpc,
+};
+use eyre::{bail, eyre, Context};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{channels::Channels, transport::Transport};
+
+/// Give up if the program doesn't serve calls, or answer one, for this long.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Client<'a> {
+    uart: &'a mut dyn Transport,
+    channels: &'a mut Channels,
+    /// Where what the program prints is shown.
+    shown: &'a mut dyn Write,
+    scanner: Scanner,
+    /// Output read but not looked at yet.
+    unread: Vec<u8>,
+    next_id: u32,
+}
+
+impl<'a> Client<'a> {
+    /// Wait for the program to serve calls. `output` is what it printed while
+    /// the installer finished loading it, and what it prints from then on is
+    /// written to `shown`.
+    pub fn connect(
+        uart: &'a mut dyn Transport,
+        output: &[u8],
+        channels: &'a mut Channels,
+        shown: &'a mut dyn Write,
+    ) -> Result<Self, eyre::Report> {
+        channels.keep(Channel::Control);
+        let mut client = Self {
+            uart,
+            channels,
+            shown,
+            scanner: Scanner::new(),
+            unread: output.to_vec(),
+            next_id: 0,
+        };
+        while Rpc::decode(&client.receive()?) != Some(Rpc::Ready) {}
+        Ok(client)
+    }
+
+    /// Call the function called `name` with `args`, as a tuple if it takes
+    /// several.
+    pub fn call<A: Serialize, R: DeserializeOwned>(
+        &mut self,
+        name: &str,
+        args: &A,
+    ) -> Result<R, eyre::Report> {
+        let args = postcard::to_allocvec(args)
+            .with_context(|| format!("encoding the arguments to {name}"))?;
+        let result = self.call_raw(name, &args)?;
+        postcard::from_bytes(&result).with_context(|| format!("decoding the result of {name}"))
+    }
+
+    /// Call the function called `name` with `args` in postcard, returning
+    /// its result in postcard.
+    pub fn call_raw(&mut self, name: &str, args: &[u8]) -> Result<Vec<u8>, eyre::Report> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(Rpc::Call { id, name, args })?;
+        loop {
+            match Rpc::decode(&self.receive()?) {
+                Some(Rpc::Return {
+                    id: returned,
+                    result,
+                }) if returned == id => {
+                    return result
+                        .map(<[u8]>::to_vec)
+                        .map_err(|e| eyre!("calling {name}: {e}"))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Let the program carry on, returning the output not shown yet for the
+    /// console.
+    pub fn finish(mut self) -> Result<Vec<u8>, eyre::Report> {
+        self.send(Rpc::Done)?;
+        self.channels.stop_keeping(Channel::Control);
+        let mut output = self.scanner.pending().to_vec();
+        output.append(&mut self.unread);
+        Ok(output)
+    }
+
+    fn send(&mut self, rpc: Rpc) -> Result<(), eyre::Report> {
+        let mut buf = [0; MAX_CHANNEL_FRAME];
+        let frame = rpc
+            .encode(&mut buf)
+            .ok_or_else(|| eyre!("{rpc:?} is too long to send"))?;
+        self.uart.put_bytes(frame)
+    }
+
+    /// Read until the program sends a message on [Channel::Control],
+    /// returning its data.
+    fn receive(&mut self) -> Result<Vec<u8>, eyre::Report> {
+        let mut buf = [0; 256];
+        let start = Instant::now();
+        loop {
+            if self.unread.is_empty() {
+                let n = self.uart.read(&mut buf)?;
+                if n == 0 {
+                    if start.elapsed() > TIMEOUT {
+                        bail!(
+                            "the program didn't answer for {} seconds, is it serving calls?",
+                            TIMEOUT.as_secs()
+                        );
+                    }
+                    continue;
+                }
+                self.unread.extend_from_slice(&buf[..n]);
+            }
+            let mut used = 0;
+            let mut received = None;
+            for &byte in &self.unread {
+                used += 1;
+                let (printed, message) = self.scanner.push(byte);
+                for &byte in printed {
+                    self.shown.write_all(self.channels.push(byte)?)?;
+                }
+                if let Some(Message::Exit(status)) = message {
+                    if status.panicked {
+                        bail!("the program panicked before answering");
+                    }
+                    bail!(
+                        "the program exited with code {} before answering",
+                        status.code
+                    );
+                }
+                received = self.channels.take(Channel::Control);
+                if received.is_some() {
+                    break;
+                }
+            }
+            self.unread.drain(..used);
+            self.shown.flush()?;
+            if let Some(data) = received {
+                return Ok(data);
+            }
+        }
+    }
+}
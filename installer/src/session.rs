@@ -0,0 +1,231 @@
+//! Drives a loaded program from code, as integration tests do, rather than
+//! from a terminal as the [console](crate::console) does.
+//!
+//! Output goes to the [Session::on_output] callback as it arrives and is kept
+//! for [Session::expect]. Channels are picked out as by the console and
+//! requests for host files are refused unless [Session::with_hostfs] serves a
+//! directory. Other messages, as from the profiler or a GDB stub, are ignored.
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bootloader_shared::{
+    channel::Channel,
+    installer::Image,
+    message::{ExitStatus, Message, Scanner},
+};
+use eyre::bail;
+
+use crate::{
+    channels::Channels,
+    hostfs::HostFs,
+    rpc::Client,
+    transport::{self, Transport},
+    Progress,
+};
+
+type OutputCallback = Box<dyn FnMut(&[u8])>;
+type ProgressCallback = Box<dyn FnMut(Progress)>;
+
+pub struct Session {
+    uart: Box<dyn Transport>,
+    channels: Channels,
+    hostfs: HostFs,
+    scanner: Scanner,
+    /// Output read but not looked at yet.
+    unread: Vec<u8>,
+    /// What the program printed since the last match of [Session::expect].
+    output: Vec<u8>,
+    on_output: Option<OutputCallback>,
+    on_progress: Option<ProgressCallback>,
+    /// The program's exit status, once it sent one.
+    status: Option<ExitStatus>,
+}
+
+impl Session {
+    /// Open a transport from a device description, see [transport::open].
+    pub fn open(device: &str) -> Result<Self, eyre::Report> {
+        Self::new(transport::open(device)?)
+    }
+
+    pub fn new(uart: Box<dyn Transport>) -> Result<Self, eyre::Report> {
+        Ok(Self {
+            uart,
+            channels: Channels::new(&[])?,
+            hostfs: HostFs::new(None)?,
+            scanner: Scanner::new(),
+            unread: Vec::new(),
+            output: Vec::new(),
+            on_output: None,
+            on_progress: None,
+            status: None,
+        })
+    }
+
+    /// Serve the files under `root` to the program.
+    pub fn with_hostfs(mut self, root: &Path) -> Result<Self, eyre::Report> {
+        self.hostfs = HostFs::new(Some(root))?;
+        Ok(self)
+    }
+
+    /// Save channels to files instead of showing or dropping them.
+    pub fn with_channels(
+        mut self,
+        subscriptions: &[(Channel, PathBuf)],
+    ) -> Result<Self, eyre::Report> {
+        self.channels = Channels::new(subscriptions)?;
+        Ok(self)
+    }
+
+    /// Call `callback` with what the program prints, as it arrives.
+    pub fn on_output(mut self, callback: impl FnMut(&[u8]) + 'static) -> Self {
+        self.on_output = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` with how each [Session::load] goes.
+    pub fn on_progress(mut self, callback: impl FnMut(Progress) + 'static) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Load `image`, at `baud` if the Pi manages it, and start following
+    /// the program.
+    pub fn load(&mut self, image: Image, baud: u32) -> Result<(), eyre::Report> {
+        let on_progress = &mut self.on_progress;
+        let output = crate::transmit(self.uart.as_mut(), image, baud, &mut |progress| {
+            if let Some(on_progress) = on_progress {
+                on_progress(progress);
+            }
+        })?;
+        self.scanner = Scanner::new();
+        self.unread = output;
+        self.output.clear();
+        self.status = None;
+        Ok(())
+    }
+
+    /// Send `input` to the program, as typed on the console.
+    pub fn send(&mut self, input: &[u8]) -> Result<(), eyre::Report> {
+        self.uart.put_bytes(input)
+    }
+
+    /// What the program printed that [Session::expect] hasn't matched yet.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The program's exit status, if it sent one.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status
+    }
+
+    /// Wait until the program prints `pattern`, returning what it printed
+    /// up to the end of it. Later calls only look at what follows.
+    pub fn expect(&mut self, pattern: &str, timeout: Duration) -> Result<String, eyre::Report> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let found = match pattern.len() {
+                0 => Some(0),
+                n => self.output.windows(n).position(|w| w == pattern.as_bytes()),
+            };
+            if let Some(at) = found {
+                let printed: Vec<_> = self.output.drain(..at + pattern.len()).collect();
+                return Ok(String::from_utf8_lossy(&printed).into_owned());
+            }
+            if let Some(status) = self.status {
+                bail!(
+                    "the program {} before printing {pattern:?}",
+                    describe(status)
+                );
+            }
+            if Instant::now() > deadline {
+                bail!(
+                    "the program didn't print {pattern:?} within {:.1} seconds",
+                    timeout.as_secs_f64()
+                );
+            }
+            self.poll()?;
+        }
+    }
+
+    /// Wait for the program to exit, returning its status.
+    pub fn wait_exit(&mut self, timeout: Duration) -> Result<ExitStatus, eyre::Report> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.status {
+                return Ok(status);
+            }
+            if Instant::now() > deadline {
+                bail!(
+                    "the program didn't exit within {:.1} seconds",
+                    timeout.as_secs_f64()
+                );
+            }
+            self.poll()?;
+        }
+    }
+
+    /// Call functions the program registered with `pi0_lib::rpc`, once it
+    /// serves calls, then let it carry on.
+    pub fn calls<T>(
+        &mut self,
+        calls: impl FnOnce(&mut Client) -> Result<T, eyre::Report>,
+    ) -> Result<T, eyre::Report> {
+        // The client scans the output afresh.
+        let mut output = self.scanner.pending().to_vec();
+        output.append(&mut self.unread);
+        self.scanner = Scanner::new();
+        let mut shown = Vec::new();
+        let mut client =
+            Client::connect(self.uart.as_mut(), &output, &mut self.channels, &mut shown)?;
+        let result = calls(&mut client);
+        let rest = client.finish();
+        self.show(&shown);
+        self.unread = rest?;
+        result
+    }
+
+    /// Read what arrives within the transport's poll interval and follow it.
+    fn poll(&mut self) -> Result<(), eyre::Report> {
+        if self.unread.is_empty() {
+            let mut buf = [0; 256];
+            let n = self.uart.read(&mut buf)?;
+            self.unread.extend_from_slice(&buf[..n]);
+        }
+        let mut shown = Vec::new();
+        for byte in std::mem::take(&mut self.unread) {
+            let (printed, message) = self.scanner.push(byte);
+            for &byte in printed {
+                shown.extend_from_slice(self.channels.push(byte)?);
+            }
+            if let Some(reply) = message.as_ref().and_then(|m| self.hostfs.answer(m)) {
+                self.uart.put_bytes(&reply)?;
+            }
+            if let Some(Message::Exit(status)) = message {
+                self.status = Some(status);
+            }
+        }
+        self.show(&shown);
+        Ok(())
+    }
+
+    fn show(&mut self, shown: &[u8]) {
+        if shown.is_empty() {
+            return;
+        }
+        if let Some(on_output) = &mut self.on_output {
+            on_output(shown);
+        }
+        self.output.extend_from_slice(shown);
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    if status.panicked {
+        "panicked".to_string()
+    } else {
+        format!("exited with code {}", status.code)
+    }
+}
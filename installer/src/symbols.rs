@@ -0,0 +1,199 @@
+//! Resolves code addresses in program output to functions and source lines.
+//!
+//! Hex values like `pc=0x00008123` are followed by `<function+offset
+//! (file:line)>` when they point into a function of the program's ELF.
+use std::{borrow::Cow, fs, path::Path, rc::Rc};
+
+use addr2line::Context;
+use eyre::Context as _;
+use gimli::{EndianRcSlice, RunTimeEndian};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+/// Longest hex value that can be an address, without the `0x`.
+const MAX_DIGITS: usize = 8;
+
+struct Function {
+    address: u32,
+    size: u32,
+    name: String,
+}
+
+pub struct Symbols {
+    /// Sorted by address.
+    functions: Vec<Function>,
+    lines: Context<EndianRcSlice<RunTimeEndian>>,
+}
+
+impl Symbols {
+    /// Read the symbols of an ELF, or `None` for any other file.
+    pub fn read(path: &Path) -> Result<Option<Self>, eyre::Report> {
+        let data = fs::read(path).with_context(|| format!("opening {}", path.display()))?;
+        if !data.starts_with(b"\x7fELF") {
+            return Ok(None);
+        }
+        Self::parse(&data)
+            .map(Some)
+            .with_context(|| format!("reading symbols from {}", path.display()))
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, eyre::Report> {
+        let file = object::File::parse(data)?;
+        let mut functions = file
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+            .filter_map(|symbol| {
+                Some(Function {
+                    // Clear the Thumb bit.
+                    address: symbol.address() as u32 & !1,
+                    size: symbol.size() as u32,
+                    name: format!("{:#}", rustc_demangle::demangle(symbol.name().ok()?)),
+                })
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|function| function.address);
+
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, eyre::Report> {
+            let data = match file.section_by_name(id.name()) {
+                Some(section) => section.uncompressed_data()?,
+                None => Cow::Borrowed(&[][..]),
+            };
+            Ok(EndianRcSlice::new(Rc::from(&*data), endian))
+        })?;
+        Ok(Self {
+            functions,
+            lines: Context::from_dwarf(dwarf)?,
+        })
+    }
+
+    /// `function+offset (file:line)` for an address inside a function.
+    pub fn describe(&self, address: u32) -> Option<String> {
+        let (name, offset) = self.function(address)?;
+        let mut description = format!("{name}+{offset:#x}");
+        if let Some(line) = self.line(address) {
+            description += &format!(" ({line})");
+        }
+        Some(description)
+    }
+
+    /// The function containing an address, and the offset into it.
+    pub fn function(&self, address: u32) -> Option<(&str, u32)> {
+        let index = self
+            .functions
+            .partition_point(|function| function.address <= address)
+            .checked_sub(1)?;
+        let function = &self.functions[index];
+        let offset = address - function.address;
+        (offset < function.size).then_some((&function.name, offset))
+    }
+
+    /// `file:line` of the code at an address.
+    pub fn line(&self, address: u32) -> Option<String> {
+        let location = self.lines.find_location(address.into()).ok()??;
+        Some(format!("{}:{}", shorten(location.file?), location.line?))
+    }
+
+    /// Names of the functions inlined at an address, outermost first.
+    pub fn inlined(&self, address: u32) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Ok(mut frames) = self.lines.find_frames(address.into()).skip_all_loads() {
+            while let Ok(Some(frame)) = frames.next() {
+                if let Some(Ok(name)) = frame.function.as_ref().map(|name| name.raw_name()) {
+                    names.push(format!("{:#}", rustc_demangle::demangle(&name)));
+                }
+            }
+        }
+        names.reverse();
+        names
+    }
+
+    /// Annotate all addresses in `text`.
+    pub fn annotate(&self, text: &[u8]) -> Vec<u8> {
+        let mut annotator = Annotator::new(self);
+        let mut annotated = Vec::with_capacity(text.len());
+        for &byte in text {
+            annotator.push(byte, &mut annotated);
+        }
+        annotator.flush(&mut annotated);
+        annotated
+    }
+}
+
+/// Paths relative to the current directory, if they are inside it.
+fn shorten(file: &str) -> &str {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| Path::new(file).strip_prefix(dir).ok()?.to_str())
+        .and_then(|relative| file.get(file.len() - relative.len()..))
+        .unwrap_or(file)
+}
+
+/// Annotates addresses in output as it streams by, holding back hex values
+/// until they end.
+pub struct Annotator<'a> {
+    symbols: &'a Symbols,
+    /// The hex value so far, starting with `0`.
+    token: Vec<u8>,
+    /// The byte before the token.
+    previous: u8,
+}
+
+impl<'a> Annotator<'a> {
+    pub fn new(symbols: &'a Symbols) -> Self {
+        Self {
+            symbols,
+            token: Vec::new(),
+            previous: b' ',
+        }
+    }
+
+    /// Add a byte of output, appending what can be printed to `out`.
+    pub fn push(&mut self, byte: u8, out: &mut Vec<u8>) {
+        let continues = match self.token.len() {
+            0 => false,
+            1 => byte == b'x' || byte == b'X',
+            n => byte.is_ascii_hexdigit() && n < 2 + MAX_DIGITS,
+        };
+        if continues {
+            self.token.push(byte);
+            return;
+        }
+        // A hex digit after the longest address means it isn't one.
+        let too_long = self.token.len() == 2 + MAX_DIGITS && byte.is_ascii_hexdigit();
+        if too_long {
+            self.previous = byte;
+            out.append(&mut self.token);
+        } else {
+            self.flush(out);
+        }
+        if byte == b'0' && !is_word(self.previous) {
+            self.token.push(byte);
+        } else {
+            out.push(byte);
+            self.previous = byte;
+        }
+    }
+
+    /// Print the held back value, annotated if it is an address.
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        if self.token.is_empty() {
+            return;
+        }
+        let address = std::str::from_utf8(&self.token[self.token.len().min(2)..])
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        out.append(&mut self.token);
+        self.previous = *out.last().unwrap();
+        if let Some(description) = address.and_then(|address| self.symbols.describe(address)) {
+            out.extend_from_slice(format!(" <{description}>").as_bytes());
+        }
+    }
+}
+
+fn is_word(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
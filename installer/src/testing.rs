@@ -0,0 +1,320 @@
+//! Runs a program built with `pi0_lib::testing`, loading it again whenever a
+//! test fails and the Pi goes back to the bootloader, and reports the results.
+use std::{
+    fmt::Write as _,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use bootloader_shared::{
+    installer::Image,
+    is_pi_get_prog_info_byte,
+    message::{Message, Scanner},
+    INSTALLER_RUN_TESTS, PI_GET_PROG_INFO,
+};
+use eyre::{bail, Context};
+
+use crate::{channels::Channels, hostfs::HostFs, symbols::Symbols, transport::Transport, Progress};
+
+/// Give up after this long without output, well past the Pi's own timeout.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Panicked,
+    /// The watchdog rebooted the Pi.
+    TimedOut,
+    /// The test stopped the program.
+    Exited {
+        code: u32,
+    },
+}
+
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    /// What the test printed.
+    pub output: Vec<u8>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+/// The test that is running.
+struct Running {
+    name: String,
+    start: Instant,
+    output: Vec<u8>,
+}
+
+impl Running {
+    fn finish(self, outcome: Outcome, duration: Option<Duration>) -> TestResult {
+        let result = TestResult {
+            name: self.name,
+            outcome,
+            duration: duration.unwrap_or_else(|| self.start.elapsed()),
+            output: self.output,
+        };
+        let status = match result.outcome {
+            Outcome::Passed => "ok".to_string(),
+            Outcome::Panicked => "FAILED (panicked)".to_string(),
+            Outcome::TimedOut => "FAILED (timed out)".to_string(),
+            Outcome::Exited { code } => format!("FAILED (exited with code {code})"),
+        };
+        println!(
+            "test {} ... {status} in {:.3}s",
+            result.name,
+            result.duration.as_secs_f64()
+        );
+        result
+    }
+}
+
+/// How a run of the program ended.
+enum Run {
+    Finished,
+    /// The program went back to the bootloader after a failed test, or the
+    /// watchdog rebooted the Pi, and it wants the program again.
+    Rebooted,
+}
+
+/// Run all tests, loading the program as often as needed, at `baud` if the Pi
+/// manages it. Addresses in the output of tests are annotated with `symbols`,
+/// if any, requests for host files are answered by `hostfs` and `channels`
+/// picks out the output to show. How each load goes is passed to `progress`.
+pub fn run(
+    uart: &mut dyn Transport,
+    image: Image,
+    baud: u32,
+    symbols: Option<&Symbols>,
+    hostfs: &mut HostFs,
+    channels: &mut Channels,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Vec<TestResult>, eyre::Report> {
+    let mut results = Vec::new();
+    let mut count = None;
+    loop {
+        let output = crate::transmit(uart, image, baud, progress)?;
+        match run_once(uart, &output, &mut results, &mut count, hostfs, channels)? {
+            Run::Rebooted if count != Some(results.len()) => {
+                println!("[loading the program again to continue]");
+            }
+            Run::Finished | Run::Rebooted => break,
+        }
+    }
+    if let Some(symbols) = symbols {
+        for result in &mut results {
+            result.output = symbols.annotate(&result.output);
+        }
+    }
+    Ok(results)
+}
+
+/// Follow one run of the loaded program, starting after the tests that already
+/// have `results`.
+fn run_once(
+    uart: &mut dyn Transport,
+    output: &[u8],
+    results: &mut Vec<TestResult>,
+    count: &mut Option<usize>,
+    hostfs: &mut HostFs,
+    channels: &mut Channels,
+) -> Result<Run, eyre::Report> {
+    let mut stdout = std::io::stdout();
+    let mut scanner = Scanner::new();
+    let mut running: Option<Running> = None;
+    // The last four bytes, to spot the bootloader asking for a program again.
+    let mut last = 0;
+    let mut buf = [0; 256];
+    let mut output = output;
+    let mut last_output = Instant::now();
+    loop {
+        if output.is_empty() {
+            let n = uart.read(&mut buf)?;
+            if n == 0 {
+                if last_output.elapsed() > IDLE_TIMEOUT {
+                    bail!(
+                        "no output for {} seconds, giving up",
+                        IDLE_TIMEOUT.as_secs()
+                    );
+                }
+                continue;
+            }
+            output = &buf[..n];
+        }
+        last_output = Instant::now();
+        for &byte in output {
+            let (printed, message) = scanner.push(byte);
+            for &byte in printed {
+                let shown = channels.push(byte)?;
+                match &mut running {
+                    Some(running) => running.output.extend_from_slice(shown),
+                    None => stdout.write_all(shown)?,
+                }
+            }
+            for &c in printed {
+                last = (last >> 8) + ((c as u32) << 24);
+            }
+            if last == PI_GET_PROG_INFO {
+                let Some(mut running) = running.take() else {
+                    bail!("the Pi rebooted outside of a test");
+                };
+                while running
+                    .output
+                    .pop_if(|&mut c| is_pi_get_prog_info_byte(c))
+                    .is_some()
+                {}
+                results.push(running.finish(Outcome::TimedOut, None));
+                return Ok(Run::Rebooted);
+            }
+            match message {
+                None
+                | Some(
+                    Message::ProfileSamples { .. }
+                    | Message::Gdb { .. }
+                    | Message::CoreRegisters { .. }
+                    | Message::CoreRegion { .. }
+                    | Message::CoreMemory { .. }
+                    | Message::CoreEnd,
+                ) => {}
+                Some(
+                    request @ (Message::HostOpen { .. }
+                    | Message::HostRead { .. }
+                    | Message::HostWrite { .. }
+                    | Message::HostClose { .. }),
+                ) => {
+                    if let Some(reply) = hostfs.answer(&request) {
+                        uart.put_bytes(&reply)?;
+                    }
+                }
+                Some(Message::Tests { count: total }) => {
+                    if results.is_empty() {
+                        println!("running {total} tests");
+                    }
+                    *count = Some(total as usize);
+                    uart.put_bytes(&INSTALLER_RUN_TESTS.to_le_bytes())?;
+                    uart.put_bytes(&(results.len() as u32).to_le_bytes())?;
+                }
+                Some(Message::TestStarted { index, name }) => {
+                    if index as usize != results.len() {
+                        bail!("expected test {} to run but got {index}", results.len());
+                    }
+                    running = Some(Running {
+                        name: name.to_string(),
+                        start: Instant::now(),
+                        output: Vec::new(),
+                    });
+                }
+                Some(Message::TestPassed { micros, .. }) => {
+                    let Some(running) = running.take() else {
+                        bail!("a test passed without starting");
+                    };
+                    let duration = Duration::from_micros(micros.into());
+                    results.push(running.finish(Outcome::Passed, Some(duration)));
+                }
+                Some(Message::Exit(status)) => {
+                    let Some(running) = running.take() else {
+                        if status.panicked {
+                            bail!("the program panicked outside of a test");
+                        }
+                        return Ok(Run::Finished);
+                    };
+                    let outcome = if status.panicked {
+                        Outcome::Panicked
+                    } else {
+                        Outcome::Exited { code: status.code }
+                    };
+                    results.push(running.finish(outcome, None));
+                    return Ok(Run::Rebooted);
+                }
+            }
+        }
+        stdout.flush()?;
+        output = &[];
+    }
+}
+
+/// Print the output of failed tests and the totals.
+pub fn summarize(results: &[TestResult]) {
+    let failed = results.iter().filter(|result| !result.passed());
+    for result in failed.clone() {
+        println!("\n---- {} output ----", result.name);
+        println!("{}", String::from_utf8_lossy(&result.output).trim_end());
+    }
+    let failed = failed.count();
+    println!(
+        "\ntest result: {}. {} passed; {failed} failed",
+        if failed == 0 { "ok" } else { "FAILED" },
+        results.len() - failed,
+    );
+}
+
+/// Write the results as a JUnit XML report.
+pub fn write_junit(path: &Path, results: &[TestResult]) -> Result<(), eyre::Report> {
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    let total = results
+        .iter()
+        .map(|result| result.duration)
+        .sum::<Duration>();
+    let mut xml = String::new();
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        xml,
+        r#"<testsuite name="pi" tests="{}" failures="{failed}" time="{:.6}">"#,
+        results.len(),
+        total.as_secs_f64()
+    )?;
+    for result in results {
+        let (classname, name) = result.name.rsplit_once("::").unwrap_or(("", &result.name));
+        write!(
+            xml,
+            r#"  <testcase classname="{}" name="{}" time="{:.6}""#,
+            escape(classname),
+            escape(name),
+            result.duration.as_secs_f64()
+        )?;
+        let output = escape(&String::from_utf8_lossy(&result.output));
+        let message = match result.outcome {
+            Outcome::Passed => {
+                writeln!(
+                    xml,
+                    ">\n    <system-out>{output}</system-out>\n  </testcase>"
+                )?;
+                continue;
+            }
+            Outcome::Panicked => "panicked".to_string(),
+            Outcome::TimedOut => "timed out".to_string(),
+            Outcome::Exited { code } => format!("exited with code {code}"),
+        };
+        writeln!(
+            xml,
+            ">\n    <failure message=\"{message}\">{output}</failure>\n  </testcase>"
+        )?;
+    }
+    writeln!(xml, "</testsuite>")?;
+    std::fs::write(path, xml).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Escape text for XML, dropping characters XML can't contain.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            _ if c < ' ' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
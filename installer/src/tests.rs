@@ -0,0 +1,183 @@
+//! Tests of the installer against scratch directories on the host, and of
+//! [Session] against a scripted program on a Unix socket.
+use std::{
+    io::{Read, Write},
+    os::unix::{
+        fs::symlink,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bootloader_shared::{
+    hostfs::{HostError, Mode, ReplyReader},
+    message::{ExitStatus, Message, MAX_FRAME_LENGTH},
+};
+
+use crate::{hostfs::HostFs, transport::SharedWriter, Session};
+
+/// An empty directory for `test` to work in.
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("installer-{}-{test}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A session with a program played by `script`, as if already loaded.
+fn session(test: &str, script: impl FnOnce(UnixStream) + Send + 'static) -> Session {
+    let path = scratch(test).join("pi.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    std::thread::spawn(move || script(listener.accept().unwrap().0));
+    Session::open(&format!("unix:{}", path.display())).unwrap()
+}
+
+fn exit(stream: &mut UnixStream, code: u32) {
+    let status = ExitStatus {
+        code,
+        panicked: false,
+    };
+    let frame = Message::Exit(status)
+        .encode(&mut [0; MAX_FRAME_LENGTH])
+        .to_vec();
+    stream.write_all(&frame).unwrap();
+}
+
+/// Takes one byte per write, as a slow device might.
+struct Trickle(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend(&buf[..1.min(buf.len())]);
+        std::thread::yield_now();
+        Ok(1.min(buf.len()))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What `hostfs` answers to `request`.
+fn answer(hostfs: &mut HostFs, request: Message) -> Result<u32, HostError> {
+    let reply = hostfs.answer(&request).unwrap();
+    let mut reader = ReplyReader::new();
+    let (last, rest) = reply.split_last().unwrap();
+    for &byte in rest {
+        assert!(reader.push(byte).is_none());
+    }
+    reader.push(*last).unwrap().result
+}
+
+#[test]
+fn refuses_symlinks_out_of_the_root() {
+    let dir = scratch("symlinks");
+    let root = dir.join("root");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(dir.join("secret"), "secret").unwrap();
+    std::fs::write(root.join("inside"), "inside").unwrap();
+    symlink(dir.join("secret"), root.join("existing")).unwrap();
+    symlink(dir.join("created"), root.join("dangling")).unwrap();
+    symlink("inside", root.join("link")).unwrap();
+    let mut hostfs = HostFs::new(Some(&root)).unwrap();
+
+    for (path, mode) in [
+        ("existing", Mode::Read),
+        ("existing", Mode::Write),
+        ("dangling", Mode::Write),
+        ("dangling", Mode::Append),
+        ("../secret", Mode::Read),
+    ] {
+        let result = answer(&mut hostfs, Message::HostOpen { mode, path });
+        assert_eq!(result, Err(HostError::OutsideRoot), "{path} {mode:?}");
+    }
+    assert!(!dir.join("created").exists());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("secret")).unwrap(),
+        "secret"
+    );
+
+    let result = answer(
+        &mut hostfs,
+        Message::HostOpen {
+            mode: Mode::Read,
+            path: "link",
+        },
+    );
+    assert!(result.is_ok(), "{result:?}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn shared_writes_arrive_whole() {
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let writer = SharedWriter::new(Box::new(Trickle(written.clone())));
+    let threads: Vec<_> = [b'a', b'b', b'c']
+        .into_iter()
+        .map(|c| {
+            let mut writer = writer.clone();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    writer.write_all(&[c; 16]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let written = written.lock().unwrap();
+    assert_eq!(written.len(), 3 * 20 * 16);
+    for chunk in written.chunks(16) {
+        assert!(chunk.iter().all(|&c| c == chunk[0]), "{chunk:?}");
+    }
+}
+
+#[test]
+fn session_follows_the_program_to_its_exit() {
+    let mut session = session("session-exit", |mut stream| {
+        stream.write_all(b"booting\nready> ").unwrap();
+        let mut line = [0; 3];
+        stream.read_exact(&mut line).unwrap();
+        stream.write_all(b"got ").unwrap();
+        stream.write_all(&line).unwrap();
+        exit(&mut stream, 3);
+        // Stay connected until the session is done.
+        let _ = stream.read(&mut [0]);
+    });
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        session.expect("ready> ", timeout).unwrap(),
+        "booting\nready> "
+    );
+    session.send(b"go\n").unwrap();
+    assert_eq!(session.expect("go\n", timeout).unwrap(), "got go\n");
+    let status = session.wait_exit(timeout).unwrap();
+    assert_eq!(
+        status,
+        ExitStatus {
+            code: 3,
+            panicked: false
+        }
+    );
+    assert_eq!(session.output(), b"");
+    let error = session.expect("more", timeout).unwrap_err().to_string();
+    assert!(error.contains("exited with code 3"), "{error}");
+}
+
+#[test]
+fn session_times_out() {
+    let mut session = session("session-timeout", |mut stream| {
+        stream.write_all(b"working").unwrap();
+        let _ = stream.read(&mut [0]);
+    });
+    let timeout = Duration::from_millis(300);
+    let error = session.expect("done", timeout).unwrap_err().to_string();
+    assert!(error.contains("didn't print \"done\""), "{error}");
+    let error = session.wait_exit(timeout).unwrap_err().to_string();
+    assert!(error.contains("didn't exit"), "{error}");
+    assert_eq!(session.output(), b"working");
+    assert_eq!(session.status(), None);
+}
@@ -0,0 +1,184 @@
+//! Byte transports the installer can talk to a bootloader over.
+//!
+//! Besides the usb-serial adapter, the other end can be a pseudo-terminal (as
+//! created by `qemu-system-arm -serial pty`), or a TCP or Unix socket (as
+//! created by `-serial tcp::4444,server` or
+//! `-serial unix:/tmp/pi.sock,server`).
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bootloader_shared::DEFAULT_BAUD;
+use eyre::Context;
+
+use crate::uart::{self, Uart};
+
+/// How long a single socket read blocks before reporting no data.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub trait Transport {
+    /// Read the bytes that are available, returning 0 if none arrived within
+    /// the backend's poll interval.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()>;
+    /// Another handle writing to the same device, for use from another thread.
+    fn writer(&self) -> std::io::Result<Box<dyn Write + Send>>;
+
+    fn put_bytes(&mut self, v: &[u8]) -> Result<(), eyre::Report> {
+        Ok(self.write_all(v)?)
+    }
+
+    /// Switch to `baud`, for backends that have a baud rate.
+    fn set_baud(&mut self, _baud: u32) -> Result<(), eyre::Report> {
+        Ok(())
+    }
+}
+
+/// A writer shared between threads, so that what each writes in one
+/// [Write::write_all] arrives in one piece rather than interleaved with the
+/// others. Clones write to the same device.
+#[derive(Clone)]
+pub struct SharedWriter {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl SharedWriter {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Box<dyn Write + Send>> {
+        // A thread that panicked mid-write can't leave more than a torn write.
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.lock().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+/// Open a transport from a device description:
+/// - `tcp:<host>:<port>` connects to a TCP socket.
+/// - `unix:<path>` connects to a Unix socket.
+/// - `pty:<path>` opens a pseudo-terminal.
+/// - anything else is the path of a serial device, run at [DEFAULT_BAUD].
+pub fn open(device: &str) -> Result<Box<dyn Transport>, eyre::Report> {
+    if let Some(address) = device.strip_prefix("tcp:") {
+        Ok(Box::new(Socket::tcp(address)?))
+    } else if let Some(path) = device.strip_prefix("unix:") {
+        Ok(Box::new(Socket::unix(Path::new(path))?))
+    } else if let Some(path) = device.strip_prefix("pty:") {
+        Ok(Box::new(Pty::open(Path::new(path))?))
+    } else {
+        Ok(Box::new(Uart::open(Path::new(device), DEFAULT_BAUD)?))
+    }
+}
+
+/// A pseudo-terminal, which has no baud rate.
+pub struct Pty {
+    file: File,
+}
+
+impl Pty {
+    pub fn open(path: &Path) -> Result<Self, eyre::Report> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        uart::configure(&file, None)?;
+        Ok(Self { file })
+    }
+}
+
+impl Transport for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buf)
+    }
+
+    fn writer(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.file.try_clone()?))
+    }
+}
+
+/// A stream socket, polled with a read timeout.
+pub struct Socket<S> {
+    stream: S,
+}
+
+/// Sockets that can be cloned into another handle.
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> std::io::Result<Self>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+impl Socket<TcpStream> {
+    pub fn tcp(address: &str) -> Result<Self, eyre::Report> {
+        let stream =
+            TcpStream::connect(address).with_context(|| format!("connecting to {address}"))?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Socket<UnixStream> {
+    pub fn unix(path: &Path) -> Result<Self, eyre::Report> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("connecting to {}", path.display()))?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Self { stream })
+    }
+}
+
+impl<S: Stream> Transport for Socket<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => Ok(n),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(buf)
+    }
+
+    fn writer(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.stream.try_clone()?))
+    }
+}
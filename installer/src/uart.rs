@@ -1,96 +1,168 @@
 use std::{
     fs::File,
-    io::{ErrorKind, Read, Write},
+    io::{Read, Write},
     os::fd::AsRawFd,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
 };
 
-use eyre::Context;
+use eyre::{eyre, Context};
+#[cfg(target_os = "linux")]
+use termios::os::linux::CRTSCTS;
+#[cfg(target_os = "macos")]
+use termios::os::macos::CRTSCTS;
 use termios::{
-    cfsetspeed, os::macos::CRTSCTS, tcsetattr, Termios, CLOCAL, CREAD, CS8, CSIZE, CSTOPB, ECHO,
-    ECHOE, ICANON, IGNBRK, ISIG, IXANY, IXOFF, IXON, OPOST, PARENB, TCSANOW, VMIN, VTIME,
+    cfsetspeed, speed_t, tcsetattr, Termios, CLOCAL, CREAD, CS8, CSIZE, CSTOPB, ECHO, ECHOE,
+    ICANON, IGNBRK, ISIG, IXANY, IXOFF, IXON, OPOST, PARENB, TCSADRAIN, TCSANOW, VMIN, VTIME,
 };
 
-const SPEED: u64 = 115_200 * 8;
-const TIMEOUT: u8 = 10;
+use crate::transport::Transport;
+
+/// How long a read waits for data, in tenths of a second.
+const READ_TIMEOUT: u8 = 1;
+
+/// Directories and file name prefixes of common USB-serial adapters (CP210x,
+/// FTDI, CH340), in order of preference.
+#[cfg(target_os = "linux")]
+pub const DEVICE_PATTERNS: &[(&str, &str)] = &[
+    ("/dev/serial/by-id", "usb-Silicon_Labs_CP210"),
+    ("/dev/serial/by-id", "usb-FTDI"),
+    ("/dev/serial/by-id", "usb-1a86"),
+    ("/dev", "ttyUSB"),
+];
+#[cfg(target_os = "macos")]
+pub const DEVICE_PATTERNS: &[(&str, &str)] = &[
+    ("/dev", "cu.SLAB_USBtoUART"),
+    ("/dev", "cu.usbserial"),
+    ("/dev", "cu.wchusbserial"),
+];
+
+/// Find the first serial device matching [DEVICE_PATTERNS].
+pub fn find_device() -> Result<PathBuf, eyre::Report> {
+    for (directory, prefix) in DEVICE_PATTERNS {
+        let Ok(entries) = std::fs::read_dir(directory) else {
+            continue;
+        };
+        let mut matches = entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with(prefix))
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        matches.sort();
+        if let Some(path) = matches.into_iter().next() {
+            return Ok(path);
+        }
+    }
+    Err(eyre!(
+        "no usb-serial device found (tried {}), pass one with --device",
+        DEVICE_PATTERNS
+            .iter()
+            .map(|(d, p)| format!("{d}/{p}*"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+/// Linux encodes speeds as flags rather than the baud rate itself.
+#[cfg(target_os = "linux")]
+fn speed(baud: u32) -> Result<speed_t, eyre::Report> {
+    use termios::os::linux::{
+        B1000000, B115200, B1152000, B1500000, B19200, B2000000, B230400, B38400, B460800, B500000,
+        B57600, B576000, B921600, B9600,
+    };
+    Ok(match baud {
+        9600 => B9600,
+        19200 => B19200,
+        38400 => B38400,
+        57600 => B57600,
+        115_200 => B115200,
+        230_400 => B230400,
+        460_800 => B460800,
+        500_000 => B500000,
+        576_000 => B576000,
+        921_600 => B921600,
+        1_000_000 => B1000000,
+        1_152_000 => B1152000,
+        1_500_000 => B1500000,
+        2_000_000 => B2000000,
+        _ => return Err(eyre!("unsupported baud rate {baud}")),
+    })
+}
+#[cfg(target_os = "macos")]
+fn speed(baud: u32) -> Result<speed_t, eyre::Report> {
+    Ok(baud as speed_t)
+}
 
 pub struct Uart {
     file: File,
 }
 
 impl Uart {
-    pub fn open() -> Result<Self, eyre::Report> {
+    pub fn open(path: &Path, baud: u32) -> Result<Self, eyre::Report> {
         let file = File::options()
             .read(true)
             .write(true)
-            .open("/dev/cu.SLAB_USBtoUART")
-            .context("missing usb file")?;
-        let fd = file.as_raw_fd();
-        let mut termios = Termios::from_fd(fd).unwrap();
-        cfsetspeed(&mut termios, SPEED).unwrap();
-        termios.c_iflag &= !IGNBRK; // disable break processing
-        termios.c_lflag = 0; // no signaling chars, no echo, no canonical processing
-        termios.c_oflag = 0; // no remapping, no delays
-        termios.c_cc[VMIN] = 0; // read doesn't block
-                                // VTIME is in .1 seconds, so have to multiply by 10.
-        termios.c_cc[VTIME] = TIMEOUT * 10; // this seems to cause issues?
-
-        // Setup 8n1 mode.
-        // Disables the Parity Enable bit(PARENB),So No Parity
-        termios.c_cflag &= !PARENB;
-        // CSTOPB = 2 Stop bits,here it is cleared so 1 Stop bit
-        termios.c_cflag &= !CSTOPB;
-        // Clears the mask for setting the data size
-        termios.c_cflag &= !CSIZE;
-        // Set the data bits = 8
-        termios.c_cflag |= CS8;
-        // No Hardware flow Control
-        termios.c_cflag &= !CRTSCTS;
-        // Enable receiver,Ignore Modem Control lines
-        termios.c_cflag |= CREAD | CLOCAL;
-
-        // Disable XON/XOFF flow control both i/p and o/p
-        termios.c_iflag &= !(IXON | IXOFF | IXANY);
-        // Non Cannonical mode
-        termios.c_iflag &= !(ICANON | ECHO | ECHOE | ISIG);
-        // No Output Processing
-        termios.c_oflag &= !OPOST;
-
-        tcsetattr(fd, TCSANOW, &termios).unwrap();
-
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        configure(&file, Some(baud))?;
         Ok(Self { file })
     }
+}
 
-    // TODO: get rid of timeout
-    pub fn getu8(&mut self, timeout: Duration) -> Result<u8, eyre::Report> {
-        let mut buf = [0u8; 1];
-        let start = Instant::now();
-        while self.file.read(&mut buf)? == 0 {
-            if Instant::now().duration_since(start) > timeout {
-                return Err(std::io::Error::new(
-                    ErrorKind::TimedOut,
-                    format!(
-                        "Timed out getting byte after {} seconds.",
-                        timeout.as_secs_f32()
-                    ),
-                )
-                .into());
-            }
-        }
-        Ok(buf[0])
+impl Transport for Uart {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
     }
 
-    pub fn get32(&mut self) -> Result<u32, eyre::Report> {
-        let mut buf = [0u8; 4];
-        self.file.read_exact(&mut buf)?;
-        Ok(u32::from_le_bytes(buf))
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buf)
     }
 
-    pub fn put_bytes(&mut self, v: &[u8]) -> Result<(), eyre::Report> {
-        Ok(self.file.write_all(v)?)
+    fn writer(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.file.try_clone()?))
     }
 
-    pub fn put32(&mut self, v: u32) -> Result<(), eyre::Report> {
-        Ok(self.file.write_all(&u32::to_le_bytes(v))?)
+    /// Switches once everything written so far has gone out.
+    fn set_baud(&mut self, baud: u32) -> Result<(), eyre::Report> {
+        let fd = self.file.as_raw_fd();
+        let mut termios = Termios::from_fd(fd).context("reading terminal attributes")?;
+        cfsetspeed(&mut termios, speed(baud)?).context("setting baud rate")?;
+        tcsetattr(fd, TCSADRAIN, &termios).context("setting terminal attributes")
     }
 }
+
+/// Put the terminal into raw 8n1 mode, setting the speed if given.
+pub fn configure(file: &File, baud: Option<u32>) -> Result<(), eyre::Report> {
+    let fd = file.as_raw_fd();
+    let mut termios = Termios::from_fd(fd).context("reading terminal attributes")?;
+    if let Some(baud) = baud {
+        cfsetspeed(&mut termios, speed(baud)?).context("setting baud rate")?;
+    }
+    termios.c_iflag &= !IGNBRK; // disable break processing
+    termios.c_lflag = 0; // no signaling chars, no echo, no canonical processing
+    termios.c_oflag = 0; // no remapping, no delays
+    termios.c_cc[VMIN] = 0; // read doesn't block
+    termios.c_cc[VTIME] = READ_TIMEOUT;
+
+    // Setup 8n1 mode.
+    // Disables the Parity Enable bit(PARENB),So No Parity
+    termios.c_cflag &= !PARENB;
+    // CSTOPB = 2 Stop bits,here it is cleared so 1 Stop bit
+    termios.c_cflag &= !CSTOPB;
+    // Clears the mask for setting the data size
+    termios.c_cflag &= !CSIZE;
+    // Set the data bits = 8
+    termios.c_cflag |= CS8;
+    // No Hardware flow Control
+    termios.c_cflag &= !CRTSCTS;
+    // Enable receiver,Ignore Modem Control lines
+    termios.c_cflag |= CREAD | CLOCAL;
+
+    // Disable XON/XOFF flow control both i/p and o/p
+    termios.c_iflag &= !(IXON | IXOFF | IXANY);
+    // Non Cannonical mode
+    termios.c_iflag &= !(ICANON | ECHO | ECHOE | ISIG);
+    // No Output Processing
+    termios.c_oflag &= !OPOST;
+
+    tcsetattr(fd, TCSANOW, &termios).context("setting terminal attributes")
+}
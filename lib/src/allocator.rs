@@ -32,6 +32,16 @@ unsafe impl GlobalAlloc for WrappedBumpAllocator {
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: alloc::Layout) {}
 }
 
+/// Bytes of the heap handed out so far, or all of it if the allocator is
+/// busy.
+pub(crate) fn used() -> usize {
+    // Safe as the allocator is only borrowed mutably while allocating.
+    let allocator = unsafe { &*core::ptr::addr_of!(ALLOCATOR) };
+    LazyCell::force(&allocator.0)
+        .try_borrow()
+        .map_or(ALLOCATED_AMOUNT, |allocator| allocator.used)
+}
+
 #[no_mangle]
 /// For some reoason this is required when using alloc.
 pub extern "C" fn __aeabi_unwind_cpp_pr0() {
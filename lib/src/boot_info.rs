@@ -0,0 +1,14 @@
+//! Blobs the bootloader loaded along with the program, see `install --blob`.
+use bootloader_shared::boot_info::{BootInfo, BOOT_INFO};
+
+/// The table the bootloader left, if it did.
+pub fn boot_info() -> Option<BootInfo> {
+    let info = unsafe { (BOOT_INFO as *const BootInfo).read_volatile() };
+    info.is_valid().then_some(info)
+}
+
+/// The contents of the blob called `name`.
+pub fn blob(name: &str) -> Option<&'static [u8]> {
+    let blob = *boot_info()?.blob(name)?;
+    Some(unsafe { core::slice::from_raw_parts(blob.address as *const u8, blob.length as usize) })
+}
@@ -183,6 +183,7 @@ pub use lockdown::*;
 cp_asm_get!(pub dfsr_get, p15, 0, c5, c0, 0);
 cp_asm_get!(pub far_get, p15, 0, c6, c0, 0);
 cp_asm_get!(pub ifar_get, p15, 0, c6, c0, 2);
+cp_asm_get!(pub ifsr_get, p15, 0, c5, c0, 1);
 
 cp_asm_set!(pub wvr0_set, p14, 0, c0, c0, 6);
 
@@ -198,6 +199,9 @@ bitfield! {
 }
 
 impl WCR0 {
+    pub fn read() -> Self {
+        Self(wcr0_get())
+    }
     pub fn write(v: Self) {
         wcr0_set(v.0)
     }
@@ -215,9 +219,10 @@ pub enum WatchpointStatus {
 pub fn get_watchpoint_status() -> WatchpointStatus {
     let wcr = WCR0(wcr0_get());
     match (wcr.get_enabled(), wcr.get_load_stores()) {
+        // Load/store access: 0b01 matches loads and 0b10 stores.
         (true, v) => WatchpointStatus::Enabled {
-            load: v >= 2,
-            store: (v & 1) == 1,
+            load: (v & 1) == 1,
+            store: v >= 2,
         },
         (false, _) => WatchpointStatus::Disabled,
     }
@@ -227,7 +232,7 @@ pub fn set_watchpoint_status(status: WatchpointStatus) {
     let mut wcr = WCR0(wcr0_get());
     match status {
         WatchpointStatus::Enabled { load, store } => {
-            wcr.set_load_stores(if load { 0b10 } else { 0b00 } + if store { 1 } else { 0 });
+            wcr.set_load_stores(if load { 0b01 } else { 0b00 } + if store { 0b10 } else { 0 });
             wcr.set_enabled(true);
         }
         WatchpointStatus::Disabled => wcr.set_enabled(false),
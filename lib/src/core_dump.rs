@@ -0,0 +1,67 @@
+//! Core dumps of a faulted program, see [bootloader_shared::core_dump].
+//!
+//! The fault handlers stream the program's registers and the memory of
+//! [segments] to the installer, which saves them as an ELF core file, `core`
+//! unless given `--core <path>`, for post-mortem debugging:
+//!
+//! ```text
+//! arm-none-eabi-gdb program.elf core
+//! ```
+//!
+//! GDB reads the registers the way it does for ARM Linux, so a GDB without
+//! Linux support needs `set osabi GNU/Linux` first.
+use core::{arch::asm, ops::Range, ptr::addr_of};
+
+use bootloader_shared::{core_dump::memory_messages, message::Message};
+
+use crate::{
+    allocator,
+    debug::Registers,
+    gdb,
+    interrupts::INT_STACK_ADDR,
+    setup::{__bss_end__, _start, STACK_ADDR},
+    uart::write_message,
+    virtual_memory::segments,
+};
+
+const SP: usize = 13;
+
+/// Send the program's registers, as saved by an abort handler, and memory to
+/// the installer, reporting the fault as `signal`.
+pub(crate) fn write(saved: &Registers, signal: u32) {
+    let registers = gdb::stopped_registers(saved);
+    write_message(Message::CoreRegisters { signal, registers });
+    // Only what is in use, the rest of the segments would be mostly zeros.
+    let code = addr_of!(_start) as usize..addr_of!(__bss_end__) as usize;
+    let heap = segments::HEAP..segments::HEAP + allocator::used();
+    let stack = in_use(registers[SP] as usize, segments::STACK, STACK_ADDR);
+    let int_stack = in_use(handler_sp(), segments::INT_STACK, INT_STACK_ADDR);
+    for region in [code, heap, stack, int_stack] {
+        let length = region.len();
+        write_message(Message::CoreRegion {
+            address: region.start as u32,
+            length: length as u32,
+        });
+        // Safe as the segments are mapped and the program is stopped.
+        let memory = unsafe { core::slice::from_raw_parts(region.start as *const u8, length) };
+        memory_messages(region.start as u32, memory).for_each(write_message);
+    }
+    write_message(Message::CoreEnd);
+}
+
+/// The used part of a stack growing down from `top` to `segment`, or all of
+/// it if `sp` is elsewhere.
+fn in_use(sp: usize, segment: usize, top: u32) -> Range<usize> {
+    let top = top as usize;
+    if (segment..top).contains(&sp) {
+        sp..top
+    } else {
+        segment..top
+    }
+}
+
+fn handler_sp() -> usize {
+    let sp: usize;
+    unsafe { asm!("mov {}, sp", out(reg) sp) };
+    sp
+}
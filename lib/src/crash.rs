@@ -0,0 +1,74 @@
+//! Tells of the last program's crash on the next boot, see
+//! [bootloader_shared::crash].
+use core::{fmt::Write, panic::PanicInfo};
+
+use bootloader_shared::{
+    core_dump::CORE_REGISTERS,
+    crash::{CrashReport, Reason, CRASH_REPORT},
+};
+
+use crate::{debug::Registers, dsb, gdb, print, println};
+
+const NAMES: [&str; CORE_REGISTERS] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr",
+    "pc", "cpsr",
+];
+
+/// Record a fault of the program, with the registers an abort handler saved.
+/// The panic that follows adds its message.
+pub(crate) fn record_fault(reason: Reason, saved: &Registers) {
+    store(CrashReport::new(reason, gdb::stopped_registers(saved)));
+}
+
+/// Record a panic, adding its message to the fault that caused it, if any.
+pub(crate) fn record_panic(info: &PanicInfo) {
+    let mut report = stored()
+        .filter(|report| report.message().is_empty())
+        .unwrap_or_else(|| CrashReport::new(Reason::Panic, [0; CORE_REGISTERS]));
+    let _ = write!(report, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(
+            report,
+            " at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+    store(report);
+}
+
+/// Print the report of how the last program crashed, if it did, and clear
+/// it. Call once the uart is set up.
+pub fn report() {
+    let Some(mut report) = stored() else {
+        return;
+    };
+    let Some(reason) = report.reason() else {
+        return;
+    };
+    println!("[last program crashed: {reason}: {}]", report.message());
+    if reason != Reason::Panic {
+        for (i, (name, value)) in NAMES.iter().zip(report.registers).enumerate() {
+            print!("{name:>4}={value:#010x}");
+            // Four to a line.
+            if i % 4 == 3 || i == CORE_REGISTERS - 1 {
+                println!();
+            }
+        }
+    }
+    report.clear();
+    store(report);
+}
+
+/// The report left at [CRASH_REPORT], if there is one.
+fn stored() -> Option<CrashReport> {
+    let report = unsafe { (CRASH_REPORT as *const CrashReport).read_volatile() };
+    report.is_valid().then_some(report)
+}
+
+fn store(report: CrashReport) {
+    unsafe { (CRASH_REPORT as *mut CrashReport).write_volatile(report) };
+    // Reach memory before a reset.
+    dsb();
+}
@@ -4,6 +4,7 @@
 use core::cell::RefCell;
 
 use alloc::{collections::btree_map::BTreeMap, rc::Rc, vec::Vec};
+use bootloader_shared::{core_dump::SIGSEGV, crash::Reason};
 use critical_section::Mutex;
 
 use crate::{
@@ -12,7 +13,7 @@ use crate::{
         set_breakpoint_address, set_breakpoint_status, set_watchpoint_status, wvr0_set,
         BreakpointStatus, WatchpointStatus, BCR0, DSCR, WCR0,
     },
-    dbg,
+    core_dump, crash, dbg, gdb,
     interrupts::run_user_code,
     println,
     setup::rpi_reboot,
@@ -22,11 +23,15 @@ use crate::{
 
 pub const CRC_ALGORITHM: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_BZIP2);
 
-pub fn data_abort_vector(pc: u32) {
+pub fn data_abort_vector(registers: Registers) -> Registers {
+    if let Some(registers) = gdb::data_abort(&registers) {
+        return registers;
+    }
+    let pc = registers.pc;
     if let WatchpointStatus::Enabled { .. } = get_watchpoint_status() {
-        steal_println!("pc = {:04x}", pc);
+        steal_println!("pc = {:#010x}", pc);
         set_watchpoint_status(WatchpointStatus::Disabled);
-        return;
+        return registers;
     }
 
     println!(
@@ -39,8 +44,10 @@ pub fn data_abort_vector(pc: u32) {
     if far_get() == 0xa77acc0_u32 {
         println!("allowing");
         crate::virtual_memory::allow_segment_illegal_access();
-        return;
+        return registers;
     }
+    crash::record_fault(Reason::DataAbort, &registers);
+    core_dump::write(&registers, SIGSEGV);
     panic!("unexpected data abort: pc={:#010x}\n", pc);
 }
 
@@ -144,9 +151,14 @@ pub fn prefetch_abort_vector(pc: u32, registers: Registers) -> Registers {
         crate::virtual_memory::allow_segment_illegal_access();
         return registers;
     }
+    if let Some(registers) = gdb::prefetch_abort(&registers) {
+        return registers;
+    }
 
     if let BreakpointStatus::Disabled = get_breakpoint_status {
-        panic!("unexpected prefetch abort: pc={}\n", pc);
+        crash::record_fault(Reason::PrefetchAbort, &registers);
+        core_dump::write(&registers, SIGSEGV);
+        panic!("unexpected prefetch abort: pc={:#010x}\n", pc);
     }
 
     let cs = unsafe { critical_section::CriticalSection::new() };
@@ -0,0 +1,414 @@
+//! Debugging with GDB through the installer, see [bootloader_shared::gdb].
+//!
+//! Call [start] to stop the program until GDB attaches, after running the
+//! installer with `--gdb <port>`:
+//!
+//! ```text
+//! install --gdb 3333 program.elf
+//! arm-none-eabi-gdb -ex 'target remote :3333' program.elf
+//! ```
+//!
+//! The stub runs in the prefetch and data abort handlers with interrupts off,
+//! polling the uart for GDB's packets. Single steps and the one hardware
+//! breakpoint share breakpoint register 0, and there is one watchpoint of up
+//! to 4 bytes within a word. GDB can't interrupt a running program, so stop
+//! it with breakpoints.
+use core::{arch::asm, cell::RefCell};
+
+use bootloader_shared::{
+    gdb::{Breakpoint, Resume, Stop, Stub, Target, REGISTERS},
+    message::{Message, MAX_PAYLOAD},
+};
+use critical_section::{CriticalSection, Mutex};
+
+use crate::{
+    coprocessor::{
+        dfsr_get, ifsr_get, set_breakpoint_address, set_breakpoint_status, set_watchpoint_address,
+        set_watchpoint_status, BreakpointStatus, WatchpointStatus, WCR0,
+    },
+    debug::{self, Registers},
+    println,
+    setup::{exit, SYSTEM_MODE, USER_MODE},
+    uart::{read_byte, write_message},
+};
+
+/// The `bkpt` that software breakpoints are patched in as.
+const BKPT: u32 = 0xe120_0070;
+/// The bits of a `bkpt` that aren't its immediate.
+const BKPT_MASK: u32 = 0xfff0_00f0;
+const MAX_SOFTWARE_BREAKPOINTS: usize = 32;
+/// End of RAM. Peripherals past it may change when read, so GDB can't access
+/// them.
+const MEMORY_END: u32 = 0x2000_0000;
+/// Exit code when GDB kills the program, as a shell reports `SIGKILL`.
+const KILLED_CODE: u32 = 128 + 9;
+/// Fault status in the DFSR and IFSR of a debug event.
+const DEBUG_EVENT: u32 = 0b00010;
+const FAULT_STATUS_MASK: u32 = 0x40f;
+const MODE_MASK: u32 = 0x1f;
+/// The I and F bits of the cpsr.
+const INTERRUPTS_OFF: u32 = 0xc0;
+const PC: usize = 15;
+const CPSR: usize = 16;
+
+static DEBUGGER: Mutex<RefCell<Option<Debugger>>> = Mutex::new(RefCell::new(None));
+
+struct Debugger {
+    stub: Stub,
+    breakpoints: Breakpoints,
+}
+
+#[derive(Default)]
+struct Breakpoints {
+    /// Address and the instruction the `bkpt` replaced.
+    software: [Option<(u32, u32)>; MAX_SOFTWARE_BREAKPOINTS],
+    hardware: Option<u32>,
+    /// Kind and address, as GDB gave them.
+    watchpoint: Option<(Breakpoint, u32)>,
+}
+
+/// Stop the program until GDB attaches and continues it. From then on,
+/// breakpoints, steps, watchpoints and aborts stop it again.
+pub fn start() {
+    debug::setup();
+    set_breakpoint_status(BreakpointStatus::Disabled);
+    set_watchpoint_status(WatchpointStatus::Disabled);
+    critical_section::with(|cs| {
+        *DEBUGGER.borrow_ref_mut(cs) = Some(Debugger {
+            stub: Stub::new(Stop::Trap),
+            breakpoints: Breakpoints::default(),
+        });
+    });
+    println!("waiting for gdb");
+    unsafe { asm!("bkpt #0") };
+}
+
+/// Run the stub if GDB is debugging the program, returning the registers to
+/// continue with.
+pub(crate) fn prefetch_abort(registers: &Registers) -> Option<Registers> {
+    let debug_event = ifsr_get() & FAULT_STATUS_MASK == DEBUG_EVENT;
+    stopped(registers, |_| {
+        if debug_event {
+            Stop::Trap
+        } else {
+            Stop::Fault
+        }
+    })
+}
+
+/// Run the stub if GDB is debugging the program, returning the registers to
+/// continue with.
+pub(crate) fn data_abort(registers: &Registers) -> Option<Registers> {
+    let debug_event = dfsr_get() & FAULT_STATUS_MASK == DEBUG_EVENT;
+    stopped(registers, |breakpoints| match breakpoints.watchpoint {
+        Some((kind, address)) if debug_event => Stop::Watch { kind, address },
+        _ => Stop::Fault,
+    })
+}
+
+fn stopped(registers: &Registers, stop: impl FnOnce(&Breakpoints) -> Stop) -> Option<Registers> {
+    // Safe because abort handlers run with interrupts disabled.
+    let cs = unsafe { CriticalSection::new() };
+    let mut debugger = DEBUGGER.borrow_ref_mut(cs);
+    let Debugger { stub, breakpoints } = debugger.as_mut()?;
+    // Steps and the hardware breakpoint are set again when resuming.
+    set_breakpoint_status(BreakpointStatus::Disabled);
+    stub.stopped(stop(breakpoints));
+    let mut program = Program::new(registers, breakpoints);
+    let resume = loop {
+        flush(stub);
+        if let Some(resume) = stub.receive(read_byte(), &mut program) {
+            flush(stub);
+            break resume;
+        }
+    };
+    if resume == Resume::Kill {
+        exit(KILLED_CODE);
+    }
+    let registers = program.resume(resume);
+    if resume == Resume::Detach {
+        *debugger = None;
+    }
+    Some(registers)
+}
+
+/// The registers of the program as GDB numbers them, from the ones an abort
+/// handler saved along with its spsr and the banked `sp` and `lr`.
+pub(crate) fn stopped_registers(saved: &Registers) -> [u32; REGISTERS] {
+    let cpsr = spsr();
+    let mode = cpsr & MODE_MASK;
+    let (sp, lr) = match mode {
+        USER_MODE | SYSTEM_MODE => (saved.sp, saved.lr),
+        _ => banked(mode),
+    };
+    let mut registers = [0; REGISTERS];
+    registers[..13].copy_from_slice(&saved.r);
+    registers[13] = sp;
+    registers[14] = lr;
+    registers[PC] = saved.pc;
+    registers[CPSR] = cpsr;
+    registers
+}
+
+/// Send the stub's output to the installer.
+fn flush(stub: &mut Stub) {
+    if let Some(output) = stub.transmit() {
+        for data in output.chunks(MAX_PAYLOAD) {
+            write_message(Message::Gdb { data });
+        }
+    }
+}
+
+/// The stopped program.
+struct Program<'a> {
+    registers: [u32; REGISTERS],
+    /// The `sp` and `lr` of user and system mode, saved by the handler.
+    saved: (u32, u32),
+    /// The mode the program stopped in.
+    mode: u32,
+    breakpoints: &'a mut Breakpoints,
+}
+
+impl<'a> Program<'a> {
+    fn new(saved: &Registers, breakpoints: &'a mut Breakpoints) -> Self {
+        let registers = stopped_registers(saved);
+        Self {
+            registers,
+            saved: (saved.sp, saved.lr),
+            mode: registers[CPSR] & MODE_MASK,
+            breakpoints,
+        }
+    }
+
+    /// Set up the debug hardware for `resume`, returning the registers to
+    /// continue with.
+    fn resume(self, resume: Resume) -> Registers {
+        let mut pc = self.registers[PC];
+        // Continuing from a `bkpt` in the program, like the one in [start],
+        // would stop again right away.
+        if in_memory(pc, 4) && read_word(pc) & BKPT_MASK == BKPT && !self.software_breakpoint(pc) {
+            pc += 4;
+        }
+        match (resume, self.breakpoints.hardware) {
+            (Resume::Step, _) => {
+                set_breakpoint_address(pc);
+                set_breakpoint_status(BreakpointStatus::Enabled { matching: false });
+            }
+            (Resume::Continue, Some(address)) => {
+                set_breakpoint_address(address);
+                set_breakpoint_status(BreakpointStatus::Enabled { matching: true });
+            }
+            _ => {}
+        }
+        set_spsr(self.registers[CPSR]);
+        let (mut sp, mut lr) = (self.registers[13], self.registers[14]);
+        if !matches!(self.mode, USER_MODE | SYSTEM_MODE) {
+            set_banked(self.mode, sp, lr);
+            (sp, lr) = self.saved;
+        }
+        Registers {
+            pc,
+            sp,
+            lr,
+            r: self.registers[..13].try_into().unwrap(),
+        }
+    }
+
+    fn software_breakpoint(&self, address: u32) -> bool {
+        self.breakpoints
+            .software
+            .iter()
+            .flatten()
+            .any(|&(patched, _)| patched == address)
+    }
+}
+
+impl Target for Program<'_> {
+    fn registers(&mut self) -> [u32; REGISTERS] {
+        self.registers
+    }
+
+    fn set_registers(&mut self, registers: [u32; REGISTERS]) {
+        self.registers = registers;
+    }
+
+    fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> bool {
+        if !in_memory(address, buf.len()) {
+            return false;
+        }
+        for (byte_address, byte) in (address..).zip(buf) {
+            *byte = read_byte_at(byte_address);
+        }
+        true
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> bool {
+        if !in_memory(address, data.len()) {
+            return false;
+        }
+        for (byte_address, &byte) in (address..).zip(data) {
+            write_byte_at(byte_address, byte);
+        }
+        sync_code();
+        true
+    }
+
+    fn insert(&mut self, kind: Breakpoint, address: u32, length: u32) -> bool {
+        let breakpoints = &mut *self.breakpoints;
+        match kind {
+            Breakpoint::Software => {
+                if address % 4 != 0 || !in_memory(address, 4) {
+                    return false;
+                }
+                let Some(slot) = breakpoints.software.iter_mut().find(|slot| slot.is_none()) else {
+                    return false;
+                };
+                *slot = Some((address, read_word(address)));
+                write_word(address, BKPT);
+                sync_code();
+            }
+            Breakpoint::Hardware => {
+                if breakpoints.hardware.is_some() {
+                    return false;
+                }
+                breakpoints.hardware = Some(address);
+            }
+            Breakpoint::Write | Breakpoint::Read | Breakpoint::Access => {
+                let offset = address % 4;
+                if breakpoints.watchpoint.is_some() || length == 0 || offset + length > 4 {
+                    return false;
+                }
+                breakpoints.watchpoint = Some((kind, address));
+                let mut wcr = WCR0::read();
+                wcr.set_byte_address_select(((1 << length) - 1) << offset);
+                WCR0::write(wcr);
+                set_watchpoint_address(address - offset);
+                set_watchpoint_status(WatchpointStatus::Enabled {
+                    load: kind != Breakpoint::Write,
+                    store: kind != Breakpoint::Read,
+                });
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, kind: Breakpoint, address: u32, _length: u32) -> bool {
+        let breakpoints = &mut *self.breakpoints;
+        match kind {
+            Breakpoint::Software => {
+                let slot = breakpoints
+                    .software
+                    .iter_mut()
+                    .find(|slot| matches!(slot, Some((patched, _)) if *patched == address));
+                let Some((_, original)) = slot.and_then(Option::take) else {
+                    return false;
+                };
+                write_word(address, original);
+                sync_code();
+            }
+            Breakpoint::Hardware => {
+                if breakpoints.hardware != Some(address) {
+                    return false;
+                }
+                breakpoints.hardware = None;
+            }
+            Breakpoint::Write | Breakpoint::Read | Breakpoint::Access => {
+                if breakpoints.watchpoint != Some((kind, address)) {
+                    return false;
+                }
+                breakpoints.watchpoint = None;
+                set_watchpoint_status(WatchpointStatus::Disabled);
+            }
+        }
+        true
+    }
+}
+
+/// Whether `length` bytes from `address` are RAM.
+fn in_memory(address: u32, length: usize) -> bool {
+    address
+        .checked_add(length as u32)
+        .is_some_and(|end| end <= MEMORY_END)
+}
+
+// Accesses use assembly, as rust/LLVM assumes address 0 is never valid.
+fn read_byte_at(address: u32) -> u8 {
+    let byte: u32;
+    unsafe { asm!("ldrb {}, [{}]", out(reg) byte, in(reg) address) };
+    byte as u8
+}
+
+fn write_byte_at(address: u32, byte: u8) {
+    unsafe { asm!("strb {}, [{}]", in(reg) byte as u32, in(reg) address) };
+}
+
+fn read_word(address: u32) -> u32 {
+    let word;
+    unsafe { asm!("ldr {}, [{}]", out(reg) word, in(reg) address) };
+    word
+}
+
+fn write_word(address: u32, word: u32) {
+    unsafe { asm!("str {}, [{}]", in(reg) word, in(reg) address) };
+}
+
+/// Make written code visible to instruction fetches.
+fn sync_code() {
+    unsafe {
+        asm!(
+            "mcr p15, 0, {0}, c7, c10, 0", // clean the data cache
+            "mcr p15, 0, {0}, c7, c10, 4", // data synchronization barrier
+            "mcr p15, 0, {0}, c7, c5, 0",  // invalidate the instruction cache
+            "mcr p15, 0, {0}, c7, c5, 6",  // flush the branch target cache
+            "mcr p15, 0, {0}, c7, c5, 4",  // flush the prefetch buffer
+            in(reg) 0,
+        )
+    }
+}
+
+/// The cpsr of the program, saved when the abort was taken.
+fn spsr() -> u32 {
+    let spsr;
+    unsafe { asm!("mrs {}, spsr", out(reg) spsr) };
+    spsr
+}
+
+fn set_spsr(spsr: u32) {
+    unsafe { asm!("msr spsr_cxsf, {}", in(reg) spsr) };
+}
+
+/// The `sp` and `lr` of a privileged `mode`, which the abort handlers don't
+/// save. Uses fixed registers, as the others are banked.
+fn banked(mode: u32) -> (u32, u32) {
+    let (sp, lr);
+    unsafe {
+        asm!(
+            "mrs r0, cpsr",
+            "msr cpsr_c, r1",
+            "mov r2, sp",
+            "mov r3, lr",
+            "msr cpsr_c, r0",
+            out("r0") _,
+            in("r1") mode | INTERRUPTS_OFF,
+            out("r2") sp,
+            out("r3") lr,
+        )
+    };
+    (sp, lr)
+}
+
+fn set_banked(mode: u32, sp: u32, lr: u32) {
+    unsafe {
+        asm!(
+            "mrs r0, cpsr",
+            "msr cpsr_c, r1",
+            "mov sp, r2",
+            "mov lr, r3",
+            "msr cpsr_c, r0",
+            out("r0") _,
+            in("r1") mode | INTERRUPTS_OFF,
+            in("r2") sp,
+            in("r3") lr,
+        )
+    };
+}
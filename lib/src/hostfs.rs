@@ -0,0 +1,120 @@
+//! Files on the host, served by the installer's console when run with
+//! `--hostfs <dir>`. See [bootloader_shared::hostfs].
+//!
+//! Each request waits for the installer's reply, so this is for test inputs
+//! and results rather than anything fast.
+pub use bootloader_shared::hostfs::{HostError, Mode};
+use bootloader_shared::{
+    hostfs::{ReplyReader, MAX_DATA, MAX_PATH, MAX_WRITE},
+    message::Message,
+};
+
+use crate::{
+    timer,
+    uart::{read_uart_raw, write_message},
+};
+
+/// How long to wait for the installer to answer a request.
+const REPLY_TIMEOUT_US: u32 = 2_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Host(HostError),
+    /// The installer didn't answer, as when it isn't running a console.
+    Timeout,
+    /// The path is longer than [MAX_PATH] bytes.
+    PathTooLong,
+}
+
+/// A file open on the host, closed when dropped.
+pub struct File {
+    handle: u32,
+}
+
+impl File {
+    pub fn open(path: &str, mode: Mode) -> Result<Self, Error> {
+        if path.len() > MAX_PATH {
+            return Err(Error::PathTooLong);
+        }
+        let (handle, _) = request(Message::HostOpen { mode, path }, &mut [])?;
+        Ok(Self { handle })
+    }
+
+    /// Fill `buf` unless the end of the file comes first, returning how many
+    /// bytes were read.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut read = 0;
+        while read < buf.len() {
+            let end = (read + MAX_DATA).min(buf.len());
+            let chunk = &mut buf[read..end];
+            let length = chunk.len() as u32;
+            let (_, n) = request(
+                Message::HostRead {
+                    handle: self.handle,
+                    length,
+                },
+                chunk,
+            )?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        Ok(read)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        for chunk in data.chunks(MAX_WRITE) {
+            request(
+                Message::HostWrite {
+                    handle: self.handle,
+                    data: chunk,
+                },
+                &mut [],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Close the file, reporting whether everything written made it to disk.
+    pub fn close(self) -> Result<(), Error> {
+        let handle = self.handle;
+        core::mem::forget(self);
+        request(Message::HostClose { handle }, &mut []).map(|_| ())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = request(
+            Message::HostClose {
+                handle: self.handle,
+            },
+            &mut [],
+        );
+    }
+}
+
+/// Send `message` and wait for the reply, copying its data into `data`.
+/// Returns the value of the reply and how much data it had.
+fn request(message: Message, data: &mut [u8]) -> Result<(u32, usize), Error> {
+    write_message(message);
+    let mut reader = ReplyReader::new();
+    let start = timer::timer_get_usec();
+    let mut buf = [0; 1];
+    loop {
+        if timer::timer_get_usec().wrapping_sub(start) > REPLY_TIMEOUT_US {
+            return Err(Error::Timeout);
+        }
+        // File data may hold anything, including INSTALLER_REBOOT.
+        let Some(&byte) = read_uart_raw(&mut buf).first() else {
+            continue;
+        };
+        if let Some(reply) = reader.push(byte) {
+            let value = reply.result.map_err(Error::Host)?;
+            let n = reply.data.len().min(data.len());
+            data[..n].copy_from_slice(&reply.data[..n]);
+            return Ok((value, n));
+        }
+    }
+}
@@ -5,6 +5,7 @@ use core::{
 
 use alloc::{boxed::Box, vec::Vec};
 use bcm2835_lpa::Peripherals;
+use bootloader_shared::{core_dump::SIGILL, crash::Reason};
 use critical_section::{CriticalSection, Mutex};
 
 use crate::{debug::Registers, dsb, steal_println, timer::timer_get_usec_raw};
@@ -170,8 +171,16 @@ reset_asm:
 
 @ Q: what are the right offsets for the following?
 undefined_instruction_asm:
+    @ saves the same registers as prefetch_abort_asm, for the core dump
     mov sp, {INT_STACK_ADDR}
-    sub r0, lr, 4
+    push {{r0-r12}}
+    cps {SYSTEM_MODE}
+    mov r2,r13
+    mov r3,r14
+    cps {UNDEFINED_MODE}
+    push {{r2-r3}}
+    sub r0, lr, #4
+    mov r1, sp
     bl undefined_instruction_vector
 
 
@@ -246,12 +255,26 @@ prefetch_abort_asm:
     pop {{r0-r12}}
     movs pc, lr 
 data_abort_asm:
+    @ saves the same registers as prefetch_abort_asm
     mov sp, {INT_STACK_ADDR}
-    push {{r0-r12,lr}}
+    push {{r0-r12}}
+    cps {SYSTEM_MODE}
+    mov r2,r13
+    mov r3,r14
+    cps {ABORT_MODE}
+    push {{r2-r3}}
+    @ the aborted instruction, to run again
     sub r0, lr, #8
+    mov r1, sp
     bl data_abort_vector
-    pop {{r0-r12,lr}}
-    sub lr, lr, #8
+    mov lr, r0
+
+    pop {{r2-r3}}
+    cps {SYSTEM_MODE}
+    mov r13,r2
+    mov r14,r3
+    cps {ABORT_MODE}
+    pop {{r0-r12}}
     movs pc, lr 
 
 @
@@ -315,6 +338,7 @@ _interrupt_table_fast:
     USER_MODE = const super::setup::USER_MODE,
     SYSTEM_MODE = const super::setup::SYSTEM_MODE,
     ABORT_MODE = const super::setup::ABORT_MODE,
+    UNDEFINED_MODE = const super::setup::UNDEFINED_MODE,
 );
 
 mod asm {
@@ -392,7 +416,7 @@ pub unsafe fn interrupt_init() {
 
 #[no_mangle]
 extern "C" fn fast_interrupt_vector(pc: u32) {
-    panic!("unexpected fast interrupt: pc={}\n", pc);
+    panic!("unexpected fast interrupt: pc={:#010x}\n", pc);
 }
 
 const USER_STACK_SIZE: usize = 1024 * 64 * 2;
@@ -404,14 +428,30 @@ extern "C" fn syscall_vector(pc: u32) -> i32 {
 }
 #[no_mangle]
 extern "C" fn reset_vector(pc: u32) {
-    panic!("unexpected reset: pc={}\n", pc);
+    panic!("unexpected reset: pc={:#010x}\n", pc);
 }
 #[no_mangle]
-extern "C" fn undefined_instruction_vector(pc: u32) {
-    panic!("unexpected undef-inst: pc={}\n", pc);
+extern "C" fn undefined_instruction_vector(pc: u32, sp: u32) {
+    with_saved_registers(pc, sp, |registers| {
+        crate::crash::record_fault(Reason::UndefinedInstruction, &registers);
+        crate::core_dump::write(&registers, SIGILL);
+        panic!("unexpected undef-inst: pc={:#010x}\n", pc);
+    });
 }
 #[no_mangle]
 extern "C" fn prefetch_abort_vector(pc: u32, sp: u32) -> u32 {
+    with_saved_registers(pc, sp, |registers| {
+        crate::debug::prefetch_abort_vector(pc, registers)
+    })
+}
+#[no_mangle]
+extern "C" fn data_abort_vector(pc: u32, sp: u32) -> u32 {
+    with_saved_registers(pc, sp, crate::debug::data_abort_vector)
+}
+
+/// Pass the registers an abort handler pushed at `sp` to `handler`, storing
+/// the ones it returns to be restored and returning where to continue.
+fn with_saved_registers(pc: u32, sp: u32, handler: impl FnOnce(Registers) -> Registers) -> u32 {
     // Registers 0-14
     let dump: &mut [u32; 15] = unsafe {
         core::slice::from_raw_parts_mut(sp as *mut u32, 15)
@@ -426,16 +466,12 @@ extern "C" fn prefetch_abort_vector(pc: u32, sp: u32) -> u32 {
         r: dump[2..].try_into().unwrap(),
     };
     // Let the handler decide where to return to.
-    let new_registers = crate::debug::prefetch_abort_vector(pc, registers);
+    let new_registers = handler(registers);
     dump[0] = new_registers.sp;
     dump[1] = new_registers.lr;
     dump[2..].copy_from_slice(&new_registers.r);
     new_registers.pc
 }
-#[no_mangle]
-extern "C" fn data_abort_vector(pc: u32) {
-    crate::debug::data_abort_vector(pc);
-}
 
 static mut CNT: u32 = 0;
 static mut PERIOD: u32 = 0;
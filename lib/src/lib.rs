@@ -6,15 +6,24 @@
 #![allow(asm_sub_register)]
 
 mod allocator;
+pub mod boot_info;
 pub mod coprocessor;
+mod core_dump;
+pub mod crash;
 mod critical_section;
 pub mod cycle_counter;
 pub mod debug;
+pub mod gdb;
 pub mod gpio;
+pub mod hostfs;
 pub mod interrupts;
+pub mod mailbox;
 mod pin_array;
+pub mod rpc;
+pub mod sd;
 pub mod setup;
 pub mod syscall;
+pub mod testing;
 pub mod thread;
 pub mod timer;
 pub mod uart;
@@ -0,0 +1,48 @@
+//! Asks the firmware for clock rates through the mailbox property interface.
+use crate::{dsb, timer};
+
+const MAILBOX_READ: usize = 0x2000b880;
+const MAILBOX_STATUS: usize = 0x2000b898;
+const MAILBOX_WRITE: usize = 0x2000b8a0;
+const MAILBOX_FULL: u32 = 1 << 31;
+const MAILBOX_EMPTY: u32 = 1 << 30;
+const MAILBOX_PROPERTY_CHANNEL: u32 = 8;
+const TAG_GET_CLOCK_RATE: u32 = 0x0003_0002;
+/// Where the GPU sees uncached ARM memory.
+const GPU_MEMORY: u32 = 0x4000_0000;
+const TIMEOUT_US: u32 = 100_000;
+
+pub const CLOCK_EMMC: u32 = 1;
+/// Clocks the mini UART, among others.
+pub const CLOCK_CORE: u32 = 4;
+
+/// The rate of `clock` in Hz, if the firmware answers.
+pub fn clock_rate(clock: u32) -> Option<u32> {
+    // Header, the tag with room for its two word value, and the end tag.
+    #[repr(C, align(16))]
+    struct Message([u32; 8]);
+    let mut message = Message([8 * 4, 0, TAG_GET_CLOCK_RATE, 8, 0, clock, 0, 0]);
+    let address = &raw mut message as usize as u32;
+    unsafe {
+        dsb();
+        while (MAILBOX_STATUS as *const u32).read_volatile() & MAILBOX_FULL != 0 {}
+        (MAILBOX_WRITE as *mut u32)
+            .write_volatile((address | GPU_MEMORY) | MAILBOX_PROPERTY_CHANNEL);
+        let start = timer::timer_get_usec();
+        loop {
+            if timer::timer_get_usec().wrapping_sub(start) > TIMEOUT_US {
+                return None;
+            }
+            if (MAILBOX_STATUS as *const u32).read_volatile() & MAILBOX_EMPTY != 0 {
+                continue;
+            }
+            if (MAILBOX_READ as *const u32).read_volatile() & 0xf == MAILBOX_PROPERTY_CHANNEL {
+                break;
+            }
+        }
+        dsb();
+        let message = (&raw const message).read_volatile();
+        // The request succeeded and the rate is set.
+        (message.0[1] == 0x8000_0000 && message.0[6] != 0).then_some(message.0[6])
+    }
+}
@@ -0,0 +1,71 @@
+//! Functions the host can call with `install --call`, for
+//! hardware-in-the-loop tests. See [bootloader_shared::rpc].
+//!
+//! ```ignore
+//! Registry::new()
+//!     .register("read_pin", |pin: u32| read_pin(pin))
+//!     .register("add", |(a, b): (u32, u32)| a + b)
+//!     .serve();
+//! ```
+use alloc::{boxed::Box, vec::Vec};
+
+pub use bootloader_shared::rpc::RpcError;
+use bootloader_shared::rpc::{self, Functions, Server};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::uart::{read_byte, write_uart};
+
+type Handler = Box<dyn FnMut(&[u8], &mut [u8]) -> Result<usize, RpcError>>;
+
+/// The functions the host can call, by name.
+#[derive(Default)]
+pub struct Registry {
+    handlers: Vec<(&'static str, Handler)>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let the host call `f` as `name`. Its argument and result are
+    /// postcard, so several arguments go in a tuple.
+    pub fn register<A: DeserializeOwned, R: Serialize>(
+        mut self,
+        name: &'static str,
+        mut f: impl FnMut(A) -> R + 'static,
+    ) -> Self {
+        self.handlers.push((
+            name,
+            Box::new(move |args, result| rpc::call_with(&mut f, args, result)),
+        ));
+        self
+    }
+
+    /// Answer calls from the host until it is done. Blocks until the host
+    /// calls, so only serve when the installer was told to.
+    pub fn serve(&mut self) {
+        let mut server = Server::new();
+        loop {
+            if let Some(frame) = server.transmit() {
+                critical_section::with(|_| write_uart(frame));
+            }
+            if !server.is_serving() {
+                return;
+            }
+            // Returns to the bootloader if the console asks for it.
+            server.receive(read_byte(), self);
+        }
+    }
+}
+
+impl Functions for Registry {
+    fn call(&mut self, name: &str, args: &[u8], result: &mut [u8]) -> Result<usize, RpcError> {
+        let (_, handler) = self
+            .handlers
+            .iter_mut()
+            .find(|(registered, _)| *registered == name)
+            .ok_or(RpcError::UnknownFunction)?;
+        handler(args, result)
+    }
+}
@@ -0,0 +1,305 @@
+//! SD card access through the EMMC controller, a sector at a time.
+//!
+//! Only what the bootloader needs to update `kernel.img`: single sector reads
+//! and writes on a 1 bit bus. The firmware already set up the card pins (GPIO
+//! 48 to 53) when it booted from the card, so they are left alone.
+use bootloader_shared::fat::{self, BlockDevice, SECTOR_SIZE};
+
+use crate::{dsb, mailbox, timer};
+
+// EMMC registers, bcm2835 p66 [starts at 0x20300000].
+const EMMC_BASE: usize = 0x20300000;
+const EMMC_BLKSIZECNT: usize = EMMC_BASE + 0x04;
+const EMMC_ARG1: usize = EMMC_BASE + 0x08;
+const EMMC_CMDTM: usize = EMMC_BASE + 0x0c;
+const EMMC_RESP0: usize = EMMC_BASE + 0x10;
+const EMMC_DATA: usize = EMMC_BASE + 0x20;
+const EMMC_STATUS: usize = EMMC_BASE + 0x24;
+const EMMC_CONTROL0: usize = EMMC_BASE + 0x28;
+const EMMC_CONTROL1: usize = EMMC_BASE + 0x2c;
+const EMMC_INTERRUPT: usize = EMMC_BASE + 0x30;
+const EMMC_IRPT_MASK: usize = EMMC_BASE + 0x34;
+const EMMC_IRPT_EN: usize = EMMC_BASE + 0x38;
+const EMMC_SLOTISR_VER: usize = EMMC_BASE + 0xfc;
+
+const STATUS_CMD_INHIBIT: u32 = 1 << 0;
+const STATUS_DAT_INHIBIT: u32 = 1 << 1;
+
+const CONTROL1_CLK_INTLEN: u32 = 1 << 0;
+const CONTROL1_CLK_STABLE: u32 = 1 << 1;
+const CONTROL1_CLK_EN: u32 = 1 << 2;
+/// Largest data timeout.
+const CONTROL1_DATA_TOUNIT_MAX: u32 = 0xe << 16;
+const CONTROL1_SRST_HC: u32 = 1 << 24;
+const CONTROL1_SRST_CMD: u32 = 1 << 25;
+/// The clock divider fields.
+const CONTROL1_CLK_FREQ: u32 = 0xffc0;
+
+const INTERRUPT_CMD_DONE: u32 = 1 << 0;
+const INTERRUPT_DATA_DONE: u32 = 1 << 1;
+const INTERRUPT_WRITE_RDY: u32 = 1 << 4;
+const INTERRUPT_READ_RDY: u32 = 1 << 5;
+const INTERRUPT_CTO_ERR: u32 = 1 << 16;
+const INTERRUPT_DTO_ERR: u32 = 1 << 20;
+/// All error bits.
+const INTERRUPT_ERRORS: u32 = 0x017f_8000;
+
+// CMDTM bits: the command index, response type and data transfer.
+const CMD_RSPNS_136: u32 = 1 << 16;
+const CMD_RSPNS_48: u32 = 2 << 16;
+const CMD_RSPNS_48_BUSY: u32 = 3 << 16;
+const CMD_ISDATA: u32 = 1 << 21;
+const CMD_DAT_DIR_CH: u32 = 1 << 4;
+
+const GO_IDLE_STATE: u32 = 0;
+const ALL_SEND_CID: u32 = (2 << 24) | CMD_RSPNS_136;
+const SEND_RELATIVE_ADDR: u32 = (3 << 24) | CMD_RSPNS_48;
+const SELECT_CARD: u32 = (7 << 24) | CMD_RSPNS_48_BUSY;
+const SEND_IF_COND: u32 = (8 << 24) | CMD_RSPNS_48;
+const SET_BLOCKLEN: u32 = (16 << 24) | CMD_RSPNS_48;
+const READ_SINGLE_BLOCK: u32 = (17 << 24) | CMD_RSPNS_48 | CMD_ISDATA | CMD_DAT_DIR_CH;
+const WRITE_BLOCK: u32 = (24 << 24) | CMD_RSPNS_48 | CMD_ISDATA;
+const APP_CMD: u32 = (55 << 24) | CMD_RSPNS_48;
+/// Sent after [APP_CMD].
+const SD_SEND_OP_COND: u32 = (41 << 24) | CMD_RSPNS_48;
+
+/// Voltage range 2.7-3.6V, sent with [SEND_IF_COND] and echoed back.
+const IF_COND_CHECK: u32 = 0x1aa;
+/// Voltage window for [SD_SEND_OP_COND].
+const OP_COND_VOLTAGE: u32 = 0x00ff_8000;
+const OP_COND_HCS: u32 = 1 << 30;
+const OP_COND_READY: u32 = 1 << 31;
+/// Error bits of a card status response.
+const CARD_STATUS_ERRORS: u32 = 0xfff9_c004;
+
+const IDENTIFICATION_CLOCK: u32 = 400_000;
+const TRANSFER_CLOCK: u32 = 25_000_000;
+/// Used if the firmware doesn't say.
+const DEFAULT_BASE_CLOCK: u32 = 250_000_000;
+
+const COMMAND_TIMEOUT_US: u32 = 100_000;
+const DATA_TIMEOUT_US: u32 = 500_000;
+/// Cards may take up to a second to power up.
+const POWER_UP_TIMEOUT_US: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The controller or card took too long.
+    Timeout,
+    /// The controller or card reported an error.
+    Failed { command: u32, status: u32 },
+    /// The card doesn't support 3.3V or never finished powering up.
+    Unusable,
+}
+
+/// An initialized SD card.
+pub struct Sd {
+    /// Relative card address, the argument of some commands.
+    rca: u32,
+    /// Addressed by sector rather than by byte.
+    high_capacity: bool,
+}
+
+impl Sd {
+    /// Reset the controller and bring the card into its transfer state.
+    pub fn init() -> Result<Self, Error> {
+        write(EMMC_CONTROL0, 0);
+        write(EMMC_CONTROL1, read(EMMC_CONTROL1) | CONTROL1_SRST_HC);
+        wait(EMMC_CONTROL1, CONTROL1_SRST_HC, 0, COMMAND_TIMEOUT_US)?;
+        write(
+            EMMC_CONTROL1,
+            read(EMMC_CONTROL1) | CONTROL1_CLK_INTLEN | CONTROL1_DATA_TOUNIT_MAX,
+        );
+        timer::delay_ms(10);
+        set_clock(IDENTIFICATION_CLOCK)?;
+        // Report everything through EMMC_INTERRUPT, without raising an IRQ.
+        write(EMMC_IRPT_EN, 0);
+        write(EMMC_IRPT_MASK, u32::MAX);
+
+        let mut sd = Self {
+            rca: 0,
+            high_capacity: false,
+        };
+        sd.command(GO_IDLE_STATE, 0)?;
+        // Version 1 cards don't answer and can't be high capacity.
+        let version_2 = match sd.command(SEND_IF_COND, IF_COND_CHECK) {
+            Ok(response) if response & 0xfff == IF_COND_CHECK => true,
+            Ok(_) => return Err(Error::Unusable),
+            Err(Error::Timeout) => {
+                // Reset the command line before trying anything else.
+                write(EMMC_CONTROL1, read(EMMC_CONTROL1) | CONTROL1_SRST_CMD);
+                wait(EMMC_CONTROL1, CONTROL1_SRST_CMD, 0, COMMAND_TIMEOUT_US)?;
+                false
+            }
+            Err(e) => return Err(e),
+        };
+
+        let argument = OP_COND_VOLTAGE | if version_2 { OP_COND_HCS } else { 0 };
+        let start = timer::timer_get_usec();
+        let op_cond = loop {
+            sd.command(APP_CMD, 0)?;
+            let response = sd.command(SD_SEND_OP_COND, argument)?;
+            if response & OP_COND_READY != 0 {
+                break response;
+            }
+            if timer::timer_get_usec().wrapping_sub(start) > POWER_UP_TIMEOUT_US {
+                return Err(Error::Unusable);
+            }
+            timer::delay_ms(10);
+        };
+        sd.high_capacity = op_cond & OP_COND_HCS != 0;
+
+        sd.command(ALL_SEND_CID, 0)?;
+        sd.rca = sd.command(SEND_RELATIVE_ADDR, 0)? & 0xffff_0000;
+        set_clock(TRANSFER_CLOCK)?;
+        sd.command(SELECT_CARD, sd.rca)?;
+        if !sd.high_capacity {
+            sd.command(SET_BLOCKLEN, SECTOR_SIZE as u32)?;
+        }
+        Ok(sd)
+    }
+
+    /// Send a command, returning the first word of its response.
+    fn command(&mut self, command: u32, argument: u32) -> Result<u32, Error> {
+        wait(EMMC_STATUS, STATUS_CMD_INHIBIT, 0, COMMAND_TIMEOUT_US)?;
+        if command & CMD_ISDATA != 0 {
+            wait(EMMC_STATUS, STATUS_DAT_INHIBIT, 0, DATA_TIMEOUT_US)?;
+        }
+        // Clear everything left over.
+        write(EMMC_INTERRUPT, read(EMMC_INTERRUPT));
+        write(EMMC_ARG1, argument);
+        write(EMMC_CMDTM, command);
+        wait_interrupt(command, INTERRUPT_CMD_DONE, COMMAND_TIMEOUT_US)?;
+        let response = read(EMMC_RESP0);
+        // R1 responses carry the card status.
+        let status = match command {
+            READ_SINGLE_BLOCK | WRITE_BLOCK | SET_BLOCKLEN | SELECT_CARD | APP_CMD => response,
+            _ => 0,
+        };
+        if status & CARD_STATUS_ERRORS != 0 {
+            return Err(Error::Failed { command, status });
+        }
+        Ok(response)
+    }
+
+    fn address(&self, sector: u32) -> u32 {
+        if self.high_capacity {
+            sector
+        } else {
+            sector * SECTOR_SIZE as u32
+        }
+    }
+
+    pub fn read_sector(&mut self, sector: u32, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), Error> {
+        write(EMMC_BLKSIZECNT, (1 << 16) | SECTOR_SIZE as u32);
+        self.command(READ_SINGLE_BLOCK, self.address(sector))?;
+        wait_interrupt(READ_SINGLE_BLOCK, INTERRUPT_READ_RDY, DATA_TIMEOUT_US)?;
+        for word in buf.chunks_exact_mut(4) {
+            word.copy_from_slice(&read(EMMC_DATA).to_le_bytes());
+        }
+        wait_interrupt(READ_SINGLE_BLOCK, INTERRUPT_DATA_DONE, DATA_TIMEOUT_US)
+    }
+
+    pub fn write_sector(&mut self, sector: u32, buf: &[u8; SECTOR_SIZE]) -> Result<(), Error> {
+        write(EMMC_BLKSIZECNT, (1 << 16) | SECTOR_SIZE as u32);
+        self.command(WRITE_BLOCK, self.address(sector))?;
+        wait_interrupt(WRITE_BLOCK, INTERRUPT_WRITE_RDY, DATA_TIMEOUT_US)?;
+        for word in buf.chunks_exact(4) {
+            write(EMMC_DATA, u32::from_le_bytes(word.try_into().unwrap()));
+        }
+        wait_interrupt(WRITE_BLOCK, INTERRUPT_DATA_DONE, DATA_TIMEOUT_US)
+    }
+}
+
+impl BlockDevice for Sd {
+    fn read(&mut self, sector: u32, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), fat::Error> {
+        self.read_sector(sector, buf)
+            .map_err(|_| fat::Error::Device { sector })
+    }
+
+    fn write(&mut self, sector: u32, buf: &[u8; SECTOR_SIZE]) -> Result<(), fat::Error> {
+        self.write_sector(sector, buf)
+            .map_err(|_| fat::Error::Device { sector })
+    }
+}
+
+/// Run the card clock at `frequency` or just below.
+fn set_clock(frequency: u32) -> Result<(), Error> {
+    wait(
+        EMMC_STATUS,
+        STATUS_CMD_INHIBIT | STATUS_DAT_INHIBIT,
+        0,
+        COMMAND_TIMEOUT_US,
+    )?;
+    write(EMMC_CONTROL1, read(EMMC_CONTROL1) & !CONTROL1_CLK_EN);
+    timer::delay_ms(10);
+
+    // The clock is the base clock divided by twice the divider.
+    let base = mailbox::clock_rate(mailbox::CLOCK_EMMC).unwrap_or(DEFAULT_BASE_CLOCK);
+    let mut divider = base.div_ceil(2 * frequency).clamp(1, 0x3ff);
+    let version = (read(EMMC_SLOTISR_VER) >> 16) & 0xff;
+    // Before version 3 of the spec only powers of two up to 128 work.
+    if version < 2 {
+        divider = divider.next_power_of_two().min(0x80);
+    }
+    let field = ((divider & 0xff) << 8) | ((divider >> 8) << 6);
+    write(
+        EMMC_CONTROL1,
+        (read(EMMC_CONTROL1) & !CONTROL1_CLK_FREQ) | field,
+    );
+    timer::delay_ms(10);
+    write(EMMC_CONTROL1, read(EMMC_CONTROL1) | CONTROL1_CLK_EN);
+    wait(
+        EMMC_CONTROL1,
+        CONTROL1_CLK_STABLE,
+        CONTROL1_CLK_STABLE,
+        COMMAND_TIMEOUT_US,
+    )
+}
+
+/// Wait for the bits `mask` of `register` to equal `value`.
+fn wait(register: usize, mask: u32, value: u32, timeout: u32) -> Result<(), Error> {
+    let start = timer::timer_get_usec();
+    while read(register) & mask != value {
+        if timer::timer_get_usec().wrapping_sub(start) > timeout {
+            return Err(Error::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the interrupt bit `done` of `command`, clearing it.
+fn wait_interrupt(command: u32, done: u32, timeout: u32) -> Result<(), Error> {
+    let start = timer::timer_get_usec();
+    loop {
+        let status = read(EMMC_INTERRUPT);
+        if status & (INTERRUPT_CTO_ERR | INTERRUPT_DTO_ERR) != 0 {
+            write(EMMC_INTERRUPT, status);
+            return Err(Error::Timeout);
+        }
+        if status & INTERRUPT_ERRORS != 0 {
+            write(EMMC_INTERRUPT, status);
+            return Err(Error::Failed { command, status });
+        }
+        if status & done != 0 {
+            write(EMMC_INTERRUPT, done);
+            return Ok(());
+        }
+        if timer::timer_get_usec().wrapping_sub(start) > timeout {
+            return Err(Error::Timeout);
+        }
+    }
+}
+
+fn read(register: usize) -> u32 {
+    dsb();
+    let value = unsafe { (register as *const u32).read_volatile() };
+    dsb();
+    value
+}
+
+fn write(register: usize, value: u32) {
+    dsb();
+    unsafe { (register as *mut u32).write_volatile(value) };
+    dsb();
+}
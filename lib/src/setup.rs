@@ -1,13 +1,17 @@
-use core::{fmt::Write, ops::DerefMut, panic::PanicInfo};
+use core::{arch::asm, fmt::Write, ops::DerefMut, panic::PanicInfo, time::Duration};
 
 use crate::gpio::{Pin, Unset};
 use crate::uart::UART_WRITER;
 use crate::{
     interrupts,
     timer::delay_ms,
-    uart::{setup_uart, UartWriter},
+    uart::{self, setup_uart, write_message, UartWriter},
 };
 use bcm2835_lpa::Peripherals;
+use bootloader_shared::{
+    message::{ExitStatus, Message},
+    BOOTLOADER_LOCATION, BOOTLOADER_RESIDENT,
+};
 use interrupts::disable_interrupts;
 
 extern "C" {
@@ -23,32 +27,93 @@ extern "C" {
 pub const SUPER_MODE: u32 = 0b10011;
 pub const SYSTEM_MODE: u32 = 0b11111;
 pub const ABORT_MODE: u32 = 0b10111;
+pub const UNDEFINED_MODE: u32 = 0b11011;
 pub const USER_MODE: u32 = 0b10000;
 pub const STACK_ADDR: u32 = 0x8000000;
 
+const PM_RSTC: usize = 0x2010001c;
+const PM_WDOG: usize = 0x20100024;
+const PM_PASSWORD: u32 = 0x5a000000;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x00000020;
+const PM_RSTC_RESET: u32 = 0x00000102;
+/// The watchdog counts down at 64 KHz from at most this.
+const PM_WDOG_TIME_SET: u32 = 0x000fffff;
+
+/// Stop the program and [reload], making the installer exit with `code`.
+pub fn exit(code: u32) -> ! {
+    exit_with(ExitStatus::code(code))
+}
+
+/// Send `status` to the installer, if the uart is set up, and [reload].
+pub fn exit_with(status: ExitStatus) -> ! {
+    disable_interrupts();
+    if uart::is_enabled() {
+        write_message(Message::Exit(status));
+    }
+    reload()
+}
+
 #[no_mangle]
-/// Taken from dawson engler's 140e staff code.
+/// Reload after a successful run.
 pub extern "C" fn rpi_reboot() -> ! {
+    exit(0)
+}
+
+/// Return to the bootloader to wait for the next program, skipping the
+/// seconds a reboot takes through the firmware. Reboots if the bootloader
+/// isn't resident, for example because the program was booted directly or
+/// overwrote it.
+pub fn reload() -> ! {
+    disable_interrupts();
+    watchdog_stop();
+    // Let the uart finish sending before the bootloader sets it up again.
+    delay_ms(10);
+    let header = BOOTLOADER_LOCATION as *const u32;
+    unsafe {
+        if header.add(1).read_volatile() == BOOTLOADER_RESIDENT {
+            let warm = header.add(2).read_volatile();
+            asm!("bx {}", in(reg) warm, options(noreturn));
+        }
+    }
+    reset()
+}
+
+/// Reboot without reporting an exit status.
+///
+/// Taken from dawson engler's 140e staff code.
+pub fn reset() -> ! {
     // uart_flush_tx();
     disable_interrupts();
     delay_ms(10);
 
     // is there a way to speed this up?
-    let pm_rstc = 0x2010001c;
-    let pm_wdog = 0x20100024;
-    let pm_password = 0x5a000000;
-    let pm_rstc_wrcfg_full_reset = 0x00000020;
     unsafe {
-        (pm_wdog as *mut u32).write_volatile(pm_password | 1);
-        (pm_rstc as *mut u32).write_volatile(pm_password | pm_rstc_wrcfg_full_reset);
+        (PM_WDOG as *mut u32).write_volatile(PM_PASSWORD | 1);
+        (PM_RSTC as *mut u32).write_volatile(PM_PASSWORD | PM_RSTC_WRCFG_FULL_RESET);
     }
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
+/// Reset unless [watchdog_stop] is called within `timeout`, which is capped at
+/// 16 seconds.
+pub fn watchdog_start(timeout: Duration) {
+    let ticks = (timeout.as_micros() * 65536 / 1_000_000).min(PM_WDOG_TIME_SET as u128) as u32;
+    unsafe {
+        (PM_WDOG as *mut u32).write_volatile(PM_PASSWORD | ticks);
+        (PM_RSTC as *mut u32).write_volatile(PM_PASSWORD | PM_RSTC_WRCFG_FULL_RESET);
+    }
+}
+
+pub fn watchdog_stop() {
+    unsafe { (PM_RSTC as *mut u32).write_volatile(PM_PASSWORD | PM_RSTC_RESET) };
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     disable_interrupts();
+    // Kept in case no host is listening, to tell on the next boot.
+    crate::crash::record_panic(info);
     // If the uart is setup and not in use, then directly use it.
     // Otherwise, setup and write there.
     let construct_uart = || {
@@ -68,7 +133,7 @@ fn panic(info: &PanicInfo) -> ! {
                 location.column()
             ));
         }
-        let _ = w.write_fmt(format_args!("\n{}\nDONE!!!\n", info.message()));
+        let _ = w.write_fmt(format_args!("\n{}\n", info.message()));
     }
     critical_section::with(|cs| {
         let Ok(mut w) = UART_WRITER.borrow(cs).try_borrow_mut() else {
@@ -78,8 +143,8 @@ fn panic(info: &PanicInfo) -> ! {
             return;
         };
         write_panic(w, info);
-        rpi_reboot();
+        exit_with(ExitStatus::panicked());
     });
     write_panic(&mut construct_uart(), info);
-    rpi_reboot();
+    exit_with(ExitStatus::panicked());
 }
@@ -0,0 +1,74 @@
+//! Runs `#[test_case]` functions on the Pi, reporting each one to the
+//! installer, which runs with `--test`.
+//!
+//! A panic returns to the bootloader and a test running past [TEST_TIMEOUT]
+//! reboots the Pi. Either way the installer then loads the program again,
+//! asking it to continue after the failed test. To use it, the crate under
+//! test needs
+//!
+//! ```ignore
+//! #![feature(custom_test_frameworks)]
+//! #![test_runner(pi0_lib::testing::runner)]
+//! #![reexport_test_harness_main = "test_main"]
+//! ```
+//!
+//! and to call `test_main()` instead of its main function under `cfg(test)`.
+use core::time::Duration;
+
+use bootloader_shared::{message::Message, INSTALLER_RUN_TESTS};
+
+use crate::{
+    setup::{exit, watchdog_start, watchdog_stop},
+    timer::timer_get_usec,
+    uart::{read_uart_u32_timeout, write_message},
+};
+
+/// A test still running after this long reboots the Pi and fails.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the installer to say which test to start at.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+pub fn runner(tests: &[&dyn Testable]) -> ! {
+    write_message(Message::Tests {
+        count: tests.len() as u32,
+    });
+    for (index, test) in tests.iter().enumerate().skip(first_test()) {
+        let index = index as u32;
+        write_message(Message::TestStarted {
+            index,
+            name: test.name(),
+        });
+        let start = timer_get_usec();
+        watchdog_start(TEST_TIMEOUT);
+        test.run();
+        watchdog_stop();
+        write_message(Message::TestPassed {
+            index,
+            micros: timer_get_usec().wrapping_sub(start),
+        });
+    }
+    exit(0)
+}
+
+/// Where the installer asked to start, or the first test if nobody answered.
+fn first_test() -> usize {
+    match read_uart_u32_timeout(REPLY_TIMEOUT) {
+        Ok(INSTALLER_RUN_TESTS) => read_uart_u32_timeout(REPLY_TIMEOUT).unwrap_or(0) as usize,
+        _ => 0,
+    }
+}
@@ -1,16 +1,39 @@
-use core::{arch::asm, cell::RefCell, time::Duration};
+use core::{
+    arch::asm,
+    cell::RefCell,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
 
 use crate::{
     gpio::{Alt5, Pin, Unset},
+    mailbox,
+    setup::reload,
     timer,
 };
 use bcm2835_lpa::{Peripherals, UART1};
+pub use bootloader_shared::channel::Channel;
+use bootloader_shared::{
+    baud_divisor,
+    channel::{encode, MAX_CHANNEL_DATA, MAX_CHANNEL_FRAME},
+    message::{Message, MAX_FRAME_LENGTH},
+    DEFAULT_BAUD, DEFAULT_CORE_CLOCK, INSTALLER_REBOOT,
+};
 use critical_section::Mutex;
 
 use crate::dsb;
 
-const ASSUMED_CLOCK_RATE: usize = 250_000_000;
-const DESIRED_BAUD_RATE: usize = 115_200 * 8;
+/// Line status register, as the PAC lacks the transmitter idle bit.
+const AUX_MU_LSR: usize = 0x2021_5054;
+const LSR_TX_IDLE: u32 = 1 << 6;
+
+/// The rate last set, see [baud].
+static BAUD: AtomicU32 = AtomicU32::new(DEFAULT_BAUD);
+/// The core clock the divisor is computed from, read once.
+static CORE_CLOCK: AtomicU32 = AtomicU32::new(0);
+/// The last four bytes [read_uart] and [read_byte] returned, to spot
+/// INSTALLER_REBOOT however the program reads its input.
+static RECEIVED: AtomicU32 = AtomicU32::new(0);
 
 pub fn setup_uart(
     p14: Pin<14, Unset>,
@@ -38,14 +61,12 @@ pub fn setup_uart(
         uart.iir()
             .modify(|_, w| w.tx_ready().set_bit().data_ready().set_bit());
 
-        // Set the baud rate.
-        uart.baud().write(|w| unsafe {
-            w.bits(
-                (ASSUMED_CLOCK_RATE / 8 / DESIRED_BAUD_RATE - 1)
-                    .try_into()
-                    .unwrap(),
-            )
-        });
+        // Programs start at the default rate, whatever the bootloader used.
+        BAUD.store(DEFAULT_BAUD, Ordering::Relaxed);
+        // Fits at any core clock the Pi runs at.
+        if let Some(divisor) = divisor(DEFAULT_BAUD) {
+            uart.baud().write(|w| unsafe { w.bits(divisor) });
+        }
         uart.lcr().modify(|_, w| w.data_size()._8bit());
         uart.mcr().modify(|_, w| w.rts().clear_bit());
         // Enable TX/RX
@@ -60,6 +81,47 @@ pub fn setup_uart(
     })
 }
 
+/// The clock the uart's rate derives from, as the firmware reports it.
+pub fn core_clock() -> u32 {
+    let mut clock = CORE_CLOCK.load(Ordering::Relaxed);
+    if clock == 0 {
+        clock = mailbox::clock_rate(mailbox::CLOCK_CORE).unwrap_or(DEFAULT_CORE_CLOCK);
+        CORE_CLOCK.store(clock, Ordering::Relaxed);
+    }
+    clock
+}
+
+/// The baud rate register value for `baud`, from the real core clock, or
+/// `None` if the uart can't run at it.
+fn divisor(baud: u32) -> Option<u16> {
+    baud_divisor(core_clock(), baud)
+}
+
+/// The baud rate the uart runs at.
+pub fn baud() -> u32 {
+    BAUD.load(Ordering::Relaxed)
+}
+
+/// Switch to `baud`, once everything written so far has gone out. Returns
+/// whether the uart runs at `baud`, staying at the old rate if it can't.
+pub fn set_baud(baud: u32) -> bool {
+    if baud == self::baud() {
+        return true;
+    }
+    let Some(divisor) = divisor(baud) else {
+        return false;
+    };
+    dsb();
+    while unsafe { (AUX_MU_LSR as *const u32).read_volatile() } & LSR_TX_IDLE == 0 {
+        unsafe { asm!("nop") }
+    }
+    let uart = unsafe { UART1::steal() };
+    uart.baud().write(|w| unsafe { w.bits(divisor) });
+    BAUD.store(baud, Ordering::Relaxed);
+    dsb();
+    true
+}
+
 pub fn read_uart_u32_timeout(timeout: Duration) -> Result<u32, ()> {
     let mut v: u32 = 0;
     let mut read_count = 0;
@@ -85,10 +147,79 @@ pub fn read_uart_u32_timeout(timeout: Duration) -> Result<u32, ()> {
     Ok(v)
 }
 
+/// Whether the uart has been set up, so writing to it won't hang.
+pub fn is_enabled() -> bool {
+    dsb();
+    let peripherals = unsafe { Peripherals::steal() };
+    let enabled = peripherals.AUX.enables().read().uart_1().bit_is_set();
+    dsb();
+    enabled
+}
+
 pub fn write_uart_u32(v: u32) {
     write_uart(&u32::to_le_bytes(v))
 }
 
+/// Send a message to the installer, see [bootloader_shared::message].
+pub fn write_message(message: Message) {
+    write_uart(message.encode(&mut [0; MAX_FRAME_LENGTH]));
+}
+
+/// Send `data` on `channel`, see [bootloader_shared::channel]. Plain output,
+/// as from [println!], is on [Channel::Console], except for zero bytes, which
+/// the installer drops.
+pub fn write_channel(channel: Channel, data: &[u8]) {
+    for chunk in data.chunks(MAX_CHANNEL_DATA) {
+        let mut frame = [0; MAX_CHANNEL_FRAME];
+        let frame = encode(channel, chunk, &mut frame);
+        // In one piece, even if an interrupt handler prints.
+        critical_section::with(|_| write_uart(frame));
+    }
+}
+
+/// Formats onto a channel, sending a frame when full and when dropped.
+pub struct ChannelWriter {
+    channel: Channel,
+    buf: [u8; MAX_CHANNEL_DATA],
+    length: usize,
+}
+
+impl ChannelWriter {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            buf: [0; MAX_CHANNEL_DATA],
+            length: 0,
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.length > 0 {
+            write_channel(self.channel, &self.buf[..self.length]);
+            self.length = 0;
+        }
+    }
+}
+
+impl core::fmt::Write for ChannelWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.length == self.buf.len() {
+                self.flush();
+            }
+            self.buf[self.length] = byte;
+            self.length += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 pub fn write_uart(bytes: &[u8]) {
     dsb();
     let uart = unsafe { UART1::steal() };
@@ -106,7 +237,22 @@ pub fn write_uart(bytes: &[u8]) {
     dsb();
 }
 
+/// Read the bytes that are available into `dest`, without waiting.
+///
+/// Returns to the bootloader if the console asks for it, so that a program
+/// can be reloaded as long as it reads its input. One that never reads the
+/// uart has to be reset instead.
 pub fn read_uart(dest: &mut [u8]) -> &[u8] {
+    let read = read_uart_raw(dest);
+    for &byte in read.iter() {
+        watch(byte);
+    }
+    read
+}
+
+/// Read the bytes that are available into `dest`, without waiting or
+/// looking for INSTALLER_REBOOT, for binary data that may hold it.
+pub fn read_uart_raw(dest: &mut [u8]) -> &[u8] {
     dsb();
     let uart = unsafe { UART1::steal() };
     for i in 0..dest.len() {
@@ -120,6 +266,7 @@ pub fn read_uart(dest: &mut [u8]) -> &[u8] {
     return dest;
 }
 
+/// Fill `dest`, waiting for each byte. Doesn't look for INSTALLER_REBOOT.
 pub fn read_all_uart(dest: &mut [u8]) {
     dsb();
     let uart = unsafe { UART1::steal() };
@@ -133,6 +280,57 @@ pub fn read_all_uart(dest: &mut [u8]) {
     dsb();
 }
 
+/// Block until a byte arrives.
+///
+/// Returns to the bootloader if the console asks for it, see [read_uart].
+pub fn read_byte() -> u8 {
+    let mut buf = [0; 1];
+    read_all_uart(&mut buf);
+    watch(buf[0]);
+    buf[0]
+}
+
+/// Return to the bootloader once `byte` completes INSTALLER_REBOOT.
+///
+/// No exit status is sent, the installer already knows it was interrupted.
+fn watch(byte: u8) {
+    let last = (RECEIVED.load(Ordering::Relaxed) >> 8) + ((byte as u32) << 24);
+    RECEIVED.store(last, Ordering::Relaxed);
+    if last == INSTALLER_REBOOT {
+        reload();
+    }
+}
+
+/// Read a line typed into the installer console, echoing it back and handling
+/// backspace. Returns the line without its ending, dropping characters that
+/// don't fit in `buf`.
+///
+/// Returns to the bootloader if the console asks for it, see [read_uart].
+pub fn read_line(buf: &mut [u8]) -> &str {
+    let mut len = 0;
+    loop {
+        let byte = read_byte();
+        match byte {
+            b'\r' | b'\n' => {
+                write_uart(b"\n");
+                // Only printable ASCII is kept.
+                return core::str::from_utf8(&buf[..len]).unwrap();
+            }
+            // Backspace and delete.
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                write_uart(b"\x08 \x08");
+            }
+            b' '..=b'~' if len < buf.len() => {
+                buf[len] = byte;
+                len += 1;
+                write_uart(&[byte]);
+            }
+            _ => {}
+        }
+    }
+}
+
 pub static UART_WRITER: Mutex<RefCell<Option<UartWriter>>> = Mutex::new(RefCell::new(None));
 
 pub struct UartWriter {
@@ -197,6 +395,9 @@ macro_rules! print {
 /// This will error if the args cause an interrupt (like software interrupt).
 #[macro_export]
 macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
     ($( $args:tt)* ) => {
         critical_section::with(|cs| {
             let mut w = $crate::uart::UART_WRITER.borrow_ref_mut(cs);
@@ -226,6 +427,18 @@ macro_rules! steal_println {
     };
 }
 
+/// Print a line on [Channel::Log], which the installer can save apart from
+/// the console.
+#[macro_export]
+macro_rules! logln {
+    ($( $args:tt)* ) => {{
+        // The frame goes out when the writer drops, at the end of the block.
+        let mut w = $crate::uart::ChannelWriter::new($crate::uart::Channel::Log);
+        core::fmt::Write::write_fmt(&mut w, format_args!($($args)*)).unwrap();
+        core::fmt::Write::write_str(&mut w, "\n").unwrap();
+    }};
+}
+
 /// Software version of uart.
 pub mod software {
 